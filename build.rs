@@ -10,11 +10,6 @@ struct Library {
 
 fn main() {
     let profile_is_release = env::var("PROFILE").unwrap() == "RELEASE";
-    let reliable_profile = if profile_is_release {
-        "RELIABLE_RELEASE"
-    } else {
-        "RELIABLE_DEBUG"
-    };
     let netcode_profile = if profile_is_release {
         "NETCODE_RELEASE"
     } else {
@@ -23,13 +18,6 @@ fn main() {
 
     let sodium = libsodium();
 
-    // build reliable
-    cc::Build::new()
-        .include("lib/reliable")
-        .files(&["lib/reliable/reliable.c"])
-        .define(reliable_profile, None)
-        .compile("reliable");
-
     // build netcode
     cc::Build::new()
         .include("lib/netcode")
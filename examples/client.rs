@@ -1,8 +1,17 @@
-use std::{mem::size_of, sync::mpsc::channel, thread::sleep, time::Duration};
+use std::{
+    error::Error,
+    io::{Read, Write},
+    mem::size_of,
+    net::TcpStream,
+    sync::mpsc::channel,
+    thread::sleep,
+    time::Duration,
+};
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use yojimbo::{
     bindings::netcode_random_bytes, client::Client, config::ClientServerConfig, initialize,
-    set_bindings_log_level, shutdown, BindingsLogLevel, PRIVATE_KEY_BYTES,
+    set_bindings_log_level, shutdown, BindingsLogLevel, CONNECT_TOKEN_BYTES, PRIVATE_KEY_BYTES,
 };
 
 #[path = "./common/mod.rs"]
@@ -23,7 +32,9 @@ fn main() {
 fn client_main() {
     let server_address = "127.0.0.1:40000".to_string();
 
-    println!("connecting client (insecure)");
+    // pass `--matcher` to get a connect token from the matcher example, instead of connecting
+    // with the private key
+    let use_matcher = std::env::args().any(|arg| arg == "--matcher");
 
     let mut time = 100.0;
 
@@ -33,11 +44,29 @@ fn client_main() {
     println!("client id is {:x}", client_id);
 
     let config = ClientServerConfig::new(8);
+    let protocol_id = config.protocol_id;
     let mut client: Client<TestMessage> = Client::new("0.0.0.0".to_string(), config, time);
 
-    let private_key = [0; PRIVATE_KEY_BYTES];
-
-    client.insecure_connect(&private_key, client_id, &[&server_address]);
+    if use_matcher {
+        println!(
+            "requesting connect token from matcher on {}",
+            MATCHER_ADDRESS
+        );
+        let connect_token = match request_connect_token(protocol_id, client_id) {
+            Ok(connect_token) => connect_token,
+            Err(err) => {
+                println!("failed to get connect token: {}", err);
+                return;
+            }
+        };
+
+        println!("connecting client (secure)");
+        client.connect(&connect_token);
+    } else {
+        println!("connecting client (insecure)");
+        let private_key = [0; PRIVATE_KEY_BYTES];
+        client.insecure_connect(&private_key, client_id, &[&server_address]);
+    }
 
     let (stop_tx, stop_rx) = channel();
     ctrlc::set_handler(move || stop_tx.send(()).unwrap()).expect("Failed to set Ctrl-C handler");
@@ -61,12 +90,12 @@ fn client_main() {
         time += delta_time;
         client.advance_time(time);
 
-        if client.is_disconnected() {
-            println!("client was disconnected");
+        if let Some(error) = client.error() {
+            println!("stopping client due to connection failure: {}", error);
             break;
         }
-        if client.connection_failed() {
-            println!("stopping client due to connection failure");
+        if client.is_disconnected() {
+            println!("client was disconnected");
             break;
         }
 
@@ -115,3 +144,36 @@ fn handle_special_message(client: &mut Client<TestMessage>) {
         }
     }
 }
+
+/// Fetch a connect token from the matcher example.
+fn request_connect_token(
+    protocol_id: u64,
+    client_id: u64,
+) -> Result<[u8; CONNECT_TOKEN_BYTES], Box<dyn Error>> {
+    let mut stream = TcpStream::connect(MATCHER_ADDRESS)?;
+    write!(
+        stream,
+        "GET /match/{}/{} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+        protocol_id,
+        client_id,
+        MATCHER_ADDRESS,
+        matcher_secret()
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("malformed response from matcher")?;
+    let status = head.lines().next().unwrap_or_default();
+    if !status.starts_with("HTTP/1.1 200") {
+        return Err(format!("matcher responded with {}", status).into());
+    }
+
+    let connect_token = STANDARD.decode(body.trim())?;
+    let connect_token = connect_token
+        .try_into()
+        .map_err(|_| "connect token has the wrong size")?;
+    Ok(connect_token)
+}
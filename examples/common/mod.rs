@@ -1,3 +1,6 @@
+// not every example uses everything in here
+#![allow(dead_code)]
+
 use std::error::Error;
 
 use serde::{Deserialize, Serialize};
@@ -5,6 +8,14 @@ use yojimbo::message::NetworkMessage;
 
 pub const SPECIAL_MESSAGE_STRING: &str = "server got the special message";
 
+/// Where the matcher example listens for token requests.
+pub const MATCHER_ADDRESS: &str = "127.0.0.1:8080";
+
+/// The secret clients must present to the matcher, from `MATCHER_SECRET`.
+pub fn matcher_secret() -> String {
+    std::env::var("MATCHER_SECRET").unwrap_or_else(|_| "change me".to_string())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TestMessage {
     Int(i32),
@@ -0,0 +1,148 @@
+//! A minimal matchmaking service, which hands out connect tokens over HTTP.
+//!
+//! Clients request a token with `GET /match/{protocol_id}/{client_id}` and an
+//! `Authorization: Bearer <secret>` header, and get back the base64 encoded
+//! connect token for the server example. The secret is read from the
+//! `MATCHER_SECRET` environment variable.
+//!
+//! This is only a reference: a real service would authenticate players
+//! properly, serve HTTPS, and pick servers with free slots.
+
+use std::{
+    error::Error,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use yojimbo::{connect_token::ConnectTokenBuilder, initialize, shutdown, PRIVATE_KEY_BYTES};
+
+#[path = "./common/mod.rs"]
+mod common;
+use common::*;
+
+/// Servers handed out to clients, in the order they should be tried.
+const SERVER_ADDRESSES: &[&str] = &["127.0.0.1:40000"];
+const TOKEN_EXPIRE_SECONDS: i32 = 30;
+const TOKEN_TIMEOUT_SECONDS: i32 = 5;
+
+fn main() {
+    env_logger::init();
+
+    initialize().unwrap();
+
+    matcher_main();
+
+    shutdown();
+}
+
+fn matcher_main() {
+    // must match the server's private key (the server example uses all zeroes)
+    let private_key = [0; PRIVATE_KEY_BYTES];
+    let secret = matcher_secret();
+
+    let listener = TcpListener::bind(MATCHER_ADDRESS).unwrap();
+    println!("matcher listening on http://{}", MATCHER_ADDRESS);
+
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(|err| err.into())
+            .and_then(|stream| handle_request(stream, &private_key, &secret));
+        if let Err(err) = result {
+            println!("failed to handle request: {}", err);
+        }
+    }
+}
+
+fn handle_request(
+    mut stream: TcpStream,
+    private_key: &[u8; PRIVATE_KEY_BYTES],
+    secret: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut authorization = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            break;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let (status, body) = respond(
+        request_line.trim_end(),
+        authorization.as_deref(),
+        private_key,
+        secret,
+    );
+    println!("{} -> {}", request_line.trim_end(), status);
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+fn respond(
+    request_line: &str,
+    authorization: Option<&str>,
+    private_key: &[u8; PRIVATE_KEY_BYTES],
+    secret: &str,
+) -> (&'static str, String) {
+    let mut parts = request_line.split_whitespace();
+    let (Some("GET"), Some(path)) = (parts.next(), parts.next()) else {
+        return ("400 Bad Request", String::new());
+    };
+
+    let expected_authorization = format!("Bearer {}", secret);
+    let authorized = authorization
+        .map(|authorization| {
+            constant_time_eq(authorization.as_bytes(), expected_authorization.as_bytes())
+        })
+        .unwrap_or(false);
+    if !authorized {
+        return ("401 Unauthorized", String::new());
+    }
+
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let ["match", protocol_id, client_id] = segments[..] else {
+        return ("404 Not Found", String::new());
+    };
+    let (Ok(protocol_id), Ok(client_id)) = (protocol_id.parse(), client_id.parse()) else {
+        return ("400 Bad Request", String::new());
+    };
+
+    let mut builder = ConnectTokenBuilder::new(private_key, protocol_id, client_id)
+        .expire_seconds(TOKEN_EXPIRE_SECONDS)
+        .timeout_seconds(TOKEN_TIMEOUT_SECONDS);
+    for address in SERVER_ADDRESSES {
+        builder = builder.server_address(address);
+    }
+
+    match builder.build() {
+        Ok(connect_token) => ("200 OK", STANDARD.encode(connect_token)),
+        Err(err) => ("500 Internal Server Error", err.to_string()),
+    }
+}
+
+/// Compare without leaking how much of the secret matched through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,50 @@
+#![no_main]
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use libfuzzer_sys::fuzz_target;
+use yojimbo::{
+    config::{ChannelType, ClientServerConfig, ConnectionConfig},
+    connection::fuzz_read_packet,
+    message::NetworkMessage,
+};
+
+#[derive(Debug, Clone)]
+struct FuzzMessage {
+    values: Vec<u32>,
+}
+
+impl NetworkMessage for FuzzMessage {
+    type Error = std::io::Error;
+
+    fn serialize<W: std::io::Write>(&self, mut writer: W) -> Result<(), Self::Error> {
+        writer.write_u8(self.values.len() as u8)?;
+        for value in &self.values {
+            writer.write_u32::<LittleEndian>(*value)?;
+        }
+
+        Ok(())
+    }
+
+    fn deserialize<R: std::io::Read>(mut reader: R) -> Result<Self, Self::Error> {
+        let len = reader.read_u8()?;
+        let mut values = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            values.push(reader.read_u32::<LittleEndian>()?);
+        }
+
+        Ok(FuzzMessage { values })
+    }
+}
+
+fn config() -> ConnectionConfig {
+    let mut config = ClientServerConfig::new(3).connection;
+    config.channels[0].kind = ChannelType::ReliableOrdered;
+    config.channels[1].kind = ChannelType::UnreliableUnordered;
+    config.channels[2].kind = ChannelType::ReliableOrdered;
+    config.channels[2].disable_blocks = true;
+    config
+}
+
+fuzz_target!(|data: &[u8]| {
+    fuzz_read_packet::<FuzzMessage>(&config(), data);
+});
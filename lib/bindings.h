@@ -11,5 +11,4 @@
     #define __STDC__ 1
 #endif
 
-#include "netcode/netcode.h"
-#include "reliable/reliable.h"
\ No newline at end of file
+#include "netcode/netcode.h"
\ No newline at end of file
@@ -3,6 +3,7 @@
 #![allow(non_snake_case)]
 
 use std::marker::PhantomData;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 #[macro_export]
 macro_rules! gf_init_default {
@@ -54,7 +55,7 @@ impl<'a> NetcodeAddress<'a> {
     }
 
     pub fn ipv6(&self) -> Option<&[u16; 8]> {
-        if self.is_ipv4() {
+        if self.is_ipv6() {
             unsafe { Some(&(*self.raw).data.ipv6) }
         } else {
             None
@@ -64,6 +65,16 @@ impl<'a> NetcodeAddress<'a> {
     pub fn port(&self) -> u16 {
         unsafe { (*self.raw).port }
     }
+
+    /// Convert to a std address. Returns None if the address is not set (e.g. for loopback clients).
+    pub fn to_socket_addr(&self) -> Option<SocketAddr> {
+        if let Some(&ipv4) = self.ipv4() {
+            Some(SocketAddr::new(Ipv4Addr::from(ipv4).into(), self.port()))
+        } else {
+            let &ipv6 = self.ipv6()?;
+            Some(SocketAddr::new(Ipv6Addr::from(ipv6).into(), self.port()))
+        }
+    }
 }
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
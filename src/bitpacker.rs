@@ -0,0 +1,519 @@
+//! Bit-level serialization for packet headers and messages.
+//!
+//! Mirrors yojimbo's `BitWriter`/`BitReader` and the `serialize_int`,
+//! `serialize_bool` and `serialize_sequence_relative` helpers. Values are
+//! packed least significant bit first.
+//!
+//! `BitWriter` implements `io::Write` and `BitReader` implements `io::Read`, so
+//! byte-oriented serializers can be used with them directly (bytes are simply
+//! written as 8 bits each, without any alignment).
+
+use std::io;
+
+/// The number of bits needed to store any integer in `[min, max]`.
+pub fn bits_required(min: u32, max: u32) -> u32 {
+    assert!(min <= max);
+    u32::BITS - (max - min).leading_zeros()
+}
+
+/// Writes values to a buffer using as few bits as possible.
+///
+/// A writer created with `BitWriter::measure` doesn't write anywhere, and
+/// only counts the bits that would have been written.
+pub struct BitWriter<'a> {
+    buffer: Option<&'a mut [u8]>,
+    bits_written: usize,
+}
+
+impl<'a> BitWriter<'a> {
+    /// Create a writer that writes to `buffer`, starting at the first byte.
+    pub fn new(buffer: &'a mut [u8]) -> BitWriter<'a> {
+        BitWriter {
+            buffer: Some(buffer),
+            bits_written: 0,
+        }
+    }
+
+    /// Create a writer that only counts the number of bits written.
+    pub fn measure() -> BitWriter<'static> {
+        BitWriter {
+            buffer: None,
+            bits_written: 0,
+        }
+    }
+
+    pub fn bits_written(&self) -> usize {
+        self.bits_written
+    }
+
+    /// The number of bytes touched so far (the last byte may be partially written).
+    pub fn bytes_written(&self) -> usize {
+        (self.bits_written + 7) >> 3
+    }
+
+    /// Write the lowest `bits` bits of `value`.
+    ///
+    /// `bits` must be at most 32, and `value` must fit in `bits`.
+    pub fn write_bits(&mut self, value: u32, bits: u32) -> io::Result<()> {
+        assert!(bits <= u32::BITS);
+        debug_assert!(
+            bits == u32::BITS || value >> bits == 0,
+            "{} does not fit in {} bits",
+            value,
+            bits
+        );
+
+        if let Some(buffer) = &mut self.buffer {
+            if self.bits_written + bits as usize > buffer.len() * 8 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "bit writer is out of space",
+                ));
+            }
+
+            let mut value = value;
+            let mut position = self.bits_written;
+            let mut remaining = bits;
+            while remaining > 0 {
+                let offset = (position % 8) as u32;
+                let count = remaining.min(8 - offset);
+                let chunk = ((value & ((1 << count) - 1)) as u8) << offset;
+
+                // bytes are written in order, so clear any stale data on first touch
+                if offset == 0 {
+                    buffer[position / 8] = chunk;
+                } else {
+                    buffer[position / 8] |= chunk;
+                }
+
+                value >>= count;
+                position += count as usize;
+                remaining -= count;
+            }
+        }
+
+        self.bits_written += bits as usize;
+
+        Ok(())
+    }
+
+    pub fn write_bool(&mut self, value: bool) -> io::Result<()> {
+        self.write_bits(value as u32, 1)
+    }
+
+    /// Write `value` using `bits_required(min, max)` bits.
+    pub fn write_int(&mut self, value: u32, min: u32, max: u32) -> io::Result<()> {
+        if value < min || value > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is outside of [{}, {}]", value, min, max),
+            ));
+        }
+
+        self.write_bits(value - min, bits_required(min, max))
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for byte in bytes {
+            self.write_bits(*byte as u32, 8)?;
+        }
+
+        Ok(())
+    }
+
+    /// Copy the first `bits` bits of `data`, which was written by another `BitWriter`.
+    ///
+    /// This is how pre-serialized data is written at any bit position.
+    pub fn write_bits_from(&mut self, data: &[u8], bits: usize) -> io::Result<()> {
+        assert!(bits <= data.len() * 8);
+
+        let mut chunks = data[..bits / 8].chunks_exact(4);
+        for chunk in &mut chunks {
+            self.write_bits(u32::from_le_bytes(chunk.try_into().unwrap()), 32)?;
+        }
+        self.write_bytes(chunks.remainder())?;
+
+        let remaining_bits = (bits % 8) as u32;
+        if remaining_bits > 0 {
+            let last = data[bits / 8] as u32 & ((1 << remaining_bits) - 1);
+            self.write_bits(last, remaining_bits)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write `sequence2` relative to `sequence1`, which must be older.
+    ///
+    /// Sequences that are close together (e.g. consecutive message IDs) take
+    /// as little as 1 bit.
+    pub fn write_sequence_relative(&mut self, sequence1: u16, sequence2: u16) -> io::Result<()> {
+        let previous = sequence1 as u32;
+        let current = sequence2 as u32 + if sequence1 > sequence2 { 65536 } else { 0 };
+
+        self.write_int_relative(previous, current)
+    }
+
+    /// Write `current` relative to `previous`, which must be smaller.
+    ///
+    /// Small differences take as few as 1 bit, and differences above 69914
+    /// are written in full (32 bits). This also makes a reasonable variable
+    /// length encoding for small counts, relative to 0.
+    pub fn write_int_relative(&mut self, previous: u32, current: u32) -> io::Result<()> {
+        if previous >= current {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} must be greater than {}", current, previous),
+            ));
+        }
+        let difference = current - previous;
+
+        for (min, max) in RELATIVE_RANGES {
+            let in_range = difference <= max;
+            self.write_bool(in_range)?;
+            if in_range {
+                return self.write_int(difference, min, max);
+            }
+        }
+
+        self.write_bits(current, 32)
+    }
+}
+
+impl<'a> io::Write for BitWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_bytes(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads values written by a `BitWriter`.
+///
+/// Reading past the end of the buffer returns an `UnexpectedEof` error, and
+/// reading an integer outside of its expected range returns an `InvalidData`
+/// error.
+pub struct BitReader<'a> {
+    buffer: &'a [u8],
+    bits_read: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(buffer: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            buffer,
+            bits_read: 0,
+        }
+    }
+
+    pub fn bits_read(&self) -> usize {
+        self.bits_read
+    }
+
+    pub fn bits_remaining(&self) -> usize {
+        self.buffer.len() * 8 - self.bits_read
+    }
+
+    /// Read `bits` bits (at most 32).
+    pub fn read_bits(&mut self, bits: u32) -> io::Result<u32> {
+        assert!(bits <= u32::BITS);
+
+        if bits as usize > self.bits_remaining() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "bit reader reached the end of the buffer",
+            ));
+        }
+
+        let mut value = 0u32;
+        let mut shift = 0;
+        while shift < bits {
+            let offset = (self.bits_read % 8) as u32;
+            let count = (bits - shift).min(8 - offset);
+            let chunk = (self.buffer[self.bits_read / 8] >> offset) as u32 & ((1 << count) - 1);
+
+            value |= chunk << shift;
+            shift += count;
+            self.bits_read += count as usize;
+        }
+
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    /// Read an integer written with `BitWriter::write_int(_, min, max)`.
+    pub fn read_int(&mut self, min: u32, max: u32) -> io::Result<u32> {
+        let value = self.read_bits(bits_required(min, max))?;
+        match min.checked_add(value) {
+            Some(value) if value <= max => Ok(value),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("integer is outside of [{}, {}]", min, max),
+            )),
+        }
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> io::Result<()> {
+        if bytes.len() * 8 > self.bits_remaining() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "bit reader reached the end of the buffer",
+            ));
+        }
+
+        for byte in bytes {
+            *byte = self.read_bits(8)? as u8;
+        }
+
+        Ok(())
+    }
+
+    /// Read a sequence written with `BitWriter::write_sequence_relative(sequence1, _)`.
+    pub fn read_sequence_relative(&mut self, sequence1: u16) -> io::Result<u16> {
+        let current = self.read_int_relative(sequence1 as u32)?;
+
+        Ok((current % 65536) as u16)
+    }
+
+    /// Read an integer written with `BitWriter::write_int_relative(previous, _)`.
+    pub fn read_int_relative(&mut self, previous: u32) -> io::Result<u32> {
+        for (min, max) in RELATIVE_RANGES {
+            if self.read_bool()? {
+                let difference = self.read_int(min, max)?;
+                return previous.checked_add(difference).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "relative integer overflowed")
+                });
+            }
+        }
+
+        self.read_bits(32)
+    }
+}
+
+impl<'a> io::Read for BitReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.bits_remaining() / 8);
+        self.read_bytes(&mut buf[..len])?;
+        Ok(len)
+    }
+}
+
+/// Ranges for encoding relative integers, tried in order (see yojimbo's
+/// `serialize_int_relative`). Larger differences are written in full.
+const RELATIVE_RANGES: [(u32, u32); 6] = [
+    (1, 1),
+    (2, 6),
+    (7, 23),
+    (24, 280),
+    (281, 4377),
+    (4378, 69914),
+];
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    #[test]
+    fn test_bits_required() {
+        assert_eq!(bits_required(0, 0), 0);
+        assert_eq!(bits_required(0, 1), 1);
+        assert_eq!(bits_required(1, 6), 3);
+        assert_eq!(bits_required(0, 255), 8);
+        assert_eq!(bits_required(0, 256), 9);
+        assert_eq!(bits_required(0, u32::MAX), 32);
+    }
+
+    #[test]
+    fn test_write_read_values() {
+        let mut buffer = [0xffu8; 64];
+        let mut writer = BitWriter::new(&mut buffer);
+
+        writer.write_bits(0, 1).unwrap();
+        writer.write_bits(1, 1).unwrap();
+        writer.write_bits(10, 8).unwrap();
+        writer.write_bits(255, 8).unwrap();
+        writer.write_bits(1000, 10).unwrap();
+        writer.write_bits(50000, 16).unwrap();
+        writer.write_bits(9999999, 32).unwrap();
+        writer.write_bool(true).unwrap();
+        writer.write_int(5, 0, 20).unwrap();
+        writer.write_int(1000, 1000, 1000).unwrap();
+        writer.write_bytes(b"hello").unwrap();
+        writer.write_all(&[1, 2, 3]).unwrap();
+
+        let bits_written = writer.bits_written();
+        assert_eq!(
+            bits_written,
+            1 + 1 + 8 + 8 + 10 + 16 + 32 + 1 + 5 + 0 + 40 + 24
+        );
+        let bytes_written = writer.bytes_written();
+        assert_eq!(bytes_written, 19);
+
+        let mut measure = BitWriter::measure();
+        measure.write_bits(9999999, 32).unwrap();
+        measure.write_bytes(b"hello").unwrap();
+        assert_eq!(measure.bits_written(), 72);
+
+        let mut reader = BitReader::new(&buffer[..bytes_written]);
+
+        assert_eq!(reader.read_bits(1).unwrap(), 0);
+        assert_eq!(reader.read_bits(1).unwrap(), 1);
+        assert_eq!(reader.read_bits(8).unwrap(), 10);
+        assert_eq!(reader.read_bits(8).unwrap(), 255);
+        assert_eq!(reader.read_bits(10).unwrap(), 1000);
+        assert_eq!(reader.read_bits(16).unwrap(), 50000);
+        assert_eq!(reader.read_bits(32).unwrap(), 9999999);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_int(0, 20).unwrap(), 5);
+        assert_eq!(reader.read_int(1000, 1000).unwrap(), 1000);
+        let mut hello = [0u8; 5];
+        reader.read_bytes(&mut hello).unwrap();
+        assert_eq!(&hello, b"hello");
+        let mut rest = [0u8; 3];
+        reader.read_exact(&mut rest).unwrap();
+        assert_eq!(rest, [1, 2, 3]);
+
+        assert_eq!(reader.bits_read(), bits_written);
+        assert!(reader.bits_remaining() < 8);
+        assert!(reader.read_exact(&mut [0u8]).is_err());
+    }
+
+    #[test]
+    fn test_write_bits_from() {
+        let mut source = [0u8; 16];
+        let mut writer = BitWriter::new(&mut source);
+        writer.write_bits(0x1234567, 27).unwrap();
+        writer.write_bits(0xabcdef, 24).unwrap();
+        writer.write_bool(true).unwrap();
+        let source_bits = writer.bits_written();
+
+        // copy to an unaligned position, after a single bit
+        let mut buffer = [0xffu8; 16];
+        let mut writer = BitWriter::new(&mut buffer);
+        writer.write_bool(false).unwrap();
+        writer.write_bits_from(&source, source_bits).unwrap();
+        writer.write_bits(0x5, 3).unwrap();
+        assert_eq!(writer.bits_written(), 1 + source_bits + 3);
+
+        let mut reader = BitReader::new(&buffer);
+        assert!(!reader.read_bool().unwrap());
+        assert_eq!(reader.read_bits(27).unwrap(), 0x1234567);
+        assert_eq!(reader.read_bits(24).unwrap(), 0xabcdef);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_bits(3).unwrap(), 0x5);
+    }
+
+    #[test]
+    fn test_write_past_end_fails() {
+        let mut buffer = [0u8; 2];
+        let mut writer = BitWriter::new(&mut buffer);
+
+        writer.write_bits(0xfff, 12).unwrap();
+        assert!(writer.write_bits(0x1f, 5).is_err());
+        writer.write_bits(0xf, 4).unwrap();
+        assert_eq!(writer.bits_written(), 16);
+
+        let mut reader = BitReader::new(&buffer);
+        assert_eq!(reader.read_bits(16).unwrap(), 0xffff);
+        assert_eq!(
+            reader.read_bits(1).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_int_out_of_range() {
+        let mut buffer = [0u8; 4];
+        let mut writer = BitWriter::new(&mut buffer);
+
+        assert!(writer.write_int(7, 0, 6).is_err());
+        writer.write_bits(7, 3).unwrap();
+
+        let mut reader = BitReader::new(&buffer);
+        assert_eq!(
+            reader.read_int(0, 6).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_sequence_relative() {
+        let pairs = [
+            (0u16, 1u16),
+            (100, 105),
+            (100, 123),
+            (65535, 0),
+            (65530, 10),
+            (0, 280),
+            (0, 4000),
+            (10, 60000),
+            (60000, 10),
+            (1, 0),
+        ];
+
+        let mut buffer = [0u8; 256];
+        let mut writer = BitWriter::new(&mut buffer);
+        for (sequence1, sequence2) in pairs {
+            writer
+                .write_sequence_relative(sequence1, sequence2)
+                .unwrap();
+        }
+        let bytes_written = writer.bytes_written();
+
+        let mut reader = BitReader::new(&buffer[..bytes_written]);
+        for (sequence1, sequence2) in pairs {
+            assert_eq!(reader.read_sequence_relative(sequence1).unwrap(), sequence2);
+        }
+
+        // consecutive sequences only take a single bit
+        let mut measure = BitWriter::measure();
+        measure.write_sequence_relative(65535, 0).unwrap();
+        assert_eq!(measure.bits_written(), 1);
+
+        assert!(BitWriter::measure().write_sequence_relative(5, 5).is_err());
+    }
+
+    #[test]
+    fn test_int_relative() {
+        let values = [
+            1u32,
+            2,
+            6,
+            7,
+            23,
+            24,
+            280,
+            281,
+            4377,
+            4378,
+            69914,
+            69915,
+            u32::MAX,
+        ];
+
+        let mut buffer = [0u8; 256];
+        let mut writer = BitWriter::new(&mut buffer);
+        for value in values {
+            writer.write_int_relative(0, value).unwrap();
+        }
+        let bytes_written = writer.bytes_written();
+
+        let mut reader = BitReader::new(&buffer[..bytes_written]);
+        for value in values {
+            assert_eq!(reader.read_int_relative(0).unwrap(), value);
+        }
+
+        let mut measure = BitWriter::measure();
+        measure.write_int_relative(0, 50).unwrap();
+        assert_eq!(measure.bits_written(), 4 + 9);
+
+        assert!(BitWriter::measure().write_int_relative(0, 0).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use std::{collections::vec_deque::Drain, fmt, sync::Arc};
+
 use crate::{
     config::{ChannelConfig, ChannelType},
     message::NetworkMessage,
@@ -8,20 +10,24 @@ use self::{processor::Processor, reliable::Reliable, unreliable::Unreliable};
 mod channel_packet_data;
 mod processor;
 mod reliable;
-mod sequence_buffer;
 mod unreliable;
 
 // TODO: encapsulate this better
-pub(crate) use channel_packet_data::ChannelPacketData;
+pub(crate) use channel_packet_data::{
+    ChannelPacketData, QueuedMessage, ReadPacketError, SerializedMessage,
+};
 
 #[cfg(feature = "serialize_check")]
 pub(crate) const SERIALIZE_CHECK_VALUE: u32 = 0x12345678;
 
 pub(crate) const CONSERVATIVE_MESSAGE_HEADER_BITS: usize = 32;
-// pub(crate) const CONSERVATIVE_FRAGMENT_HEADER_BITS: usize = 64;
+pub(crate) const CONSERVATIVE_FRAGMENT_HEADER_BITS: usize = 64;
 pub(crate) const CONSERVATIVE_CHANNEL_HEADER_BITS: usize = 32;
 pub(crate) const CONSERVATIVE_PACKET_HEADER_BITS: usize = 16;
 
+/// Priority of messages sent without one (see `Client::send_message_with_priority`).
+pub const DEFAULT_MESSAGE_PRIORITY: f32 = 1.0;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChannelErrorLevel {
     /// No error. All is well.
@@ -53,29 +59,113 @@ pub enum ChannelErrorLevel {
     /// information for all channels) and remove some messages from the send
     /// queue.
     SendQueueFull,
-    /// The channel received a packet containing data for blocks, but this channel is configured to disable blocks. See ChannelConfig::disableBlocks.
+    /// The channel received a packet containing data for blocks, or the user tried to send a message with a block attached, but this channel is configured to disable blocks. See `ChannelConfig::disable_blocks`.
     BlocksDisabled,
-    /// Serialize read failed for a message sent to this channel. Check your message serialize functions, one of them is returning false on serialize read. This can also be caused by a desync in message read and write.
+    /// The user tried to send a message with an empty block, or a block larger than
+    /// `ChannelConfig::max_block_size`.
+    InvalidBlockSize,
+    /// The user tried to send a message with a block attached, but the message and the
+    /// block's first fragment (which are sent together) don't fit in a packet.
+    MessageTooLarge,
+    /// A message sent on this channel failed to serialize (messages are serialized once, when
+    /// they are sent). Check your message serialize functions, one of them is returning an error.
     FailedToSerialize,
     /// The channel tried to allocate some memory but couldn't.
     OutOfMemory,
+    /// A message on a `ReliableOrdered` channel wasn't acked within
+    /// `ChannelConfig::message_time_to_live`.
+    MessageExpired,
+}
+
+/// Why a message couldn't be sent with `try_send_message`. Hands the message back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError<M> {
+    /// The channel's send queue is full. Try again after the queue drains (for reliable
+    /// channels, once the messages are acked).
+    QueueFull(M),
+    /// Not connected.
+    NotConnected(M),
+    /// The connection, or this channel, is in an error state (see `ChannelErrorLevel`), so
+    /// nothing more can be sent on it. The connection is closed on the next update.
+    ConnectionFailed(M),
+    /// There is no channel with this index.
+    InvalidChannel(M),
+    /// There is no client slot with this index.
+    InvalidClient(M),
+    /// The message has a block attached, but the channel doesn't have blocks enabled. See
+    /// `ChannelConfig::disable_blocks`.
+    BlocksDisabled(M),
+    /// The message's block is empty, or larger than `ChannelConfig::max_block_size`.
+    InvalidBlockSize(M),
+    /// The message has a block attached, and together with the block's first fragment it
+    /// doesn't fit in a packet.
+    MessageTooLarge(M),
+    /// The message returned an error from `NetworkMessage::serialize_bits`.
+    FailedToSerialize(M),
+}
+
+impl<M> SendError<M> {
+    /// Take back the message which couldn't be sent.
+    pub fn into_message(self) -> M {
+        match self {
+            SendError::QueueFull(message)
+            | SendError::NotConnected(message)
+            | SendError::ConnectionFailed(message)
+            | SendError::InvalidChannel(message)
+            | SendError::InvalidClient(message)
+            | SendError::BlocksDisabled(message)
+            | SendError::InvalidBlockSize(message)
+            | SendError::MessageTooLarge(message)
+            | SendError::FailedToSerialize(message) => message,
+        }
+    }
 }
 
+impl<M> fmt::Display for SendError<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::QueueFull(_) => write!(f, "send queue is full"),
+            SendError::NotConnected(_) => write!(f, "not connected"),
+            SendError::ConnectionFailed(_) => write!(f, "connection failed"),
+            SendError::InvalidChannel(_) => write!(f, "invalid channel index"),
+            SendError::InvalidClient(_) => write!(f, "invalid client index"),
+            SendError::BlocksDisabled(_) => write!(f, "blocks are disabled on this channel"),
+            SendError::InvalidBlockSize(_) => write!(f, "block is empty or too large"),
+            SendError::MessageTooLarge(_) => write!(f, "message is too large to send with a block"),
+            SendError::FailedToSerialize(_) => write!(f, "failed to serialize message"),
+        }
+    }
+}
+
+impl<M: fmt::Debug> std::error::Error for SendError<M> {}
+
 pub struct Channel<M> {
     config: ChannelConfig,
     channel_index: usize,
+    /// The most bits this channel can write to a packet.
+    max_channel_bits: usize,
     error_level: ChannelErrorLevel,
     processor: Box<dyn Processor<M>>,
     counters: ChannelCounters,
 }
 
 impl<M: NetworkMessage> Channel<M> {
-    pub(crate) fn new(config: ChannelConfig, channel_index: usize, time: f64) -> Channel<M> {
+    pub(crate) fn new(
+        config: ChannelConfig,
+        channel_index: usize,
+        max_packet_size: usize,
+        time: f64,
+    ) -> Channel<M> {
         let processor: Box<dyn Processor<M>> = match config.kind {
-            ChannelType::ReliableOrdered => Box::new(Reliable::new(config.clone(), time)),
-            ChannelType::UnreliableUnordered => Box::new(Unreliable::new(&config)),
+            ChannelType::ReliableOrdered | ChannelType::ReliableUnordered => {
+                Box::new(Reliable::new(config.clone(), time))
+            }
+            ChannelType::UnreliableUnordered | ChannelType::UnreliableSequenced => {
+                Box::new(Unreliable::new(&config, max_packet_size, time))
+            }
         };
         Channel {
+            max_channel_bits: config.max_channel_bits(max_packet_size),
             config,
             channel_index,
             error_level: ChannelErrorLevel::None,
@@ -102,7 +192,9 @@ impl<M: NetworkMessage> Channel<M> {
     ///
     /// Called by Connection::advance_time for each channel configured on the connection.
     pub(crate) fn advance_time(&mut self, time: f64) {
-        self.processor.advance_time(time);
+        if let Err(level) = self.processor.advance_time(time) {
+            self.set_error_level(level);
+        }
     }
 
     /// Get channel packet data for this channel.
@@ -110,7 +202,7 @@ impl<M: NetworkMessage> Channel<M> {
         &mut self,
         packet_sequence: u16,
         available_bits: usize,
-    ) -> (ChannelPacketData<M>, usize) {
+    ) -> (ChannelPacketData<SerializedMessage>, usize) {
         self.processor.packet_data(
             &self.config,
             self.channel_index,
@@ -119,6 +211,15 @@ impl<M: NetworkMessage> Channel<M> {
         )
     }
 
+    /// Get the messages to deliver straight to a channel in the same process.
+    pub(crate) fn local_packet_data(&mut self, packet_sequence: u16) -> ChannelPacketData<M> {
+        if self.error_level() != ChannelErrorLevel::None {
+            return ChannelPacketData::empty();
+        }
+        self.processor
+            .local_packet_data(self.channel_index, packet_sequence)
+    }
+
     pub(crate) fn process_packet_data(
         &mut self,
         packet_data: ChannelPacketData<M>,
@@ -127,15 +228,33 @@ impl<M: NetworkMessage> Channel<M> {
         if self.error_level() != ChannelErrorLevel::None {
             return;
         }
+        if packet_data.block.is_some() && !self.config.blocks_enabled() {
+            self.set_error_level(ChannelErrorLevel::BlocksDisabled);
+            return;
+        }
         // TODO: detect failed_to_serialize (maybe do this in the connection?)
-        self.processor
-            .process_packet_data(packet_data, packet_sequence);
+        if let Err(level) = self
+            .processor
+            .process_packet_data(packet_data, packet_sequence)
+        {
+            self.set_error_level(level);
+        }
     }
 
     pub(crate) fn process_ack(&mut self, packet_sequence: u16) {
         self.processor.process_ack(packet_sequence)
     }
 
+    /// Take the ids of the sent messages which have been acked.
+    pub(crate) fn poll_acked(&mut self) -> Drain<'_, u16> {
+        self.processor.message_acks().take_acked()
+    }
+
+    /// Take the ids of the sent messages which were lost (unreliable channels only).
+    pub(crate) fn poll_lost(&mut self) -> Drain<'_, u16> {
+        self.processor.message_acks().take_lost()
+    }
+
     pub(crate) fn error_level(&self) -> ChannelErrorLevel {
         self.error_level
     }
@@ -148,19 +267,156 @@ impl<M: NetworkMessage> Channel<M> {
         self.processor.has_messages_to_send()
     }
 
-    pub(crate) fn send_message(&mut self, message: M) {
+    /// Returns the message id, or None if the message was dropped.
+    ///
+    /// `priority` is clamped to a finite value of at least 0 (NaN becomes 0).
+    pub(crate) fn send_message(&mut self, message: M, priority: f32) -> Option<u16> {
+        let priority = if priority.is_nan() {
+            0.0
+        } else {
+            priority.clamp(0.0, f32::MAX)
+        };
+
         if self.error_level() != ChannelErrorLevel::None {
-            return;
+            return None;
+        }
+
+        if let Err(level) = self.check_can_queue(&message) {
+            self.set_error_level(level);
+            return None;
+        }
+
+        let serialized = match SerializedMessage::new(&message) {
+            Ok(serialized) => serialized,
+            Err(err) => {
+                log::error!("failed to serialize message: {:?}", err);
+                self.set_error_level(ChannelErrorLevel::FailedToSerialize);
+                return None;
+            }
+        };
+
+        if let Err(level) = self.check_fits(&message, &serialized) {
+            self.set_error_level(level);
+            return None;
+        }
+
+        Some(self.queue_message(QueuedMessage::Owned(message), serialized, priority))
+    }
+
+    /// Like `send_message`, for a message which is also sent on other connections.
+    pub(crate) fn send_shared_message(
+        &mut self,
+        message: &Arc<M>,
+        serialized: &SerializedMessage,
+    ) -> Option<u16> {
+        if self.error_level() != ChannelErrorLevel::None {
+            return None;
         }
 
+        if let Err(level) = self
+            .check_can_queue(message)
+            .and_then(|()| self.check_fits(message, serialized))
+        {
+            self.set_error_level(level);
+            return None;
+        }
+
+        Some(self.queue_message(
+            QueuedMessage::Shared(message.clone()),
+            serialized.clone(),
+            DEFAULT_MESSAGE_PRIORITY,
+        ))
+    }
+
+    /// Like `send_message`, but a full send queue, a block which can't be sent on this channel,
+    /// or a message which fails to serialize hands the message back instead of putting the
+    /// channel in an error state.
+    pub(crate) fn try_send_message(&mut self, message: M) -> Result<u16, SendError<M>> {
+        if self.error_level() != ChannelErrorLevel::None {
+            return Err(SendError::ConnectionFailed(message));
+        }
+        match self.check_can_queue(&message) {
+            Ok(()) => {}
+            Err(ChannelErrorLevel::BlocksDisabled) => {
+                return Err(SendError::BlocksDisabled(message))
+            }
+            Err(ChannelErrorLevel::InvalidBlockSize) => {
+                return Err(SendError::InvalidBlockSize(message))
+            }
+            Err(_) => return Err(SendError::QueueFull(message)),
+        }
+        let serialized = match SerializedMessage::new(&message) {
+            Ok(serialized) => serialized,
+            Err(_) => return Err(SendError::FailedToSerialize(message)),
+        };
+        if self.check_fits(&message, &serialized).is_err() {
+            return Err(SendError::MessageTooLarge(message));
+        }
+
+        Ok(self.queue_message(
+            QueuedMessage::Owned(message),
+            serialized,
+            DEFAULT_MESSAGE_PRIORITY,
+        ))
+    }
+
+    /// The error the channel would go into if `message` was queued.
+    fn check_can_queue(&self, message: &M) -> Result<(), ChannelErrorLevel> {
         if !self.can_send_message() {
-            self.set_error_level(ChannelErrorLevel::SendQueueFull);
-            return;
+            return Err(ChannelErrorLevel::SendQueueFull);
         }
+        if let Some(block) = message.block() {
+            if !self.config.blocks_enabled() {
+                return Err(ChannelErrorLevel::BlocksDisabled);
+            }
+            if block.is_empty() || block.len() > self.config.max_block_size {
+                log::debug!(
+                    "block size must be between 1 and {} bytes, but was {}",
+                    self.config.max_block_size,
+                    block.len()
+                );
+                return Err(ChannelErrorLevel::InvalidBlockSize);
+            }
+        }
+        Ok(())
+    }
+
+    /// The error the channel would go into if `message` was queued, since it can never be
+    /// written to a packet.
+    fn check_fits(
+        &self,
+        message: &M,
+        serialized: &SerializedMessage,
+    ) -> Result<(), ChannelErrorLevel> {
+        let Some(block) = message.block() else { return Ok(()) };
+        // the first fragment is sent along with the message
+        let first_fragment_bits = CONSERVATIVE_FRAGMENT_HEADER_BITS
+            + 8 * block.len().min(self.config.block_fragment_size)
+            + CONSERVATIVE_MESSAGE_HEADER_BITS
+            + serialized.bits();
+        if first_fragment_bits > self.max_channel_bits {
+            log::debug!(
+                "message and first block fragment are {} bits, but at most {} bits fit in a packet",
+                first_fragment_bits,
+                self.max_channel_bits
+            );
+            return Err(ChannelErrorLevel::MessageTooLarge);
+        }
+        Ok(())
+    }
 
-        self.processor.send_message(message);
+    /// Queue a message which passed `check_can_queue` and `check_fits`, returning its id.
+    fn queue_message(
+        &mut self,
+        message: QueuedMessage<M>,
+        serialized: SerializedMessage,
+        priority: f32,
+    ) -> u16 {
+        let message_id = self.processor.send_message(message, serialized, priority);
 
         self.counters.sent += 1;
+
+        message_id
     }
 
     pub(crate) fn receive_message(&mut self) -> Option<(u16, M)> {
@@ -176,7 +432,7 @@ impl<M: NetworkMessage> Channel<M> {
     }
 
     /// All errors go through this function to make debug logging easier.
-    fn set_error_level(&mut self, level: ChannelErrorLevel) {
+    pub(crate) fn set_error_level(&mut self, level: ChannelErrorLevel) {
         if self.error_level != level && level != ChannelErrorLevel::None {
             log::error!(
                 "channel {} went into error state: {:?}",
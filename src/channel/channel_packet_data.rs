@@ -1,9 +1,12 @@
-use std::io::{self, Cursor};
-
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    fmt::{self, Debug},
+    io,
+    sync::Arc,
+};
 
 use crate::{
-    config::{ChannelType, ConnectionConfig},
+    bitpacker::{bits_required, BitReader, BitWriter},
+    config::{ChannelConfig, ChannelType, ConnectionConfig},
     message::NetworkMessage,
 };
 
@@ -12,7 +15,9 @@ use super::SERIALIZE_CHECK_VALUE;
 
 /// Contains a series of messages sent on `channel_index`.
 ///
-/// Defines how the channel index is serialized to packets.
+/// Defines how the channel index is serialized to packets. Packets are written
+/// from `ChannelPacketData<SerializedMessage>`, so channels never have to copy
+/// the messages in their send queues, and read into `ChannelPacketData<M>`.
 pub(crate) struct ChannelPacketData<M> {
     pub(crate) channel_index: usize,
     /// List of `(message_id, and message)`
@@ -29,221 +34,469 @@ pub(crate) struct ChannelPacketData<M> {
     ///
     /// Bear in mind that the message ID will wrap at the bounds of u16.
     pub(crate) messages: Vec<(u16, M)>,
+    /// A fragment of a block message.
+    ///
+    /// If this is set, `messages` is empty; a packet carries either a list of
+    /// messages or a single block fragment for a channel.
+    pub(crate) block: Option<BlockFragment<M>>,
 }
 
-impl<M: NetworkMessage> ChannelPacketData<M> {
-    pub(crate) fn serialize(
-        &self,
-        config: &ConnectionConfig,
-        dest: &mut Cursor<&mut [u8]>,
-    ) -> Result<(), M::Error> {
-        dest.write_u16::<LittleEndian>(self.channel_index.try_into().unwrap())
-            .unwrap();
-        let config = &config.channels[self.channel_index];
+/// Error reading channel packet data from a received packet.
+#[derive(Debug)]
+pub(crate) enum ReadPacketError<E> {
+    /// The packet ended early or contains an out of range value.
+    Io(io::Error),
+    /// The packet refers to a channel that doesn't exist.
+    InvalidChannel(usize),
+    /// The packet has more messages for `channel_index` than
+    /// `ChannelConfig::max_messages_per_packet` allows.
+    TooManyMessages {
+        channel_index: usize,
+        message_count: usize,
+    },
+    /// The check value written after each message with the `serialize_check`
+    /// feature didn't match.
+    #[cfg(feature = "serialize_check")]
+    SerializeCheckFailed(u32),
+    /// The packet has a block fragment for a channel which doesn't have blocks enabled.
+    BlocksDisabled(usize),
+    /// A message on `channel_index` failed to deserialize.
+    Message { channel_index: usize, error: E },
+}
 
-        // TODO: block messages
+impl<E: Debug> fmt::Display for ReadPacketError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadPacketError::Io(err) => write!(f, "malformed packet: {}", err),
+            ReadPacketError::InvalidChannel(channel_index) => {
+                write!(f, "channel {} does not exist", channel_index)
+            }
+            #[cfg(feature = "serialize_check")]
+            ReadPacketError::SerializeCheckFailed(check_value) => write!(
+                f,
+                "expected check value {} but found {}",
+                SERIALIZE_CHECK_VALUE, check_value
+            ),
+            ReadPacketError::BlocksDisabled(channel_index) => write!(
+                f,
+                "packet has a block fragment for channel {}, which has blocks disabled",
+                channel_index
+            ),
+            ReadPacketError::TooManyMessages {
+                channel_index,
+                message_count,
+            } => write!(
+                f,
+                "packet has {} messages for channel {}, which is over the limit",
+                message_count, channel_index
+            ),
+            ReadPacketError::Message {
+                channel_index,
+                error,
+            } => write!(
+                f,
+                "failed to deserialize message on channel {}: {:?}",
+                channel_index, error
+            ),
+        }
+    }
+}
 
-        let has_messages = !self.messages.is_empty();
+impl<E> From<io::Error> for ReadPacketError<E> {
+    fn from(err: io::Error) -> Self {
+        ReadPacketError::Io(err)
+    }
+}
 
-        dest.write_u8(if has_messages { 1 } else { 0 }).unwrap();
+/// A single fragment of a block attached to a message.
+pub(crate) struct BlockFragment<M> {
+    /// ID of the block message this fragment belongs to.
+    pub(crate) message_id: u16,
+    pub(crate) fragment_id: u16,
+    pub(crate) num_fragments: u16,
+    pub(crate) fragment_data: Vec<u8>,
+    /// The block message itself, which is only sent with the first fragment.
+    pub(crate) message: Option<M>,
+}
 
-        if !has_messages {
-            return Ok(());
-        }
+/// A message written with `NetworkMessage::serialize_bits`, ready to be copied into packets.
+///
+/// Messages are serialized once when they are sent; cloning only bumps a reference count, so
+/// resends (and sends of the same message to many connections) don't serialize it again.
+#[derive(Clone)]
+pub(crate) struct SerializedMessage {
+    data: Arc<[u8]>,
+    bits: usize,
+}
 
-        debug_assert!(config.max_messages_per_packet - 1 <= u8::MAX as usize,);
-        assert!(self.messages.len() <= config.max_messages_per_packet);
-        dest.write_u8((self.messages.len() - 1).try_into().unwrap())
-            .unwrap();
+impl SerializedMessage {
+    pub(crate) fn new<M: NetworkMessage>(message: &M) -> Result<SerializedMessage, M::Error> {
+        let mut writer = BitWriter::measure();
+        message.serialize_bits(&mut writer)?;
+        let bits = writer.bits_written();
 
-        match config.kind {
-            ChannelType::UnreliableUnordered => self.serialize_unordered(dest)?,
-            ChannelType::ReliableOrdered => self.serialize_ordered(dest)?,
-        }
+        let mut data = vec![0u8; writer.bytes_written()];
+        let mut writer = BitWriter::new(&mut data[..]);
+        message.serialize_bits(&mut writer)?;
+        debug_assert_eq!(writer.bits_written(), bits);
 
-        Ok(())
+        Ok(SerializedMessage {
+            data: data.into(),
+            bits,
+        })
     }
 
-    pub(crate) fn deserialize(
-        config: &ConnectionConfig,
-        src: &mut Cursor<&[u8]>,
-    ) -> Result<ChannelPacketData<M>, M::Error> {
-        let channel_index = src.read_u16::<LittleEndian>().unwrap() as usize;
-        let config = &config.channels[channel_index];
+    /// The number of bits this message takes in a packet.
+    pub(crate) fn bits(&self) -> usize {
+        self.bits
+    }
 
-        // TODO: block messages
+    /// Read the message back, without its block.
+    pub(crate) fn read<M: NetworkMessage>(&self) -> Result<M, M::Error> {
+        M::deserialize_bits(&mut BitReader::new(&self.data))
+    }
 
-        let has_messages = src.read_u8().unwrap() == 1;
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits_from(&self.data, self.bits).unwrap();
+    }
+}
 
-        if !has_messages {
-            return Ok(ChannelPacketData::empty());
+/// A message waiting in a send queue, which may be shared with the send queues of other
+/// connections (see `Server::send_message_to`).
+pub(crate) enum QueuedMessage<M> {
+    Owned(M),
+    Shared(Arc<M>),
+}
+
+impl<M: NetworkMessage> QueuedMessage<M> {
+    pub(crate) fn get(&self) -> &M {
+        match self {
+            QueuedMessage::Owned(message) => message,
+            QueuedMessage::Shared(message) => message,
         }
+    }
 
-        let message_count = 1 + src.read_u8().unwrap() as usize;
+    /// Take the message to deliver it locally.
+    ///
+    /// Messages are not `Clone`, so a message which is still shared with other send queues is
+    /// read back from `serialized`, and its block (if any) attached again.
+    pub(crate) fn into_message(self, serialized: &SerializedMessage) -> M {
+        let shared = match self {
+            QueuedMessage::Owned(message) => return message,
+            QueuedMessage::Shared(shared) => shared,
+        };
+        let shared = match Arc::try_unwrap(shared) {
+            Ok(message) => return message,
+            Err(shared) => shared,
+        };
+
+        let mut message = serialized
+            .read::<M>()
+            .expect("failed to read back a serialized message");
+        if let Some(block) = shared.block() {
+            message.attach_block(block.to_vec());
+        }
 
-        debug_assert!(config.max_messages_per_packet - 1 <= u8::MAX as usize);
-        assert!(message_count <= config.max_messages_per_packet);
+        message
+    }
+}
 
-        let mut messages = Vec::with_capacity(message_count);
+impl ChannelPacketData<SerializedMessage> {
+    pub(crate) fn serialize(&self, config: &ConnectionConfig, writer: &mut BitWriter) {
+        let num_channels = config.channels.len() as u32;
+        writer
+            .write_int(self.channel_index as u32, 0, num_channels - 1)
+            .unwrap();
+        let config = &config.channels[self.channel_index];
+
+        writer.write_bool(self.block.is_some()).unwrap();
+
+        if let Some(block) = &self.block {
+            return Self::serialize_block(config, block, writer);
+        }
+
+        let has_messages = !self.messages.is_empty();
+
+        writer.write_bool(has_messages).unwrap();
+
+        if !has_messages {
+            return;
+        }
+
+        assert!(self.messages.len() <= config.max_messages_per_packet);
+        // the count is written relative to 0, so small counts take only a few bits
+        writer
+            .write_int_relative(0, self.messages.len() as u32)
+            .unwrap();
 
         match config.kind {
-            ChannelType::UnreliableUnordered => {
-                ChannelPacketData::deserialize_unordered(src, message_count, &mut messages)?
+            ChannelType::UnreliableUnordered | ChannelType::UnreliableSequenced => {
+                self.serialize_unordered(writer)
             }
-            ChannelType::ReliableOrdered => {
-                ChannelPacketData::deserialize_ordered(src, message_count, &mut messages)?
+            ChannelType::ReliableOrdered | ChannelType::ReliableUnordered => {
+                self.serialize_ordered(writer)
             }
         }
-
-        Ok(ChannelPacketData {
-            channel_index,
-            messages,
-        })
     }
 
-    pub(crate) fn serialize_unordered(
-        &self,
-        mut writer: &mut Cursor<&mut [u8]>,
-    ) -> Result<(), M::Error> {
-        for (_, message) in &self.messages {
-            message.serialize(&mut writer)?;
+    fn serialize_block(
+        config: &ChannelConfig,
+        block: &BlockFragment<SerializedMessage>,
+        writer: &mut BitWriter,
+    ) {
+        debug_assert!(block.fragment_data.len() <= config.block_fragment_size);
+
+        let max_fragments = config.max_fragments_per_block() as u32;
+
+        writer.write_bits(block.message_id as u32, 16).unwrap();
+        writer
+            .write_int(block.num_fragments as u32, 1, max_fragments)
+            .unwrap();
+        writer
+            .write_int(block.fragment_id as u32, 0, block.num_fragments as u32 - 1)
+            .unwrap();
+        writer
+            .write_int(
+                block.fragment_data.len() as u32,
+                1,
+                config.block_fragment_size as u32,
+            )
+            .unwrap();
+        writer.write_bytes(&block.fragment_data[..]).unwrap();
+
+        // the block message is sent along with the first fragment
+        if block.fragment_id == 0 {
+            let message = block
+                .message
+                .as_ref()
+                .expect("first block fragment is missing its message");
+            message.write(writer);
 
             Self::serialize_check(writer);
         }
-
-        Ok(())
     }
 
-    pub(crate) fn deserialize_unordered(
-        mut reader: &mut Cursor<&[u8]>,
-        message_count: usize,
-        messages: &mut Vec<(u16, M)>,
-    ) -> Result<(), M::Error> {
-        for _ in 0..message_count {
-            // the ID is actually decided in `Processor::process_packet_data` - set 0 for now
-            messages.push((0, M::deserialize(&mut reader)?));
+    fn serialize_unordered(&self, writer: &mut BitWriter) {
+        for (_, message) in &self.messages {
+            message.write(writer);
 
-            Self::deserialize_check(reader);
+            Self::serialize_check(writer);
         }
-
-        Ok(())
     }
 
-    pub(crate) fn serialize_ordered(
-        &self,
-        mut writer: &mut Cursor<&mut [u8]>,
-    ) -> Result<(), M::Error> {
+    fn serialize_ordered(&self, writer: &mut BitWriter) {
         /*
            this order (IDs list followed by messages list) is taken from
            yojimbo (which serializes IDs relative to previous ID for
            compression)
         */
 
-        // write the message IDs
+        // write the message IDs; the first in full, the rest relative to the previous ID
+        let mut previous_id = None;
         for (id, _) in &self.messages {
-            // TODO: serialize sequence relative
-            writer.write_u16::<LittleEndian>(*id).unwrap();
+            match previous_id {
+                None => writer.write_bits(*id as u32, 16).unwrap(),
+                Some(previous_id) => writer.write_sequence_relative(previous_id, *id).unwrap(),
+            }
+            previous_id = Some(*id);
         }
 
         Self::serialize_check(writer);
 
         // write the message contents
         for (_, message) in &self.messages {
-            message.serialize(&mut writer)?;
+            message.write(writer);
 
             Self::serialize_check(writer);
         }
+    }
+
+    #[inline]
+    fn serialize_check(_writer: &mut BitWriter) {
+        #[cfg(feature = "serialize_check")]
+        {
+            _writer
+                .write_bits(SERIALIZE_CHECK_VALUE, 32)
+                .expect("failed to write check value");
+        }
+    }
+}
+
+impl<M: NetworkMessage> ChannelPacketData<M> {
+    pub(crate) fn deserialize(
+        config: &ConnectionConfig,
+        reader: &mut BitReader,
+    ) -> Result<ChannelPacketData<M>, ReadPacketError<M::Error>> {
+        let num_channels = config.channels.len() as u32;
+        let channel_index = reader.read_bits(bits_required(0, num_channels - 1))? as usize;
+        let Some(config) = config.channels.get(channel_index) else {
+            return Err(ReadPacketError::InvalidChannel(channel_index));
+        };
+
+        let block_message = reader.read_bool()?;
+
+        if block_message {
+            // the block limits aren't validated for these channels, so can't be read against
+            if !config.blocks_enabled() {
+                return Err(ReadPacketError::BlocksDisabled(channel_index));
+            }
+            let block = ChannelPacketData::deserialize_block(config, channel_index, reader)?;
+            return Ok(ChannelPacketData {
+                channel_index,
+                messages: Vec::new(),
+                block: Some(block),
+            });
+        }
+
+        let has_messages = reader.read_bool()?;
+
+        if !has_messages {
+            return Ok(ChannelPacketData::empty());
+        }
+
+        let message_count = reader.read_int_relative(0)? as usize;
+        if message_count > config.max_messages_per_packet {
+            return Err(ReadPacketError::TooManyMessages {
+                channel_index,
+                message_count,
+            });
+        }
+
+        let mut messages = Vec::with_capacity(message_count);
+
+        match config.kind {
+            ChannelType::UnreliableUnordered | ChannelType::UnreliableSequenced => {
+                ChannelPacketData::deserialize_unordered(
+                    reader,
+                    channel_index,
+                    message_count,
+                    &mut messages,
+                )?
+            }
+            ChannelType::ReliableOrdered | ChannelType::ReliableUnordered => {
+                ChannelPacketData::deserialize_ordered(
+                    reader,
+                    channel_index,
+                    message_count,
+                    &mut messages,
+                )?
+            }
+        }
+
+        Ok(ChannelPacketData {
+            channel_index,
+            messages,
+            block: None,
+        })
+    }
+
+    fn deserialize_block(
+        config: &ChannelConfig,
+        channel_index: usize,
+        reader: &mut BitReader,
+    ) -> Result<BlockFragment<M>, ReadPacketError<M::Error>> {
+        let max_fragments = config.max_fragments_per_block() as u32;
+
+        let message_id = reader.read_bits(16)? as u16;
+        let num_fragments = reader.read_int(1, max_fragments)? as u16;
+        let fragment_id = reader.read_int(0, num_fragments as u32 - 1)? as u16;
+        let fragment_size = reader.read_int(1, config.block_fragment_size as u32)? as usize;
+
+        let mut fragment_data = vec![0u8; fragment_size];
+        reader.read_bytes(&mut fragment_data[..])?;
+
+        let message = if fragment_id == 0 {
+            Some(Self::deserialize_message(reader, channel_index)?)
+        } else {
+            None
+        };
+
+        Ok(BlockFragment {
+            message_id,
+            fragment_id,
+            num_fragments,
+            fragment_data,
+            message,
+        })
+    }
+
+    pub(crate) fn deserialize_unordered(
+        reader: &mut BitReader,
+        channel_index: usize,
+        message_count: usize,
+        messages: &mut Vec<(u16, M)>,
+    ) -> Result<(), ReadPacketError<M::Error>> {
+        for _ in 0..message_count {
+            // the ID is actually decided in `Processor::process_packet_data` - set 0 for now
+            messages.push((0, Self::deserialize_message(reader, channel_index)?));
+        }
 
         Ok(())
     }
 
     pub(crate) fn deserialize_ordered(
-        mut reader: &mut Cursor<&[u8]>,
+        reader: &mut BitReader,
+        channel_index: usize,
         message_count: usize,
         messages: &mut Vec<(u16, M)>,
-    ) -> Result<(), M::Error> {
+    ) -> Result<(), ReadPacketError<M::Error>> {
         // read the message IDs
-        let mut message_ids = Vec::with_capacity(message_count);
+        let mut message_ids: Vec<u16> = Vec::with_capacity(message_count);
         for _ in 0..message_count {
-            let id = reader.read_u16::<LittleEndian>().unwrap();
+            let id = match message_ids.last() {
+                None => reader.read_bits(16)? as u16,
+                Some(previous_id) => reader.read_sequence_relative(*previous_id)?,
+            };
             message_ids.push(id);
         }
 
-        Self::deserialize_check(reader);
+        Self::deserialize_check(reader)?;
 
         // read the messages
         let expect_length = message_ids.len();
         for id in message_ids {
-            let message = M::deserialize(&mut reader)?;
+            let message = Self::deserialize_message(reader, channel_index)?;
             messages.push((id, message));
-
-            Self::deserialize_check(reader);
         }
         assert_eq!(messages.len(), expect_length);
 
         Ok(())
     }
 
-    #[inline]
-    fn deserialize_check(_reader: &mut Cursor<&[u8]>) {
-        #[cfg(feature = "serialize_check")]
-        {
-            let check_value = _reader
-                .read_u32::<LittleEndian>()
-                .expect("expected check value, found end of stream");
-            assert_eq!(
-                check_value, SERIALIZE_CHECK_VALUE,
-                "expected check value {} but found {}",
-                SERIALIZE_CHECK_VALUE, check_value
-            );
-        }
+    fn deserialize_message(
+        reader: &mut BitReader,
+        channel_index: usize,
+    ) -> Result<M, ReadPacketError<M::Error>> {
+        let message = M::deserialize_bits(reader).map_err(|error| ReadPacketError::Message {
+            channel_index,
+            error,
+        })?;
+
+        Self::deserialize_check(reader)?;
+
+        Ok(message)
     }
 
     #[inline]
-    fn serialize_check(_writer: &mut Cursor<&mut [u8]>) {
+    fn deserialize_check(_reader: &mut BitReader) -> Result<(), ReadPacketError<M::Error>> {
         #[cfg(feature = "serialize_check")]
         {
-            _writer
-                .write_u32::<LittleEndian>(SERIALIZE_CHECK_VALUE)
-                .expect("failed to write check value");
+            let check_value = _reader.read_bits(32)?;
+            if check_value != SERIALIZE_CHECK_VALUE {
+                return Err(ReadPacketError::SerializeCheckFailed(check_value));
+            }
         }
+
+        Ok(())
     }
+}
 
+impl<M> ChannelPacketData<M> {
     pub(crate) fn empty() -> ChannelPacketData<M> {
         ChannelPacketData {
             channel_index: usize::MAX,
             messages: Vec::new(),
+            block: None,
         }
     }
 }
-
-/// A writer just like std::io::Sink but it measures like yojimbo's measure stream.
-pub(crate) struct MeasureSink {
-    pub(crate) bytes: usize,
-}
-
-impl MeasureSink {
-    pub(crate) fn new() -> MeasureSink {
-        MeasureSink { bytes: 0 }
-    }
-}
-
-impl io::Write for MeasureSink {
-    #[inline]
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.bytes += buf.len();
-        Ok(buf.len())
-    }
-
-    #[inline]
-    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
-        let total_len = bufs.iter().map(|b| b.len()).sum();
-        self.bytes += total_len;
-        Ok(total_len)
-    }
-
-    #[inline]
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
-    }
-}
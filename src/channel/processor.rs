@@ -1,13 +1,79 @@
+use std::collections::{vec_deque::Drain, VecDeque};
+
 use crate::config::ChannelConfig;
 
-use super::channel_packet_data::ChannelPacketData;
+use super::{
+    channel_packet_data::{ChannelPacketData, QueuedMessage, SerializedMessage},
+    ChannelErrorLevel,
+};
+
+/// Ids of sent messages which were acked (or, on unreliable channels, lost), waiting to be
+/// polled by the application.
+///
+/// Holds at most `capacity` ids of each kind; the oldest are dropped if they aren't polled.
+pub(crate) struct MessageAcks {
+    capacity: usize,
+    acked: VecDeque<u16>,
+    lost: VecDeque<u16>,
+}
+
+impl MessageAcks {
+    pub(crate) fn new(capacity: usize) -> MessageAcks {
+        MessageAcks {
+            capacity,
+            acked: VecDeque::new(),
+            lost: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn ack(&mut self, message_id: u16) {
+        if self.acked.len() == self.capacity {
+            self.acked.pop_front();
+        }
+        self.acked.push_back(message_id);
+    }
+
+    pub(crate) fn lose(&mut self, message_id: u16) {
+        if self.lost.len() == self.capacity {
+            self.lost.pop_front();
+        }
+        self.lost.push_back(message_id);
+    }
+
+    pub(crate) fn take_acked(&mut self) -> Drain<'_, u16> {
+        self.acked.drain(..)
+    }
+
+    pub(crate) fn take_lost(&mut self) -> Drain<'_, u16> {
+        self.lost.drain(..)
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.acked.clear();
+        self.lost.clear();
+    }
+}
 
 pub(crate) trait Processor<M> {
-    fn advance_time(&mut self, new_time: f64);
+    /// Returns an error if the channel can't continue (e.g. a reliable message expired); the
+    /// channel goes into that error state.
+    fn advance_time(&mut self, new_time: f64) -> Result<(), ChannelErrorLevel>;
     fn reset(&mut self);
     fn can_send_message(&self) -> bool;
     fn has_messages_to_send(&self) -> bool;
-    fn send_message(&mut self, message: M);
+    /// Queue `message`, which was already serialized to `serialized` (packets are written from
+    /// `serialized`, and `message` is only used for local delivery).
+    ///
+    /// Unreliable channels send messages with more (accumulated) `priority` first; reliable
+    /// channels always send messages in order, and ignore it.
+    ///
+    /// Returns the id the message will be reported with in `message_acks`.
+    fn send_message(
+        &mut self,
+        message: QueuedMessage<M>,
+        serialized: SerializedMessage,
+        priority: f32,
+    ) -> u16;
     fn receive_message(&mut self) -> Option<(u16, M)>;
     fn packet_data(
         &mut self,
@@ -15,7 +81,24 @@ pub(crate) trait Processor<M> {
         channel_index: usize,
         packet_sequence: u16,
         available_bits: usize,
-    ) -> (ChannelPacketData<M>, usize);
-    fn process_packet_data(&mut self, packet_data: ChannelPacketData<M>, packet_sequence: u16);
+    ) -> (ChannelPacketData<SerializedMessage>, usize);
+    /// Take the messages to deliver to a receiver in the same process, without
+    /// building packets (so there are no size limits or acks).
+    fn local_packet_data(
+        &mut self,
+        channel_index: usize,
+        packet_sequence: u16,
+    ) -> ChannelPacketData<M>;
+    /// Process the packet data received for this channel.
+    ///
+    /// Returns an error if the channel can't accept the data (e.g. the
+    /// channel has desynced); the channel goes into that error state.
+    fn process_packet_data(
+        &mut self,
+        packet_data: ChannelPacketData<M>,
+        packet_sequence: u16,
+    ) -> Result<(), ChannelErrorLevel>;
     fn process_ack(&mut self, ack: u16);
+    /// The sent messages which were acked or lost, by message id.
+    fn message_acks(&mut self) -> &mut MessageAcks;
 }
@@ -14,16 +14,23 @@
 
 // }
 
+use std::collections::VecDeque;
+
 use crate::{
-    channel::{channel_packet_data::MeasureSink, CONSERVATIVE_MESSAGE_HEADER_BITS},
+    bitpacker::BitWriter,
+    channel::{
+        channel_packet_data::{QueuedMessage, SerializedMessage},
+        CONSERVATIVE_FRAGMENT_HEADER_BITS, CONSERVATIVE_MESSAGE_HEADER_BITS,
+    },
     config::{ChannelConfig, ChannelType},
     message::NetworkMessage,
+    sequence_buffer::{sequence_greater_than, sequence_less_than, SequenceBuffer},
 };
 
 use super::{
-    processor::Processor,
-    sequence_buffer::{sequence_greater_than, sequence_less_than, SequenceBuffer},
-    ChannelPacketData,
+    channel_packet_data::BlockFragment,
+    processor::{MessageAcks, Processor},
+    ChannelErrorLevel, ChannelPacketData,
 };
 
 pub(crate) struct Reliable<M> {
@@ -44,20 +51,59 @@ pub(crate) struct Reliable<M> {
 
     sent_packets: SequenceBuffer<SentPacketEntry>,
     message_send_queue: SequenceBuffer<MessageSendQueueEntry<M>>,
+    /// Ordered only: received messages, waiting for the messages before them.
     message_receive_queue: SequenceBuffer<MessageReceiveQueueEntry<M>>,
+
+    /// The block currently being sent (the block message is always the oldest unacked message).
+    send_block: SendBlockData,
+    /// The block currently being received.
+    receive_block: ReceiveBlockData<M>,
+
+    /// False for `ReliableUnordered` channels, which deliver messages as soon as they arrive.
+    ordered: bool,
+    /// Unordered only: the messages received ahead of `receive_message_id`, so duplicates
+    /// can be dropped.
+    received_message_ids: SequenceBuffer<()>,
+    /// Unordered only: received messages, waiting for `receive_message`.
+    ready_messages: VecDeque<(u16, M)>,
+
+    /// Ids of the sent messages which have been acked, or (unordered only) which expired.
+    message_acks: MessageAcks,
 }
 
 impl<M> Reliable<M> {
     pub(crate) fn new(config: ChannelConfig, time: f64) -> Reliable<M> {
-        assert!(matches!(config.kind, ChannelType::ReliableOrdered));
+        assert!(matches!(
+            config.kind,
+            ChannelType::ReliableOrdered | ChannelType::ReliableUnordered
+        ));
 
         let sent_packets = SequenceBuffer::new(config.sent_packet_buffer_size);
         let sent_packet_message_ids =
             vec![0u16; config.max_messages_per_packet * config.sent_packet_buffer_size];
         let message_send_queue = SequenceBuffer::new(config.message_send_queue_size);
-        let message_receive_queue = SequenceBuffer::new(config.message_receive_queue_size);
+        let ordered = config.kind == ChannelType::ReliableOrdered;
+        // each kind of channel only uses one of the receive buffers
+        let (message_receive_queue, received_message_ids) = if ordered {
+            (
+                SequenceBuffer::new(config.message_receive_queue_size),
+                SequenceBuffer::new(0),
+            )
+        } else {
+            (
+                SequenceBuffer::new(0),
+                SequenceBuffer::new(config.message_receive_queue_size),
+            )
+        };
+        let message_acks = MessageAcks::new(config.message_send_queue_size);
 
-        // TODO: blocks
+        let max_fragments_per_block = if config.blocks_enabled() {
+            config.max_fragments_per_block()
+        } else {
+            0
+        };
+        let send_block = SendBlockData::new(max_fragments_per_block);
+        let receive_block = ReceiveBlockData::new(max_fragments_per_block);
 
         Reliable {
             time,
@@ -72,15 +118,34 @@ impl<M> Reliable<M> {
             sent_packets,
             message_send_queue,
             message_receive_queue,
+
+            send_block,
+            receive_block,
+
+            ordered,
+            received_message_ids,
+            ready_messages: VecDeque::new(),
+
+            message_acks,
         }
     }
+
+    /// True if the oldest unacked message is a block message.
+    ///
+    /// While a block is being sent, no other messages are sent on this channel.
+    fn sending_block_message(&self) -> bool {
+        self.message_send_queue
+            .get(self.oldest_unacked_message_id)
+            .map(|entry| entry.block)
+            .unwrap_or(false)
+    }
 }
 
 impl<M: NetworkMessage> Reliable<M> {
     /// Find all messages in the send queue (respecting channel config) that need to be sent.
     ///
     /// A message is considered for sending if:
-    ///  - it should fit in the available bits based on `measured_bits`
+    ///  - it should fit in the available bits based on its serialized size
     ///  - `message_resend_time` has elapsed or the [message has never been sent]*
     ///  - there are more than 4 bytes available
     ///
@@ -104,15 +169,16 @@ impl<M: NetworkMessage> Reliable<M> {
 
         let give_up_bits = 4 * 8;
         let message_limit = std::cmp::min(
-            self.message_receive_queue.capacity(),
+            self.config.message_receive_queue_size,
             self.message_send_queue.capacity(),
         );
 
         let mut used_bits = CONSERVATIVE_MESSAGE_HEADER_BITS;
         let mut give_up_counter = 0;
+        let mut previous_message_id = 0;
 
         for i in 0..message_limit {
-            if available_bits - used_bits < give_up_bits {
+            if available_bits.saturating_sub(used_bits) < give_up_bits {
                 break;
             }
 
@@ -124,13 +190,26 @@ impl<M: NetworkMessage> Reliable<M> {
 
             let Some(entry) = self.message_send_queue.get_mut(message_id) else { continue };
 
+            // block messages are sent on their own, once all the preceding messages are acked
+            if entry.block {
+                break;
+            }
+
             if entry.time_last_sent + self.config.message_resend_time <= self.time
-                && available_bits >= entry.measured_bits
+                && available_bits >= entry.serialized.bits()
             {
-                let mut message_bits = entry.measured_bits;
-
-                // TODO: serialize message id relative to previous message
-                message_bits += 2 * 8; // we will serialize a u16 for the message ID
+                let mut message_bits = entry.serialized.bits();
+
+                // the first ID is written in full, the rest relative to the previous ID
+                if message_ids.is_empty() {
+                    message_bits += 16;
+                } else {
+                    let mut writer = BitWriter::measure();
+                    writer
+                        .write_sequence_relative(previous_message_id, message_id)
+                        .unwrap();
+                    message_bits += writer.bits_written();
+                }
 
                 if used_bits + message_bits > available_bits {
                     give_up_counter += 1;
@@ -139,6 +218,7 @@ impl<M: NetworkMessage> Reliable<M> {
 
                 used_bits += message_bits;
                 message_ids.push(message_id);
+                previous_message_id = message_id;
                 entry.time_last_sent = self.time;
             }
 
@@ -150,24 +230,103 @@ impl<M: NetworkMessage> Reliable<M> {
         (message_ids, used_bits)
     }
 
-    /// Generate ChannelPacketData by copying all messages in the send queue
+    /// Generate ChannelPacketData from the serialized messages in the send queue
     /// with an ID in `message_ids`.
     fn get_message_packet_data(
         &mut self,
         channel_index: usize,
         message_ids: &[u16],
-    ) -> ChannelPacketData<M> {
+    ) -> ChannelPacketData<SerializedMessage> {
         let mut messages = Vec::with_capacity(message_ids.len());
 
         for id in message_ids {
-            let message = self.message_send_queue.get(*id).unwrap().message.clone();
-            messages.push((*id, message));
+            let entry = self.message_send_queue.get(*id).unwrap();
+            messages.push((*id, entry.serialized.clone()));
         }
 
         ChannelPacketData {
             channel_index,
             messages,
+            block: None,
+        }
+    }
+
+    /// Generate ChannelPacketData containing the next fragment of the block
+    /// being sent, if a fragment is due to be (re)sent and fits in `available_bits`.
+    ///
+    /// Assumes `sending_block_message` is true.
+    fn get_fragment_packet_data(
+        &mut self,
+        channel_index: usize,
+        packet_sequence: u16,
+        mut available_bits: usize,
+    ) -> (ChannelPacketData<SerializedMessage>, usize) {
+        available_bits = self
+            .config
+            .packet_budget
+            .map(|bytes| std::cmp::min(bytes * 8, available_bits))
+            .unwrap_or(available_bits);
+
+        let message_id = self.oldest_unacked_message_id;
+        let entry = self.message_send_queue.get(message_id).unwrap();
+        let block = entry
+            .message
+            .get()
+            .block()
+            .expect("block message has no block");
+        let fragment_size = self.config.block_fragment_size;
+
+        if !self.send_block.active {
+            // start sending this block
+            let num_fragments = (block.len() as f64 / fragment_size as f64).ceil() as usize;
+            self.send_block.start(message_id, num_fragments);
+        }
+
+        // find the next fragment to send (there may not be one)
+        let resend_time = self.config.block_fragment_resend_time;
+        let time = self.time;
+        let Some(fragment_id) = (0..self.send_block.num_fragments).find(|&i| {
+            !self.send_block.acked_fragment[i]
+                && self.send_block.fragment_send_time[i] + resend_time <= time
+        }) else {
+            return (ChannelPacketData::empty(), 0);
+        };
+
+        let fragment_start = fragment_id * fragment_size;
+        let fragment_end = std::cmp::min(fragment_start + fragment_size, block.len());
+
+        let mut fragment_bits =
+            CONSERVATIVE_FRAGMENT_HEADER_BITS + 8 * (fragment_end - fragment_start);
+        if fragment_id == 0 {
+            fragment_bits += CONSERVATIVE_MESSAGE_HEADER_BITS + entry.serialized.bits();
+        }
+
+        if fragment_bits > available_bits {
+            return (ChannelPacketData::empty(), 0);
         }
+
+        let fragment = BlockFragment {
+            message_id,
+            fragment_id: fragment_id as u16,
+            num_fragments: self.send_block.num_fragments as u16,
+            fragment_data: block[fragment_start..fragment_end].to_vec(),
+            message: if fragment_id == 0 {
+                Some(entry.serialized.clone())
+            } else {
+                None
+            },
+        };
+
+        self.send_block.fragment_send_time[fragment_id] = self.time;
+        self.add_fragment_packet_entry(message_id, fragment_id as u16, packet_sequence);
+
+        let packet_data = ChannelPacketData {
+            channel_index,
+            messages: Vec::new(),
+            block: Some(fragment),
+        };
+
+        (packet_data, fragment_bits)
     }
 
     /// Add an entry for this sequence number to `sent_packets`.
@@ -185,14 +344,238 @@ impl<M: NetworkMessage> Reliable<M> {
                 acked: false,
                 time_sent: self.time,
                 message_ids: message_ids_ref,
+                block_fragment: None,
             }
         });
     }
+
+    /// Add an entry for this sequence number to `sent_packets`, for a packet
+    /// containing a single block fragment.
+    fn add_fragment_packet_entry(
+        &mut self,
+        message_id: u16,
+        fragment_id: u16,
+        packet_sequence: u16,
+    ) {
+        self.sent_packets
+            .insert_with(packet_sequence, || SentPacketEntry {
+                acked: false,
+                time_sent: self.time,
+                message_ids: (0, 0),
+                block_fragment: Some((message_id, fragment_id)),
+            });
+    }
+
+    /// Handle a block fragment received from the network.
+    ///
+    /// Fragments are only accepted for the next message we expect to add to
+    /// the receive queue. Once all fragments arrive, the block is attached to
+    /// its message and the message is added to the receive queue.
+    fn process_packet_fragment(
+        &mut self,
+        fragment: BlockFragment<M>,
+    ) -> Result<(), ChannelErrorLevel> {
+        let expected_message_id = self.message_receive_queue.sequence_pointer();
+        if fragment.message_id != expected_message_id {
+            return Ok(());
+        }
+
+        let fragment_size = self.config.block_fragment_size;
+        let fragment_id = fragment.fragment_id as usize;
+        let num_fragments = fragment.num_fragments as usize;
+
+        // start receiving a new block
+        if !self.receive_block.active {
+            self.receive_block
+                .start(fragment.message_id, num_fragments, fragment_size);
+        }
+
+        if fragment_id >= self.receive_block.num_fragments
+            || num_fragments != self.receive_block.num_fragments
+        {
+            log::error!(
+                "desync: received fragment {} of {} but expected {} fragments",
+                fragment_id,
+                num_fragments,
+                self.receive_block.num_fragments
+            );
+            return Err(ChannelErrorLevel::Desync);
+        }
+
+        if self.receive_block.received_fragment[fragment_id] {
+            // duplicate fragment
+            return Ok(());
+        }
+
+        let last_fragment = fragment_id == num_fragments - 1;
+        if !last_fragment && fragment.fragment_data.len() != fragment_size {
+            log::error!(
+                "desync: received fragment of {} bytes but expected {}",
+                fragment.fragment_data.len(),
+                fragment_size
+            );
+            return Err(ChannelErrorLevel::Desync);
+        }
+
+        let fragment_start = fragment_id * fragment_size;
+        let fragment_end = fragment_start + fragment.fragment_data.len();
+        self.receive_block.block_data[fragment_start..fragment_end]
+            .copy_from_slice(&fragment.fragment_data[..]);
+
+        if last_fragment {
+            self.receive_block.block_size = fragment_end;
+            if self.receive_block.block_size > self.config.max_block_size {
+                log::error!(
+                    "desync: received block of {} bytes but the maximum is {}",
+                    self.receive_block.block_size,
+                    self.config.max_block_size
+                );
+                return Err(ChannelErrorLevel::Desync);
+            }
+        }
+
+        if fragment_id == 0 {
+            self.receive_block.block_message = fragment.message;
+        }
+
+        self.receive_block.received_fragment[fragment_id] = true;
+        self.receive_block.num_received_fragments += 1;
+
+        if self.receive_block.num_received_fragments < self.receive_block.num_fragments {
+            return Ok(());
+        }
+
+        // finished receiving the block
+        let message_id = self.receive_block.message_id;
+        if !self.message_receive_queue.available(message_id) {
+            // Did you forget to dequeue messages on the receiver?
+            log::error!(
+                "desync: received block {} but the receive queue is full; are you handling messages?",
+                message_id
+            );
+            return Err(ChannelErrorLevel::Desync);
+        }
+
+        let mut message = self
+            .receive_block
+            .block_message
+            .take()
+            .expect("first block fragment is missing its message");
+        let mut block_data = std::mem::take(&mut self.receive_block.block_data);
+        block_data.truncate(self.receive_block.block_size);
+        message.attach_block(block_data);
+
+        self.message_receive_queue
+            .insert_with(message_id, || MessageReceiveQueueEntry {
+                message_id,
+                message,
+            });
+
+        self.receive_block.active = false;
+
+        Ok(())
+    }
+
+    /// Handle a message received on an unordered channel, which can be received straight
+    /// away unless it is a duplicate.
+    ///
+    /// `receive_message_id` is advanced past the messages received without gaps, so older
+    /// messages are known to be duplicates.
+    fn process_unordered_message(&mut self, id: u16, message: M) -> Result<(), ChannelErrorLevel> {
+        let capacity = self.config.message_receive_queue_size as u16;
+        if sequence_less_than(id, self.receive_message_id) {
+            return Ok(());
+        }
+        let max_message_id = self.receive_message_id.wrapping_add(capacity - 1);
+        if sequence_greater_than(id, max_message_id) {
+            if self.config.message_time_to_live.is_none() {
+                log::error!("desync: received message {} but the latest we can handle is {}; are you handling messages?", id, max_message_id);
+                return Err(ChannelErrorLevel::Desync);
+            }
+            // The sender only sends this far ahead once every message before the window was
+            // acked (so was received here) or expired, so the missing ones will never arrive.
+            self.receive_message_id = id.wrapping_sub(capacity - 1);
+        }
+
+        if self.received_message_ids.exists(id) {
+            return Ok(());
+        }
+
+        if self.ready_messages.len() >= self.config.message_receive_queue_size {
+            // Did you forget to dequeue messages on the receiver?
+            log::error!(
+                "desync: received message {} but the receive queue is full; are you handling messages?",
+                id
+            );
+            return Err(ChannelErrorLevel::Desync);
+        }
+
+        self.received_message_ids.insert_with(id, || ());
+        self.ready_messages.push_back((id, message));
+
+        while self
+            .received_message_ids
+            .take(self.receive_message_id)
+            .is_some()
+        {
+            self.receive_message_id = self.receive_message_id.wrapping_add(1);
+        }
+
+        Ok(())
+    }
+
+    /// Unordered only: drop the messages which weren't acked within `time_to_live`, and report
+    /// them as lost. The receiver doesn't wait for them (see `process_unordered_message`).
+    fn drop_expired_messages(&mut self, time_to_live: f64) {
+        let mut message_id = self.oldest_unacked_message_id;
+        while message_id != self.send_message_id {
+            if let Some(entry) = self.message_send_queue.get(message_id) {
+                // messages are queued in order, so the rest are newer
+                if entry.time_queued + time_to_live > self.time {
+                    break;
+                }
+                log::debug!(
+                    "dropped reliable message {}: it was not acked within {} seconds",
+                    message_id,
+                    time_to_live
+                );
+                self.message_send_queue.take(message_id);
+                self.message_acks.lose(message_id);
+            }
+            message_id = message_id.wrapping_add(1);
+        }
+        self.oldest_unacked_message_id = update_oldest_unacked_message_id(
+            self.oldest_unacked_message_id,
+            &self.message_send_queue,
+        );
+    }
 }
 
 impl<M: NetworkMessage> Processor<M> for Reliable<M> {
-    fn advance_time(&mut self, new_time: f64) {
+    fn advance_time(&mut self, new_time: f64) -> Result<(), ChannelErrorLevel> {
         self.time = new_time;
+
+        let Some(time_to_live) = self.config.message_time_to_live else { return Ok(()) };
+        if !self.ordered {
+            self.drop_expired_messages(time_to_live);
+            return Ok(());
+        }
+
+        // later messages can't be delivered without the oldest one, so the channel fails
+        // instead of dropping it
+        let Some(entry) = self.message_send_queue.get(self.oldest_unacked_message_id) else {
+            return Ok(());
+        };
+        if entry.time_queued + time_to_live <= self.time {
+            log::error!(
+                "reliable message {} was not acked within {} seconds",
+                entry.message_id,
+                time_to_live
+            );
+            return Err(ChannelErrorLevel::MessageExpired);
+        }
+
+        Ok(())
     }
 
     fn reset(&mut self) {
@@ -204,7 +587,13 @@ impl<M: NetworkMessage> Processor<M> for Reliable<M> {
         self.message_send_queue.reset();
         self.message_receive_queue.reset();
 
-        // TODO: blocks
+        self.send_block.reset();
+        self.receive_block.reset();
+
+        self.received_message_ids.reset();
+        self.ready_messages.clear();
+
+        self.message_acks.reset();
     }
 
     /// There are messages to send if oldest_unacked_message_id is "less than"
@@ -218,33 +607,48 @@ impl<M: NetworkMessage> Processor<M> for Reliable<M> {
         self.message_send_queue.available(self.send_message_id)
     }
 
-    fn send_message(&mut self, message: M) {
+    fn send_message(
+        &mut self,
+        message: QueuedMessage<M>,
+        serialized: SerializedMessage,
+        _priority: f32,
+    ) -> u16 {
         // TODO: return Err if can_send_message is false
         assert!(self.can_send_message());
 
-        // TODO: blocks
+        if let Some(block) = message.get().block() {
+            assert!(
+                !block.is_empty() && block.len() <= self.config.max_block_size,
+                "block size must be between 1 and {} bytes, but was {}",
+                self.config.max_block_size,
+                block.len()
+            );
+        }
+        let block = message.get().block().is_some();
 
         let result = self
             .message_send_queue
-            .insert_with(self.send_message_id, || {
-                let mut sink = MeasureSink::new();
-                message.serialize(&mut sink).unwrap();
-                let measured_bits = 8 * sink.bytes;
-
-                MessageSendQueueEntry {
-                    message_id: self.send_message_id,
-                    message,
-                    measured_bits,
-                    time_last_sent: -1.0,
-                }
+            .insert_with(self.send_message_id, || MessageSendQueueEntry {
+                message_id: self.send_message_id,
+                message,
+                serialized,
+                time_last_sent: -1.0,
+                time_queued: self.time,
+                block,
             });
 
         assert!(result, "can_send_message should make this impossible");
 
+        let message_id = self.send_message_id;
         self.send_message_id = self.send_message_id.wrapping_add(1);
+        message_id
     }
 
     fn receive_message(&mut self) -> Option<(u16, M)> {
+        if !self.ordered {
+            return self.ready_messages.pop_front();
+        }
+
         let entry = match self.message_receive_queue.take(self.receive_message_id) {
             Some(entry) => entry,
             None => return None,
@@ -262,12 +666,14 @@ impl<M: NetworkMessage> Processor<M> for Reliable<M> {
         channel_index: usize,
         packet_sequence: u16,
         available_bits: usize,
-    ) -> (ChannelPacketData<M>, usize) {
+    ) -> (ChannelPacketData<SerializedMessage>, usize) {
         if !self.has_messages_to_send() {
             return (ChannelPacketData::empty(), 0);
         }
 
-        // TODO: blocks
+        if self.sending_block_message() {
+            return self.get_fragment_packet_data(channel_index, packet_sequence, available_bits);
+        }
 
         let (message_ids, message_bits) = self.get_messages_to_send(available_bits);
 
@@ -280,22 +686,70 @@ impl<M: NetworkMessage> Processor<M> for Reliable<M> {
         }
     }
 
-    fn process_packet_data(&mut self, packet_data: ChannelPacketData<M>, _packet_sequence: u16) {
-        // TODO: blocks
+    /// Local messages are acked as soon as they are taken, and blocks stay attached to
+    /// their message.
+    fn local_packet_data(
+        &mut self,
+        channel_index: usize,
+        _packet_sequence: u16,
+    ) -> ChannelPacketData<M> {
+        // same limit as `get_messages_to_send`, so the receive queue can hold every message
+        let message_limit = std::cmp::min(
+            self.config.message_receive_queue_size,
+            self.message_send_queue.capacity(),
+        );
+
+        let mut messages = Vec::new();
+        while self.has_messages_to_send() && messages.len() < message_limit {
+            let message_id = self.oldest_unacked_message_id;
+            if let Some(entry) = self.message_send_queue.take(message_id) {
+                assert_eq!(entry.message_id, message_id);
+                self.message_acks.ack(message_id);
+                messages.push((message_id, entry.message.into_message(&entry.serialized)));
+            }
+            self.oldest_unacked_message_id = update_oldest_unacked_message_id(
+                message_id.wrapping_add(1),
+                &self.message_send_queue,
+            );
+        }
+        self.send_block.reset();
+
+        ChannelPacketData {
+            channel_index,
+            messages,
+            block: None,
+        }
+    }
+
+    fn process_packet_data(
+        &mut self,
+        packet_data: ChannelPacketData<M>,
+        _packet_sequence: u16,
+    ) -> Result<(), ChannelErrorLevel> {
+        if let Some(fragment) = packet_data.block {
+            return self.process_packet_fragment(fragment);
+        }
+
         {
             let min_message_id = self.receive_message_id;
             let max_message_id = self
                 .receive_message_id
-                .wrapping_add((self.message_receive_queue.capacity() - 1) as u16);
+                .wrapping_add((self.config.message_receive_queue_size - 1) as u16);
 
             /* yojimbo ReliableOrderedChannel::ProcessPacketMessages */
             for (id, message) in packet_data.messages {
+                if !self.ordered {
+                    self.process_unordered_message(id, message)?;
+                    continue;
+                }
+
                 if sequence_less_than(id, min_message_id) {
                     continue;
                 }
                 if sequence_greater_than(id, max_message_id) {
                     // Did you forget to dequeue messages on the receiver?
-                    panic!("TODO: return a desync error (1), recieved {} but the latest we can handle is {}; are your handling client messages?", id, max_message_id);
+                    log::error!("desync: received message {} but the latest we can handle is {}; are you handling messages?", id, max_message_id);
+                    return Err(ChannelErrorLevel::Desync);
                 }
 
                 let result =
@@ -309,10 +763,17 @@ impl<M: NetworkMessage> Processor<M> for Reliable<M> {
                     // The message we got was too old; are we sending acks?
                     // This should generally be unreachable, SendQueueFull
                     // typically happens first.
-                    panic!("TODO: return a desync error (2), received {} but the oldest we can handle is {}", id, min_message_id);
+                    log::error!(
+                        "desync: received message {} but the oldest we can handle is {}",
+                        id,
+                        min_message_id
+                    );
+                    return Err(ChannelErrorLevel::Desync);
                 }
             }
         }
+
+        Ok(())
     }
 
     fn process_ack(&mut self, ack: u16) {
@@ -323,6 +784,8 @@ impl<M: NetworkMessage> Processor<M> for Reliable<M> {
         assert!(!entry.acked);
         entry.acked = true;
 
+        let block_fragment = entry.block_fragment;
+
         // remove all the acked messages from the send queue
         let (first_message, message_count) = entry.message_ids;
         let last_message = first_message + message_count;
@@ -331,6 +794,7 @@ impl<M: NetworkMessage> Processor<M> for Reliable<M> {
             let mut take_success = false;
             if let Some(entry) = self.message_send_queue.take(*message_id) {
                 assert_eq!(entry.message_id, *message_id);
+                self.message_acks.ack(*message_id);
                 take_success = true;
             } // else: this message was probably acked in another packet
             if take_success {
@@ -341,15 +805,45 @@ impl<M: NetworkMessage> Processor<M> for Reliable<M> {
             }
         }
 
-        // TODO: blocks
+        // mark the block fragment as acked, and remove the block message from the send queue once all fragments are acked
+        if let Some((block_message_id, fragment_id)) = block_fragment {
+            let fragment_id = fragment_id as usize;
+            if self.send_block.active
+                && self.send_block.block_message_id == block_message_id
+                && !self.send_block.acked_fragment[fragment_id]
+            {
+                self.send_block.acked_fragment[fragment_id] = true;
+                self.send_block.num_acked_fragments += 1;
+
+                if self.send_block.num_acked_fragments == self.send_block.num_fragments {
+                    self.send_block.active = false;
+                    let entry = self.message_send_queue.take(block_message_id);
+                    assert!(entry.is_some());
+                    self.message_acks.ack(block_message_id);
+                    self.oldest_unacked_message_id = update_oldest_unacked_message_id(
+                        self.oldest_unacked_message_id,
+                        &self.message_send_queue,
+                    );
+                }
+            }
+        }
+    }
+
+    fn message_acks(&mut self) -> &mut MessageAcks {
+        &mut self.message_acks
     }
 }
 
 struct MessageSendQueueEntry<M> {
     message_id: u16,
-    message: M,
+    message: QueuedMessage<M>,
+    /// Written to packets each time the message is (re)sent.
+    serialized: SerializedMessage,
     time_last_sent: f64,
-    measured_bits: usize,
+    /// Time the message was added to the send queue, for `message_time_to_live`.
+    time_queued: f64,
+    /// True if this message has a block attached.
+    block: bool,
 }
 
 struct MessageReceiveQueueEntry<M> {
@@ -366,6 +860,108 @@ struct SentPacketEntry {
     message_ids: (usize, usize),
     /// True if this packet has been acked
     acked: bool,
+    /// If this packet contained a block fragment, in the format (block message id, fragment id)
+    block_fragment: Option<(u16, u16)>,
+}
+
+/// Tracks the progress of the block being sent.
+struct SendBlockData {
+    /// True if we are currently sending a block.
+    active: bool,
+    num_fragments: usize,
+    num_acked_fragments: usize,
+    /// The message id the block is attached to.
+    block_message_id: u16,
+    acked_fragment: Vec<bool>,
+    /// The time each fragment was last sent, or -1.0 if it has not been sent.
+    fragment_send_time: Vec<f64>,
+}
+
+impl SendBlockData {
+    fn new(max_fragments_per_block: usize) -> SendBlockData {
+        SendBlockData {
+            active: false,
+            num_fragments: 0,
+            num_acked_fragments: 0,
+            block_message_id: 0,
+            acked_fragment: vec![false; max_fragments_per_block],
+            fragment_send_time: vec![-1.0; max_fragments_per_block],
+        }
+    }
+
+    fn start(&mut self, block_message_id: u16, num_fragments: usize) {
+        assert!(num_fragments <= self.acked_fragment.len());
+        self.active = true;
+        self.num_fragments = num_fragments;
+        self.num_acked_fragments = 0;
+        self.block_message_id = block_message_id;
+        for acked in &mut self.acked_fragment {
+            *acked = false;
+        }
+        for time in &mut self.fragment_send_time {
+            *time = -1.0;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.active = false;
+        self.num_fragments = 0;
+        self.num_acked_fragments = 0;
+    }
+}
+
+/// Tracks the progress of the block being received.
+struct ReceiveBlockData<M> {
+    /// True if we are currently receiving a block.
+    active: bool,
+    num_fragments: usize,
+    num_received_fragments: usize,
+    /// The message id the block is attached to.
+    message_id: u16,
+    /// The size of the block, which is only known once the last fragment arrives.
+    block_size: usize,
+    received_fragment: Vec<bool>,
+    /// Reassembly buffer, allocated when a block starts arriving.
+    block_data: Vec<u8>,
+    /// The block message, which arrives with the first fragment.
+    block_message: Option<M>,
+}
+
+impl<M> ReceiveBlockData<M> {
+    fn new(max_fragments_per_block: usize) -> ReceiveBlockData<M> {
+        ReceiveBlockData {
+            active: false,
+            num_fragments: 0,
+            num_received_fragments: 0,
+            message_id: 0,
+            block_size: 0,
+            received_fragment: vec![false; max_fragments_per_block],
+            block_data: Vec::new(),
+            block_message: None,
+        }
+    }
+
+    fn start(&mut self, message_id: u16, num_fragments: usize, fragment_size: usize) {
+        assert!(num_fragments <= self.received_fragment.len());
+        self.active = true;
+        self.num_fragments = num_fragments;
+        self.num_received_fragments = 0;
+        self.message_id = message_id;
+        self.block_size = 0;
+        for received in &mut self.received_fragment {
+            *received = false;
+        }
+        self.block_data = vec![0u8; num_fragments * fragment_size];
+        self.block_message = None;
+    }
+
+    fn reset(&mut self) {
+        self.active = false;
+        self.num_fragments = 0;
+        self.num_received_fragments = 0;
+        self.block_data = Vec::new();
+        self.block_message = None;
+    }
 }
 
 /// Advance `oldest_unacked_message_id` until it references
@@ -395,3 +991,278 @@ fn update_oldest_unacked_message_id<M>(
 }
 
 // TODO: fix https://github.com/networkprotocol/yojimbo/issues/138
+
+#[cfg(test)]
+mod test {
+    use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+    use crate::{bitpacker::BitReader, config::ClientServerConfig};
+
+    use super::*;
+
+    /// Not `Clone`, since channels must never need to copy messages.
+    #[derive(Debug, PartialEq, Eq)]
+    struct TestMessage {
+        value: u64,
+    }
+
+    impl NetworkMessage for TestMessage {
+        type Error = std::io::Error;
+
+        fn serialize<W: std::io::Write>(&self, mut writer: W) -> Result<(), Self::Error> {
+            writer.write_u64::<LittleEndian>(self.value)?;
+
+            Ok(())
+        }
+
+        fn deserialize<R: std::io::Read>(mut reader: R) -> Result<Self, Self::Error> {
+            let value = reader.read_u64::<LittleEndian>()?;
+
+            Ok(TestMessage { value })
+        }
+    }
+
+    fn new_receiver(receive_queue_size: usize) -> Reliable<TestMessage> {
+        let mut config = ChannelConfig::new(ChannelType::ReliableOrdered);
+        config.message_receive_queue_size = receive_queue_size;
+        Reliable::new(config, 100.0)
+    }
+
+    fn message_packet_data<I: Iterator<Item = u16>>(ids: I) -> ChannelPacketData<TestMessage> {
+        ChannelPacketData {
+            channel_index: 0,
+            messages: ids.map(|id| (id, TestMessage { value: id as _ })).collect(),
+            block: None,
+        }
+    }
+
+    fn fragment_packet_data(
+        fragment_id: u16,
+        num_fragments: u16,
+        fragment_size: usize,
+    ) -> ChannelPacketData<TestMessage> {
+        ChannelPacketData {
+            channel_index: 0,
+            messages: Vec::new(),
+            block: Some(BlockFragment {
+                message_id: 0,
+                fragment_id,
+                num_fragments,
+                fragment_data: vec![0u8; fragment_size],
+                message: (fragment_id == 0).then_some(TestMessage { value: 0 }),
+            }),
+        }
+    }
+
+    #[test]
+    fn desync_when_receive_queue_is_full() {
+        let size = 16;
+        let mut reliable = new_receiver(size);
+
+        // fill the receive queue without dequeuing anything
+        let result = reliable.process_packet_data(message_packet_data(0..size as u16), 0);
+        assert_eq!(result, Ok(()));
+
+        // the next message can't be buffered until the receiver catches up
+        let result = reliable.process_packet_data(message_packet_data(16..17), 1);
+        assert_eq!(result, Err(ChannelErrorLevel::Desync));
+
+        assert_eq!(
+            reliable.receive_message(),
+            Some((0, TestMessage { value: 0 }))
+        );
+
+        let result = reliable.process_packet_data(message_packet_data(16..17), 2);
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn desync_when_message_is_too_old_for_receive_queue() {
+        let size = 16;
+        let mut reliable = new_receiver(size);
+
+        let result = reliable.process_packet_data(message_packet_data(0..16), 0);
+        assert_eq!(result, Ok(()));
+        while reliable.receive_message().is_some() {}
+        let result = reliable.process_packet_data(message_packet_data(16..32), 1);
+        assert_eq!(result, Ok(()));
+
+        // This can't happen through the public API (the receive window check
+        // catches it first), so force `receive_message_id` to lag behind the
+        // receive queue to reach the second check.
+        reliable.receive_message_id = 10;
+
+        let result = reliable.process_packet_data(message_packet_data(12..13), 2);
+        assert_eq!(result, Err(ChannelErrorLevel::Desync));
+    }
+
+    #[test]
+    fn desync_when_block_fragment_count_changes() {
+        let mut reliable = new_receiver(16);
+        let fragment_size = reliable.config.block_fragment_size;
+
+        let result = reliable.process_packet_data(fragment_packet_data(0, 3, fragment_size), 0);
+        assert_eq!(result, Ok(()));
+
+        let result = reliable.process_packet_data(fragment_packet_data(1, 4, fragment_size), 1);
+        assert_eq!(result, Err(ChannelErrorLevel::Desync));
+    }
+
+    #[test]
+    fn unordered_receives_each_message_once() {
+        let mut config = ChannelConfig::new(ChannelType::ReliableUnordered);
+        config.message_receive_queue_size = 4;
+        let mut reliable = Reliable::new(config, 100.0);
+
+        let packets = [vec![2], vec![0, 2], vec![1], vec![0, 1, 2], vec![3, 4, 5]];
+        let mut received = Vec::new();
+        for (sequence, ids) in packets.into_iter().enumerate() {
+            let result =
+                reliable.process_packet_data(message_packet_data(ids.into_iter()), sequence as u16);
+            assert_eq!(result, Ok(()));
+            while let Some((id, message)) = reliable.receive_message() {
+                assert_eq!(message.value, id as u64);
+                received.push(id);
+            }
+        }
+        assert_eq!(received, [2, 0, 1, 3, 4, 5]);
+
+        // messages up to 5 arrived without gaps, so the receive window is now 6 to 9
+        let result = reliable.process_packet_data(message_packet_data(10..11), 5);
+        assert_eq!(result, Err(ChannelErrorLevel::Desync));
+    }
+
+    #[test]
+    fn unacked_message_expires() {
+        let mut config = ChannelConfig::new(ChannelType::ReliableOrdered);
+        config.message_time_to_live = Some(1.0);
+        let mut sender = Reliable::new(config.clone(), 100.0);
+
+        let message = TestMessage { value: 0 };
+        let serialized = SerializedMessage::new(&message).unwrap();
+        sender.send_message(QueuedMessage::Owned(message), serialized, 1.0);
+        let (packet_data, _) = sender.packet_data(&config, 0, 0, 1024 * 8);
+        assert_eq!(packet_data.messages.len(), 1);
+
+        assert_eq!(sender.advance_time(100.5), Ok(()));
+        assert_eq!(
+            sender.advance_time(101.0),
+            Err(ChannelErrorLevel::MessageExpired)
+        );
+
+        // once acked, the message can't expire
+        let mut sender = Reliable::new(config.clone(), 100.0);
+        let message = TestMessage { value: 0 };
+        let serialized = SerializedMessage::new(&message).unwrap();
+        sender.send_message(QueuedMessage::Owned(message), serialized, 1.0);
+        sender.packet_data(&config, 0, 0, 1024 * 8);
+        sender.process_ack(0);
+        assert_eq!(sender.advance_time(200.0), Ok(()));
+    }
+
+    #[test]
+    fn unordered_unacked_messages_are_dropped() {
+        let mut config = ChannelConfig::new(ChannelType::ReliableUnordered);
+        config.message_time_to_live = Some(1.0);
+        let mut sender = Reliable::new(config.clone(), 100.0);
+
+        let send = |sender: &mut Reliable<TestMessage>, value| {
+            let message = TestMessage { value };
+            let serialized = SerializedMessage::new(&message).unwrap();
+            sender.send_message(QueuedMessage::Owned(message), serialized, 1.0)
+        };
+
+        send(&mut sender, 0);
+        send(&mut sender, 1);
+        sender.packet_data(&config, 0, 0, 1024 * 8);
+        assert_eq!(sender.advance_time(100.5), Ok(()));
+        send(&mut sender, 2);
+
+        // unlike ordered channels, expiring is not an error
+        assert_eq!(sender.advance_time(101.0), Ok(()));
+        let acks = sender.message_acks();
+        assert_eq!(acks.take_lost().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(acks.take_acked().count(), 0);
+        assert_eq!(sender.oldest_unacked_message_id, 2);
+
+        let (packet_data, _) = sender.packet_data(&config, 0, 1, 1024 * 8);
+        let ids: Vec<u16> = packet_data.messages.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [2]);
+    }
+
+    #[test]
+    fn unordered_receiver_skips_expired_messages() {
+        let mut config = ChannelConfig::new(ChannelType::ReliableUnordered);
+        config.message_receive_queue_size = 4;
+        config.message_time_to_live = Some(1.0);
+        let mut reliable = Reliable::new(config, 100.0);
+
+        let receive_ids = |reliable: &mut Reliable<TestMessage>| -> Vec<u16> {
+            std::iter::from_fn(|| reliable.receive_message())
+                .map(|(id, _)| id)
+                .collect()
+        };
+
+        let result = reliable.process_packet_data(message_packet_data([0, 2].into_iter()), 0);
+        assert_eq!(result, Ok(()));
+        assert_eq!(receive_ids(&mut reliable), [0, 2]);
+
+        // 1 expired, so the sender moved on to 4 and up
+        let result = reliable.process_packet_data(message_packet_data(4..7), 1);
+        assert_eq!(result, Ok(()));
+        assert_eq!(receive_ids(&mut reliable), [4, 5, 6]);
+
+        // messages behind the window are dropped, even if they're resent
+        let result = reliable.process_packet_data(message_packet_data(1..3), 2);
+        assert_eq!(result, Ok(()));
+        assert!(reliable.receive_message().is_none());
+    }
+
+    #[test]
+    fn relative_message_ids_are_budgeted() {
+        let config = ChannelConfig::new(ChannelType::ReliableOrdered);
+        let mut sender = Reliable::new(config.clone(), 100.0);
+        for i in 0..50 {
+            let message = TestMessage { value: i };
+            let serialized = SerializedMessage::new(&message).unwrap();
+            sender.send_message(QueuedMessage::Owned(message), serialized, 1.0);
+        }
+
+        let (packet_data, used_bits) = sender.packet_data(&config, 0, 0, 8 * 1024 * 8);
+        assert_eq!(packet_data.messages.len(), 50);
+
+        // the first ID takes 16 bits, each consecutive ID after it takes 1 bit
+        let expect_bits = CONSERVATIVE_MESSAGE_HEADER_BITS + 50 * 64 + 16 + 49;
+        assert_eq!(used_bits, expect_bits);
+    }
+
+    #[test]
+    fn relative_message_ids_round_trip() {
+        let config = ClientServerConfig::new(1).connection;
+        let ids = [65530, 65531, 65535, 4, 300, 5000, 40000];
+
+        let mut buffer = vec![0u8; 1024];
+        let mut writer = BitWriter::new(&mut buffer[..]);
+        let messages = ids.iter().map(|id| {
+            let message = TestMessage { value: *id as _ };
+            (*id, SerializedMessage::new(&message).unwrap())
+        });
+        ChannelPacketData {
+            channel_index: 0,
+            messages: messages.collect(),
+            block: None,
+        }
+        .serialize(&config, &mut writer);
+        let bytes_written = writer.bytes_written();
+
+        let mut reader = BitReader::new(&buffer[..bytes_written]);
+        let packet_data =
+            ChannelPacketData::<TestMessage>::deserialize(&config, &mut reader).unwrap();
+
+        let received: Vec<_> = packet_data.messages.iter().map(|(id, _)| *id).collect();
+        assert_eq!(received, ids);
+        for (id, message) in packet_data.messages {
+            assert_eq!(message.value, id as u64);
+        }
+    }
+}
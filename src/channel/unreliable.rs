@@ -1,44 +1,115 @@
 use std::collections::VecDeque;
 
 use crate::{
-    channel::channel_packet_data::MeasureSink,
     config::{ChannelConfig, ChannelType},
     message::NetworkMessage,
+    sequence_buffer::sequence_less_than,
 };
 
 use super::{
-    channel_packet_data::ChannelPacketData, processor::Processor, CONSERVATIVE_MESSAGE_HEADER_BITS,
+    channel_packet_data::{ChannelPacketData, QueuedMessage, SerializedMessage},
+    processor::{MessageAcks, Processor},
+    ChannelErrorLevel, CONSERVATIVE_MESSAGE_HEADER_BITS,
 };
 
+/// Packets are acked by the newest packet sequence received and a bitfield of the 32 packets up
+/// to it, so an unacked packet further behind an acked packet is presumed lost.
+const ACK_BITS: u16 = 32;
+
 /// Messages sent across this channel are not guaranteed to arrive, and may be received in a different order than they were sent.
 /// This channel type is best used for time critical data like snapshots and object state.
+///
+/// For `UnreliableSequenced` channels, messages from packets older than the newest packet
+/// received on this channel are dropped instead. So that older messages never arrive in newer
+/// packets, these channels send messages in the order they were queued, ignoring priority.
+///
+/// Messages are sent by priority: every packet, each queued message gains its priority, and
+/// the messages with the most accumulated priority are written first. Messages which don't
+/// fit are dropped, unless the channel has a `message_time_to_live`, in which case they stay
+/// queued for later packets until they expire. Messages too big to ever fit are always dropped.
+/// Once the packet is full or has `max_messages_per_packet` messages, the rest always wait for
+/// the next packet.
+///
+/// Sent messages are reported as acked when their packet is acked, or lost when it's presumed
+/// lost (or they expired before being sent).
 pub(crate) struct Unreliable<M = ()> {
-    message_send_queue: VecDeque<M>,
+    time: f64,
+    time_to_live: Option<f64>,
+    /// Messages bigger than this can never be written to a packet.
+    max_message_bits: usize,
+    send_message_id: u16,
+    message_send_queue: VecDeque<MessageSendQueueEntry<M>>,
+    /// Unacked packets with messages from this channel, oldest first. Holds at most
+    /// `sent_packet_buffer_size` packets; older packets are presumed lost.
+    sent_packets: VecDeque<SentPacketEntry>,
+    sent_packet_capacity: usize,
+    message_acks: MessageAcks,
     message_receive_queue: VecDeque<(u16, M)>,
+    sequenced: bool,
+    /// Sequence of the newest packet with messages for this channel.
+    newest_packet_sequence: Option<u16>,
 }
 
 impl<M> Unreliable<M> {
-    pub(crate) fn new(config: &ChannelConfig) -> Unreliable<M> {
-        debug_assert_eq!(config.kind, ChannelType::UnreliableUnordered);
+    pub(crate) fn new(config: &ChannelConfig, max_packet_size: usize, time: f64) -> Unreliable<M> {
+        debug_assert!(matches!(
+            config.kind,
+            ChannelType::UnreliableUnordered | ChannelType::UnreliableSequenced
+        ));
 
         let send_capacity = config.message_send_queue_size;
         let receive_capacity = config.message_receive_queue_size;
 
+        let max_message_bits = config
+            .max_channel_bits(max_packet_size)
+            .saturating_sub(CONSERVATIVE_MESSAGE_HEADER_BITS);
+
         Unreliable {
+            time,
+            time_to_live: config.message_time_to_live,
+            max_message_bits,
+            send_message_id: 0,
             message_send_queue: VecDeque::with_capacity(send_capacity),
+            sent_packets: VecDeque::new(),
+            sent_packet_capacity: config.sent_packet_buffer_size,
+            message_acks: MessageAcks::new(send_capacity),
             message_receive_queue: VecDeque::with_capacity(receive_capacity),
+            sequenced: config.kind == ChannelType::UnreliableSequenced,
+            newest_packet_sequence: None,
         }
     }
 }
 
 impl<M: NetworkMessage> Processor<M> for Unreliable<M> {
-    fn advance_time(&mut self, _new_time: f64) {
-        /* no-op for unreliable channels */
+    fn advance_time(&mut self, new_time: f64) -> Result<(), ChannelErrorLevel> {
+        self.time = new_time;
+
+        if let Some(time_to_live) = self.time_to_live {
+            let queued = self.message_send_queue.len();
+            let message_acks = &mut self.message_acks;
+            self.message_send_queue.retain(|entry| {
+                let expired = entry.time_queued + time_to_live <= new_time;
+                if expired {
+                    message_acks.lose(entry.message_id);
+                }
+                !expired
+            });
+            let expired = queued - self.message_send_queue.len();
+            if expired > 0 {
+                log::debug!("dropped {} expired unreliable messages", expired);
+            }
+        }
+
+        Ok(())
     }
 
     fn reset(&mut self) {
+        self.send_message_id = 0;
         self.message_send_queue.clear();
+        self.sent_packets.clear();
+        self.message_acks.reset();
         self.message_receive_queue.clear();
+        self.newest_packet_sequence = None;
     }
 
     fn can_send_message(&self) -> bool {
@@ -47,11 +118,34 @@ impl<M: NetworkMessage> Processor<M> for Unreliable<M> {
     }
 
     fn has_messages_to_send(&self) -> bool {
-        self.message_send_queue.is_empty()
+        !self.message_send_queue.is_empty()
     }
 
-    fn send_message(&mut self, message: M) {
-        self.message_send_queue.push_back(message)
+    fn send_message(
+        &mut self,
+        message: QueuedMessage<M>,
+        serialized: SerializedMessage,
+        priority: f32,
+    ) -> u16 {
+        debug_assert!(
+            priority.is_finite() && priority >= 0.0,
+            "message priority must be positive, but was {}",
+            priority
+        );
+
+        let message_id = self.send_message_id;
+        self.send_message_id = self.send_message_id.wrapping_add(1);
+
+        self.message_send_queue.push_back(MessageSendQueueEntry {
+            message_id,
+            message,
+            serialized,
+            priority,
+            accumulated_priority: 0.0,
+            time_queued: self.time,
+        });
+
+        message_id
     }
 
     fn receive_message(&mut self) -> Option<(u16, M)> {
@@ -64,7 +158,7 @@ impl<M: NetworkMessage> Processor<M> for Unreliable<M> {
         channel_index: usize,
         packet_sequence: u16,
         mut available_bits: usize,
-    ) -> (ChannelPacketData<M>, usize) {
+    ) -> (ChannelPacketData<SerializedMessage>, usize) {
         if self.message_send_queue.is_empty() {
             return (ChannelPacketData::empty(), 0);
         }
@@ -76,12 +170,30 @@ impl<M: NetworkMessage> Processor<M> for Unreliable<M> {
             available_bits = std::cmp::min(packet_budget * 8, available_bits);
         }
 
+        let mut send_order: Vec<usize> = (0..self.message_send_queue.len()).collect();
+        // the receiver judges sequenced messages by packet, so they must be sent in order
+        if !self.sequenced {
+            for entry in &mut self.message_send_queue {
+                entry.accumulated_priority += entry.priority;
+            }
+
+            // the sort is stable, so older messages go first when priorities are equal
+            send_order.sort_by(|&a, &b| {
+                let a = self.message_send_queue[a].accumulated_priority;
+                let b = self.message_send_queue[b].accumulated_priority;
+                b.total_cmp(&a)
+            });
+        }
+
         let mut used_bits = CONSERVATIVE_MESSAGE_HEADER_BITS;
         let give_up_bits = 4 * 8;
 
         let mut messages = Vec::new();
+        let mut message_ids = Vec::new();
+        let mut keep = vec![true; self.message_send_queue.len()];
 
-        loop {
+        for index in send_order {
+            // the rest wait for the next packet
             if available_bits.saturating_sub(used_bits) < give_up_bits {
                 break;
             }
@@ -90,18 +202,33 @@ impl<M: NetworkMessage> Processor<M> for Unreliable<M> {
                 break;
             }
 
-            let message = match self.message_send_queue.pop_front() {
-                Some(message) => message,
-                None => break,
-            };
-
-            // TODO: block message
+            let entry = &self.message_send_queue[index];
+            let message = &entry.serialized;
+            let message_bits = message.bits();
 
-            let mut sink = MeasureSink::new();
-            message.serialize(&mut sink).unwrap();
-            let message_bits = 8 * sink.bytes;
+            if message_bits > self.max_message_bits {
+                log::warn!(
+                    "dropped unreliable message {}: it is {} bits, but at most {} bits fit in a packet",
+                    entry.message_id,
+                    message_bits,
+                    self.max_message_bits
+                );
+                self.message_acks.lose(entry.message_id);
+                keep[index] = false;
+                continue;
+            }
 
             if used_bits + message_bits > available_bits {
+                // without a time to live, messages only get one chance to be sent
+                if self.time_to_live.is_none() {
+                    self.message_acks.lose(entry.message_id);
+                    keep[index] = false;
+                    continue;
+                }
+                // a newer message can't overtake a sequenced message which is held over
+                if self.sequenced {
+                    break;
+                }
                 continue;
             }
 
@@ -109,22 +236,80 @@ impl<M: NetworkMessage> Processor<M> for Unreliable<M> {
 
             assert!(used_bits <= available_bits);
 
-            messages.push((packet_sequence, message));
+            messages.push((packet_sequence, message.clone()));
+            message_ids.push(entry.message_id);
+            keep[index] = false;
         }
 
+        let mut index = 0;
+        self.message_send_queue.retain(|_| {
+            index += 1;
+            keep[index - 1]
+        });
+
         if messages.is_empty() {
             return (ChannelPacketData::empty(), 0);
         }
 
+        if self.sent_packets.len() == self.sent_packet_capacity {
+            if let Some(lost_packet) = self.sent_packets.pop_front() {
+                for message_id in lost_packet.message_ids {
+                    self.message_acks.lose(message_id);
+                }
+            }
+        }
+        self.sent_packets.push_back(SentPacketEntry {
+            packet_sequence,
+            message_ids,
+        });
+
         let packet_data = ChannelPacketData {
             channel_index: channel_index as _,
             messages,
+            block: None,
         };
 
         (packet_data, used_bits)
     }
 
-    fn process_packet_data(&mut self, packet_data: ChannelPacketData<M>, packet_sequence: u16) {
+    fn local_packet_data(
+        &mut self,
+        channel_index: usize,
+        packet_sequence: u16,
+    ) -> ChannelPacketData<M> {
+        let message_acks = &mut self.message_acks;
+        let messages = self
+            .message_send_queue
+            .drain(..)
+            .map(|entry| {
+                // delivered straight away, so there's no packet to wait for
+                message_acks.ack(entry.message_id);
+                let message = entry.message.into_message(&entry.serialized);
+                (packet_sequence, message)
+            })
+            .collect();
+
+        ChannelPacketData {
+            channel_index,
+            messages,
+            block: None,
+        }
+    }
+
+    fn process_packet_data(
+        &mut self,
+        packet_data: ChannelPacketData<M>,
+        packet_sequence: u16,
+    ) -> Result<(), ChannelErrorLevel> {
+        if self.sequenced {
+            if let Some(newest) = self.newest_packet_sequence {
+                if sequence_less_than(packet_sequence, newest) {
+                    return Ok(());
+                }
+            }
+            self.newest_packet_sequence = Some(packet_sequence);
+        }
+
         for (_, message) in packet_data.messages {
             if self.message_receive_queue.len() < self.message_receive_queue.capacity() {
                 // the packet_sequence overrides any ID that may have been set
@@ -132,9 +317,353 @@ impl<M: NetworkMessage> Processor<M> for Unreliable<M> {
                     .push_back((packet_sequence, message));
             }
         }
+
+        Ok(())
+    }
+
+    fn process_ack(&mut self, ack: u16) {
+        let oldest_ackable = ack.wrapping_sub(ACK_BITS - 1);
+        let message_acks = &mut self.message_acks;
+        self.sent_packets.retain(|entry| {
+            if entry.packet_sequence == ack {
+                entry
+                    .message_ids
+                    .iter()
+                    .for_each(|&id| message_acks.ack(id));
+                false
+            } else if sequence_less_than(entry.packet_sequence, oldest_ackable) {
+                entry
+                    .message_ids
+                    .iter()
+                    .for_each(|&id| message_acks.lose(id));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn message_acks(&mut self) -> &mut MessageAcks {
+        &mut self.message_acks
+    }
+}
+
+struct SentPacketEntry {
+    packet_sequence: u16,
+    message_ids: Vec<u16>,
+}
+
+struct MessageSendQueueEntry<M> {
+    message_id: u16,
+    message: QueuedMessage<M>,
+    serialized: SerializedMessage,
+    priority: f32,
+    /// Grows by `priority` for every packet this message is considered for.
+    accumulated_priority: f32,
+    /// Time the message was added to the send queue, for `message_time_to_live`.
+    time_queued: f64,
+}
+
+#[cfg(test)]
+mod test {
+    use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    struct TestMessage {
+        value: u64,
+    }
+
+    impl NetworkMessage for TestMessage {
+        type Error = std::io::Error;
+
+        fn serialize<W: std::io::Write>(&self, mut writer: W) -> Result<(), Self::Error> {
+            writer.write_u64::<LittleEndian>(self.value)?;
+
+            Ok(())
+        }
+
+        fn deserialize<R: std::io::Read>(mut reader: R) -> Result<Self, Self::Error> {
+            let value = reader.read_u64::<LittleEndian>()?;
+
+            Ok(TestMessage { value })
+        }
+    }
+
+    fn message_packet_data(value: u64) -> ChannelPacketData<TestMessage> {
+        ChannelPacketData {
+            channel_index: 0,
+            messages: vec![(0, TestMessage { value }), (0, TestMessage { value })],
+            block: None,
+        }
+    }
+
+    fn receive_values(unreliable: &mut Unreliable<TestMessage>) -> Vec<(u16, u64)> {
+        std::iter::from_fn(|| unreliable.receive_message())
+            .map(|(id, message)| (id, message.value))
+            .collect()
+    }
+
+    #[test]
+    fn sequenced_drops_stale_messages() {
+        let config = ChannelConfig::new(ChannelType::UnreliableSequenced);
+        let mut unreliable = Unreliable::new(&config, 1200, 100.0);
+
+        // packet sequences wrap, so 65535 is older than 1
+        for sequence in [65534, 1, 65535, 2, 0] {
+            let result =
+                unreliable.process_packet_data(message_packet_data(sequence as u64), sequence);
+            assert_eq!(result, Ok(()));
+        }
+
+        assert_eq!(
+            receive_values(&mut unreliable),
+            [
+                (65534, 65534),
+                (65534, 65534),
+                (1, 1),
+                (1, 1),
+                (2, 2),
+                (2, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn send_by_accumulated_priority() {
+        let mut config = ChannelConfig::new(ChannelType::UnreliableUnordered);
+        // otherwise messages which don't fit are dropped
+        config.message_time_to_live = Some(10.0);
+        let mut unreliable = Unreliable::new(&config, 1200, 100.0);
+
+        let send = |unreliable: &mut Unreliable<TestMessage>, value, priority| {
+            let message = TestMessage { value };
+            let serialized = SerializedMessage::new(&message).unwrap();
+            unreliable.send_message(QueuedMessage::Owned(message), serialized, priority);
+        };
+        // room for one message per packet
+        let available_bits = CONSERVATIVE_MESSAGE_HEADER_BITS + 64 + 40;
+        let sent_value = |unreliable: &mut Unreliable<TestMessage>| {
+            let (packet_data, _) = unreliable.packet_data(&config, 0, 0, available_bits);
+            let serialized = &packet_data.messages[0].1;
+            let message: TestMessage = serialized.read().unwrap();
+            assert_eq!(packet_data.messages.len(), 1);
+            message.value
+        };
+
+        send(&mut unreliable, 0, 1.0);
+        send(&mut unreliable, 1, 3.0);
+        assert_eq!(sent_value(&mut unreliable), 1);
+
+        // message 0 keeps gaining priority, so it eventually wins
+        send(&mut unreliable, 2, 3.0);
+        assert_eq!(sent_value(&mut unreliable), 2);
+        send(&mut unreliable, 3, 3.0);
+        assert_eq!(sent_value(&mut unreliable), 0);
+        assert_eq!(sent_value(&mut unreliable), 3);
+        assert!(!unreliable.has_messages_to_send());
+    }
+
+    #[test]
+    fn sequenced_sends_in_order() {
+        let mut config = ChannelConfig::new(ChannelType::UnreliableSequenced);
+        config.message_time_to_live = Some(10.0);
+        let mut sender = Unreliable::new(&config, 1200, 100.0);
+        let mut receiver = Unreliable::new(&config, 1200, 100.0);
+
+        // the low priority message is older, so it must not arrive after the newer one
+        for (value, priority) in [(0, 0.0), (1, 10.0)] {
+            let message = TestMessage { value };
+            let serialized = SerializedMessage::new(&message).unwrap();
+            sender.send_message(QueuedMessage::Owned(message), serialized, priority);
+        }
+
+        // room for one message per packet
+        let available_bits = CONSERVATIVE_MESSAGE_HEADER_BITS + 64 + 40;
+        for packet_sequence in 0..2 {
+            let (packet_data, _) = sender.packet_data(&config, 0, packet_sequence, available_bits);
+            let messages = packet_data
+                .messages
+                .iter()
+                .map(|(id, serialized)| (*id, serialized.read().unwrap()))
+                .collect();
+            let packet_data = ChannelPacketData {
+                channel_index: 0,
+                messages,
+                block: None,
+            };
+            let result = receiver.process_packet_data(packet_data, packet_sequence);
+            assert_eq!(result, Ok(()));
+        }
+
+        assert_eq!(receive_values(&mut receiver), [(0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn expired_messages_are_dropped() {
+        let mut config = ChannelConfig::new(ChannelType::UnreliableUnordered);
+        config.message_time_to_live = Some(1.0);
+        let mut unreliable = Unreliable::new(&config, 1200, 100.0);
+
+        let send = |unreliable: &mut Unreliable<TestMessage>, value| {
+            let message = TestMessage { value };
+            let serialized = SerializedMessage::new(&message).unwrap();
+            unreliable.send_message(QueuedMessage::Owned(message), serialized, 1.0);
+        };
+
+        send(&mut unreliable, 0);
+        assert_eq!(unreliable.advance_time(100.5), Ok(()));
+        send(&mut unreliable, 1);
+        assert_eq!(unreliable.advance_time(101.0), Ok(()));
+
+        let packet_data = unreliable.local_packet_data(0, 0);
+        let values: Vec<u64> = packet_data
+            .messages
+            .iter()
+            .map(|(_, message)| message.value)
+            .collect();
+        assert_eq!(values, [1]);
+        let acks = unreliable.message_acks();
+        assert_eq!(acks.take_lost().collect::<Vec<_>>(), [0]);
+        assert_eq!(acks.take_acked().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn messages_are_acked_or_lost_with_their_packet() {
+        let config = ChannelConfig::new(ChannelType::UnreliableUnordered);
+        let mut unreliable = Unreliable::new(&config, 1200, 100.0);
+
+        // one message per packet, in packets 10 to 12
+        for (value, packet_sequence) in (0..3).zip(10..) {
+            let message = TestMessage { value };
+            let serialized = SerializedMessage::new(&message).unwrap();
+            let message_id =
+                unreliable.send_message(QueuedMessage::Owned(message), serialized, 1.0);
+            assert_eq!(message_id, value as u16);
+            let (packet_data, _) = unreliable.packet_data(&config, 0, packet_sequence, 1024 * 8);
+            assert_eq!(packet_data.messages.len(), 1);
+        }
+
+        unreliable.process_ack(11);
+        let acks = unreliable.message_acks();
+        assert_eq!(acks.take_acked().collect::<Vec<_>>(), [1]);
+        assert_eq!(acks.take_lost().count(), 0);
+
+        // 10 is still in the ack bits of packet 41, but not of packet 42
+        unreliable.process_ack(41);
+        assert_eq!(unreliable.message_acks().take_lost().count(), 0);
+        unreliable.process_ack(42);
+        assert_eq!(
+            unreliable.message_acks().take_lost().collect::<Vec<_>>(),
+            [0]
+        );
+
+        unreliable.process_ack(12);
+        let acks = unreliable.message_acks();
+        assert_eq!(acks.take_acked().collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn messages_which_dont_fit_are_dropped() {
+        /// Serializes to exactly its bytes.
+        struct BytesMessage(Vec<u8>);
+
+        impl NetworkMessage for BytesMessage {
+            type Error = std::io::Error;
+
+            fn serialize<W: std::io::Write>(&self, mut writer: W) -> Result<(), Self::Error> {
+                writer.write_all(&self.0)
+            }
+
+            fn deserialize<R: std::io::Read>(mut reader: R) -> Result<Self, Self::Error> {
+                let mut bytes = Vec::new();
+                reader.read_to_end(&mut bytes)?;
+                Ok(BytesMessage(bytes))
+            }
+        }
+
+        let send = |unreliable: &mut Unreliable<BytesMessage>, size| {
+            let message = BytesMessage(vec![0; size]);
+            let serialized = SerializedMessage::new(&message).unwrap();
+            unreliable.send_message(QueuedMessage::Owned(message), serialized, 1.0)
+        };
+
+        for time_to_live in [None, Some(10.0)] {
+            let mut config = ChannelConfig::new(ChannelType::UnreliableUnordered);
+            config.message_time_to_live = time_to_live;
+            // at most 54 bytes of messages fit in a packet
+            let mut unreliable = Unreliable::new(&config, 64, 100.0);
+
+            let too_big = send(&mut unreliable, 60);
+            let sent = send(&mut unreliable, 8);
+            let not_sent = send(&mut unreliable, 8);
+
+            // room for one of the small messages
+            let available_bits = CONSERVATIVE_MESSAGE_HEADER_BITS + 8 * 8 + 40;
+            let (packet_data, _) = unreliable.packet_data(&config, 0, 0, available_bits);
+            assert_eq!(packet_data.messages.len(), 1);
+            unreliable.process_ack(0);
+
+            let acks = unreliable.message_acks();
+            assert_eq!(acks.take_acked().collect::<Vec<_>>(), [sent]);
+            let lost: Vec<u16> = acks.take_lost().collect();
+            if time_to_live.is_none() {
+                assert_eq!(lost, [too_big, not_sent]);
+                assert!(!unreliable.has_messages_to_send());
+            } else {
+                // only dropped once it expires
+                assert_eq!(lost, [too_big]);
+                assert!(unreliable.has_messages_to_send());
+            }
+        }
     }
 
-    fn process_ack(&mut self, _ack: u16) {
-        /* no-op for unreliable channels */
+    #[test]
+    fn messages_past_max_messages_per_packet_wait() {
+        for time_to_live in [None, Some(10.0)] {
+            let mut config = ChannelConfig::new(ChannelType::UnreliableUnordered);
+            config.message_time_to_live = time_to_live;
+            config.max_messages_per_packet = 2;
+            let mut unreliable = Unreliable::new(&config, 1200, 100.0);
+
+            let ids: Vec<u16> = (0..4)
+                .map(|value| {
+                    let message = TestMessage { value };
+                    let serialized = SerializedMessage::new(&message).unwrap();
+                    unreliable.send_message(QueuedMessage::Owned(message), serialized, 1.0)
+                })
+                .collect();
+
+            let (packet_data, _) = unreliable.packet_data(&config, 0, 0, 1000);
+            assert_eq!(packet_data.messages.len(), 2);
+
+            // the rest are held over for the next packet, with or without a time to live
+            assert_eq!(unreliable.message_acks().take_lost().count(), 0);
+            let (packet_data, _) = unreliable.packet_data(&config, 0, 1, 1000);
+            assert_eq!(packet_data.messages.len(), 2);
+            assert!(!unreliable.has_messages_to_send());
+
+            unreliable.process_ack(1);
+            let acked: Vec<u16> = unreliable.message_acks().take_acked().collect();
+            assert_eq!(acked, ids[2..]);
+        }
+    }
+
+    #[test]
+    fn unordered_keeps_stale_messages() {
+        let config = ChannelConfig::new(ChannelType::UnreliableUnordered);
+        let mut unreliable = Unreliable::new(&config, 1200, 100.0);
+
+        for sequence in [2, 1] {
+            let result =
+                unreliable.process_packet_data(message_packet_data(sequence as u64), sequence);
+            assert_eq!(result, Ok(()));
+        }
+
+        assert_eq!(
+            receive_values(&mut unreliable),
+            [(2, 2), (2, 2), (1, 1), (1, 1)]
+        );
     }
 }
@@ -1,73 +1,252 @@
-use std::ffi::{c_void, CString};
-use std::{slice, usize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::usize;
 
-use crate::channel::ChannelCounters;
+use crate::channel::{ChannelCounters, SendError};
 use crate::config::ClientServerConfig;
+use crate::connect_token::ConnectTokenBuilder;
 use crate::connection::{Connection, ConnectionErrorLevel};
 use crate::message::NetworkMessage;
 use crate::network_info::NetworkInfo;
 use crate::network_simulator::NetworkSimulator;
-use crate::{bindings::*, gf_init_default, PRIVATE_KEY_BYTES};
+use crate::reliable::ReliableEndpoint;
+use crate::transport::{ClientTransport, NetcodeClientTransport};
+use crate::{bindings::*, CONNECT_TOKEN_BYTES, PRIVATE_KEY_BYTES, USER_DATA_BYTES};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ClientState {
-    Error,
+    /// Failed to connect, or the connection failed. Stays in this state until `connect` is
+    /// called again.
+    Error(ClientError),
     Disconnected,
     Connecting,
     Connected,
 }
 
-impl ClientState {
-    fn try_from_netcode_client_state_i32(val: i32) -> Option<ClientState> {
-        // REFACTOR: I think we can derive this with FromPrimitive in num-traits
-        let mapped = match val {
-            3 => ClientState::Connected,
-            1 | 2 => ClientState::Connecting,
-            0 => ClientState::Disconnected,
-            x if x < 0 && x > -7 => ClientState::Error,
-            _ => {
-                return None;
+/// Why a client failed to connect, or lost its connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientError {
+    /// The connect token expired before the client connected.
+    ConnectTokenExpired,
+    /// The server rejected the connect token, or it could not be generated.
+    InvalidConnectToken,
+    /// The server stopped responding after the client connected.
+    ConnectionTimedOut,
+    /// The server did not respond to the connection response.
+    ConnectionResponseTimedOut,
+    /// The server did not respond to the connection request.
+    ConnectionRequestTimedOut,
+    /// The server denied the connection, usually because it is full.
+    ConnectionDenied,
+    /// The message connection failed (e.g. a channel desynced, see `ChannelErrorLevel`).
+    Connection(ConnectionErrorLevel),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::ConnectTokenExpired => write!(f, "connect token expired"),
+            ClientError::InvalidConnectToken => write!(f, "invalid connect token"),
+            ClientError::ConnectionTimedOut => write!(f, "connection timed out"),
+            ClientError::ConnectionResponseTimedOut => {
+                write!(f, "connection response timed out")
             }
-        };
-        Some(mapped)
+            ClientError::ConnectionRequestTimedOut => write!(f, "connection request timed out"),
+            ClientError::ConnectionDenied => write!(f, "connection denied"),
+            ClientError::Connection(error_level) => {
+                write!(f, "connection error: {:?}", error_level)
+            }
+        }
     }
 }
 
-pub struct Client<M> {
+impl std::error::Error for ClientError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// The client state changed, e.g. from `Connecting` to `Connected`.
+    StateChanged {
+        previous: ClientState,
+        current: ClientState,
+    },
+}
+
+pub struct Client<M, T = NetcodeClientTransport> {
     config: ClientServerConfig,
-    endpoint: *mut reliable_endpoint_t,
+    endpoint: Option<ReliableEndpoint>,
     connection: Option<Connection<M>>,
     network_simulator: Option<NetworkSimulator>,
     packet_buffer: Vec<u8>,
     client_state: ClientState,
-    #[allow(unused)]
+    /// Only known for loopback clients, `usize::MAX` otherwise.
     client_index: usize,
+    /// Messages are exchanged with the server directly, see `connect_local`.
+    local: bool,
     time: f64,
 
-    client: *mut netcode_client_t,
-    address: String,
-    bound_port: Option<u16>,
+    transport: T,
     client_id: u64,
+
+    /// Events for `poll_event`.
+    events: VecDeque<ClientEvent>,
 }
 
 impl<M: NetworkMessage> Client<M> {
     pub fn new(address: String, config: ClientServerConfig, time: f64) -> Client<M> {
+        Client::with_transport(NetcodeClientTransport::new(address), config, time)
+    }
+
+    /// Connect with a connect token from your matchmaking service (see `ConnectTokenBuilder`).
+    pub fn connect(&mut self, connect_token: &[u8; CONNECT_TOKEN_BYTES]) {
+        self.connect_with(*connect_token);
+    }
+
+    /// Connect by generating a connect token with the server's private key.
+    ///
+    /// Only use this for development; clients should never know the private key. Use
+    /// `connect` with a token from your matchmaking service instead.
+    pub fn insecure_connect(
+        &mut self,
+        private_key: &[u8; PRIVATE_KEY_BYTES],
+        client_id: u64,
+        server_addresses: &[&str],
+    ) {
+        self.insecure_connect_with_user_data(
+            private_key,
+            client_id,
+            server_addresses,
+            &[0u8; USER_DATA_BYTES],
+        );
+    }
+
+    /// Like `insecure_connect`, with `user_data` for the server (see `Server::client_user_data`).
+    pub fn insecure_connect_with_user_data(
+        &mut self,
+        private_key: &[u8; PRIVATE_KEY_BYTES],
+        client_id: u64,
+        server_addresses: &[&str],
+        user_data: &[u8; USER_DATA_BYTES],
+    ) {
+        assert!(!server_addresses.is_empty());
+        assert!(server_addresses.len() <= NETCODE_MAX_SERVERS_PER_CONNECT as usize);
+
+        let mut builder = ConnectTokenBuilder::new(private_key, self.config.protocol_id, client_id)
+            .expire_seconds(self.config.timeout)
+            .timeout_seconds(self.config.timeout)
+            .user_data(user_data);
+        for address in server_addresses {
+            builder = builder.server_address(address);
+        }
+        let connect_token = match builder.build() {
+            Ok(connect_token) => connect_token,
+            Err(err) => {
+                log::error!("failed to generate insecure connect token: {}", err);
+                self.disconnect();
+                self.set_state(ClientState::Error(ClientError::InvalidConnectToken));
+                return;
+            }
+        };
+        self.connect_with(connect_token);
+        if !self.is_disconnected() {
+            self.client_id = client_id;
+        }
+    }
+
+    /// Connect to a server running in the same process, as its loopback client `client_index`.
+    ///
+    /// The client is connected straight away, and never sends packets over the network. The
+    /// server must connect the same slot with `Server::connect_loopback_client`, and packets are
+    /// exchanged with `Server::pump_loopback_client`. Disconnect with `disconnect` as usual.
+    pub fn connect_loopback(&mut self, client_index: usize, client_id: u64, max_clients: usize) {
+        self.disconnect();
+        self.connect_internal();
+        self.transport
+            .connect_loopback(client_index, max_clients, self.time);
+        self.set_state(self.transport.state());
+        if self.is_disconnected() {
+            self.disconnect();
+            return;
+        }
+        self.client_id = client_id;
+        self.client_index = client_index;
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.transport.is_loopback()
+    }
+
+    /// Like `connect_loopback`, but messages are handed to and from the server directly,
+    /// without being serialized or sent in packets.
+    ///
+    /// The server must connect the same slot with `Server::connect_local_client`, and messages
+    /// are exchanged with `Server::pump_loopback_client`.
+    pub fn connect_local(&mut self, client_index: usize, client_id: u64, max_clients: usize) {
+        self.connect_loopback(client_index, client_id, max_clients);
+        self.local = self.is_loopback();
+    }
+
+    /// True if connected with `connect_local`.
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+
+    pub fn bound_port(&self) -> Option<u16> {
+        self.transport.bound_port()
+    }
+
+    /// The client index of a loopback client.
+    pub(crate) fn loopback_client_index(&self) -> Option<usize> {
+        if self.is_loopback() {
+            Some(self.client_index)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn transport_mut(&mut self) -> &mut NetcodeClientTransport {
+        &mut self.transport
+    }
+
+    pub(crate) fn connection_mut(&mut self) -> Option<&mut Connection<M>> {
+        self.connection.as_mut()
+    }
+}
+
+impl<M: NetworkMessage, T: ClientTransport> Client<M, T> {
+    /// Create a client which connects to servers over `transport`.
+    pub fn with_transport(transport: T, config: ClientServerConfig, time: f64) -> Client<M, T> {
+        if let Err(err) = config.validate() {
+            panic!("invalid config: {}", err);
+        }
+
         let packet_buffer = vec![0u8; config.connection.max_packet_size];
 
         Client {
             config,
-            endpoint: std::ptr::null_mut(),
+            endpoint: None,
             connection: None,
             network_simulator: None,
             packet_buffer,
             client_state: ClientState::Disconnected,
             client_index: usize::MAX,
+            local: false,
             time,
 
-            client: std::ptr::null_mut(),
-            address,
-            bound_port: None,
+            transport,
             client_id: 0,
+
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Connect to a server with a token for the transport (see `ClientTransport::ConnectToken`).
+    pub fn connect_with(&mut self, connect_token: T::ConnectToken) {
+        self.disconnect();
+        self.connect_internal();
+        self.transport.connect(connect_token, self.time);
+        self.set_state(self.transport.state());
+        if self.is_disconnected() {
+            self.disconnect();
         }
     }
 
@@ -76,137 +255,125 @@ impl<M: NetworkMessage> Client<M> {
 
         {
             /* yojimbo BaseClient::AdvanceTime */
-            if !self.endpoint.is_null() {
-                if let Some(connection) = &mut self.connection {
-                    connection.advance_time(self.time);
-                    if connection.error_level() != ConnectionErrorLevel::None {
-                        log::error!("connection error. disconnecting client");
-                        self.disconnect();
-                        return;
-                    }
-                    unsafe {
-                        reliable_endpoint_update(self.endpoint, self.time);
-                        let mut num_acks = 0;
-                        let acks = reliable_endpoint_get_acks(self.endpoint, &mut num_acks);
-                        connection.process_acks(acks, num_acks);
-                        reliable_endpoint_clear_acks(self.endpoint);
-                    }
+            if let (Some(endpoint), Some(connection)) = (&mut self.endpoint, &mut self.connection) {
+                connection.advance_time(self.time);
+                let error_level = connection.error_level();
+                if error_level != ConnectionErrorLevel::None {
+                    log::error!("connection error. disconnecting client");
+                    self.set_state(ClientState::Error(ClientError::Connection(error_level)));
+                    self.disconnect();
+                    return;
                 }
+                endpoint.update(self.time);
+                connection.process_acks(&endpoint.take_acks());
             }
             if let Some(network_simulator) = &mut self.network_simulator {
                 network_simulator.advance_time(self.time);
             }
         }
 
-        if self.client.is_null() {
+        if self.is_disconnected() {
             return;
         }
-        let state = unsafe {
-            netcode_client_update(self.client, self.time);
-            let state = netcode_client_state(self.client);
-            client_state_from_netcode_state(state)
-        };
-        self.client_state = state;
-        if matches!(state, ClientState::Disconnected | ClientState::Error) {
+        self.transport.update(self.time);
+        let state = self.transport.state();
+        self.set_state(state);
+        if matches!(state, ClientState::Disconnected | ClientState::Error(_)) {
             self.disconnect();
         }
         if let Some(network_simulator) = &mut self.network_simulator {
             if network_simulator.active() {
                 for (_, packet_data) in network_simulator.receive_packets() {
-                    unsafe {
-                        netcode_client_send_packet(
-                            self.client,
-                            packet_data.as_ptr(),
-                            packet_data.len() as i32,
-                        );
-                    }
+                    self.transport.send_packet(packet_data);
                 }
             }
         }
     }
 
-    pub fn insecure_connect(
-        &mut self,
-        private_key: &[u8; PRIVATE_KEY_BYTES],
-        client_id: u64,
-        server_addresses: &[&str],
-    ) {
-        assert!(!server_addresses.is_empty());
-        assert!(server_addresses.len() <= NETCODE_MAX_SERVERS_PER_CONNECT as usize);
-
-        self.disconnect();
-        self.connect_internal();
-        self.client_id = client_id;
-        self.create_client();
-        if self.client.is_null() {
-            self.disconnect();
-            return;
-        }
-        let mut connect_token = match generate_insecure_connect_token(
-            &self.config,
-            private_key,
-            client_id,
-            server_addresses,
-        ) {
-            Some(connect_token) => connect_token,
-            None => {
-                log::error!("failed to generate insecure connect token");
-                self.client_state = ClientState::Error;
-                return;
-            }
-        };
-        unsafe { netcode_client_connect(self.client, connect_token.as_mut_ptr()) };
-        self.client_state = ClientState::Connecting;
-    }
-
     pub fn send_packets(&mut self) {
-        if !self.is_connected() {
+        if !self.is_connected() || self.local {
             return;
         }
-        assert!(!self.client.is_null());
-        let packet_sequence = unsafe { reliable_endpoint_next_packet_sequence(self.endpoint) };
-        if let Some(connection) = &mut self.connection {
-            let written_bytes =
-                connection.generate_packet(packet_sequence, &mut self.packet_buffer[..]);
-            if written_bytes > 0 {
-                unsafe {
-                    let written_slice = &mut self.packet_buffer[..written_bytes];
-                    reliable_endpoint_send_packet(
-                        self.endpoint,
-                        written_slice.as_mut_ptr(),
-                        written_slice.len() as _,
-                    );
-                }
+        let (Some(endpoint), Some(connection)) = (&mut self.endpoint, &mut self.connection) else {
+            return;
+        };
+        let packet_sequence = endpoint.next_packet_sequence();
+        let written_bytes =
+            connection.generate_packet(packet_sequence, &mut self.packet_buffer[..]);
+        if written_bytes > 0 {
+            let packets = endpoint.send_packet(&self.packet_buffer[..written_bytes]);
+            for packet in packets {
+                self.transmit_packet(&packet);
             }
         }
     }
 
     pub fn receive_packets(&mut self) {
-        if !self.is_connected() {
+        if !self.is_connected() || self.local {
             return;
         }
-        assert!(!self.client.is_null());
-        loop {
-            unsafe {
-                let mut packet_bytes: i32 = 0;
-                let mut packet_sequence: u64 = 0;
-                let packet_data = netcode_client_receive_packet(
-                    self.client,
-                    &mut packet_bytes,
-                    &mut packet_sequence,
-                );
-                if packet_data.is_null() {
-                    break;
-                }
-                reliable_endpoint_receive_packet(self.endpoint, packet_data, packet_bytes);
-                netcode_client_free_packet(self.client, packet_data as *mut _);
-            }
-        }
+        let (Some(endpoint), Some(connection)) = (&mut self.endpoint, &mut self.connection) else {
+            return;
+        };
+        while self.transport.receive_packet(|packet_data| {
+            endpoint.receive_packet(packet_data, |sequence, payload| {
+                connection.process_packet(sequence, payload)
+            });
+        }) {}
     }
 
-    pub fn send_message(&mut self, channel_index: usize, message: M) {
-        let Some(connection) = self.connection.as_mut() else { return };
-        connection.send_message(channel_index, message);
+    /// Queue a message to be sent on a channel.
+    ///
+    /// Returns the message id, which `poll_acked` reports once the message is delivered, or
+    /// `None` if the message was dropped (see `try_send_message`).
+    ///
+    /// On reliable channels, the receiver gets the same id from `receive_message_with_id`. On
+    /// unreliable channels the id only means something to the sender: the receiver gets the
+    /// packet sequence instead. To agree on a message (e.g. the last acked snapshot), put your
+    /// own id in the message.
+    pub fn send_message(&mut self, channel_index: usize, message: M) -> Option<u16> {
+        self.connection
+            .as_mut()?
+            .send_message(channel_index, message)
+    }
+
+    /// Like `send_message`, for unreliable channels which are sending more than fits in a
+    /// packet (e.g. state updates for many objects).
+    ///
+    /// Every packet, each waiting message gains its `priority`, and the messages with the most
+    /// are sent first. Messages which don't fit wait for the next packet if the channel has a
+    /// `ChannelConfig::message_time_to_live` (so low priority messages are delayed but still
+    /// sent eventually, unless they expire), and are dropped otherwise. Once a packet is full, or
+    /// has `ChannelConfig::max_messages_per_packet` messages, the remaining messages wait for
+    /// the next packet either way. Messages sent with
+    /// `send_message` have `DEFAULT_MESSAGE_PRIORITY`. Reliable and `UnreliableSequenced`
+    /// channels always send messages in order, and ignore the priority.
+    ///
+    /// Negative and NaN priorities are treated as 0, and infinite ones as `f32::MAX`.
+    pub fn send_message_with_priority(
+        &mut self,
+        channel_index: usize,
+        message: M,
+        priority: f32,
+    ) -> Option<u16> {
+        self.connection
+            .as_mut()?
+            .send_message_with_priority(channel_index, message, priority)
+    }
+
+    /// Like `send_message`, but hands the message back if it can't be sent, instead of dropping
+    /// it (or disconnecting, if the send queue is full or the channel doesn't allow blocks).
+    ///
+    /// Messages sent while connecting are sent once connected.
+    pub fn try_send_message(
+        &mut self,
+        channel_index: usize,
+        message: M,
+    ) -> Result<u16, SendError<M>> {
+        let Some(connection) = self.connection.as_mut() else {
+            return Err(SendError::NotConnected(message));
+        };
+        connection.try_send_message(channel_index, message)
     }
 
     /// Receive a message.
@@ -231,13 +398,46 @@ impl<M: NetworkMessage> Client<M> {
     ///
     /// For unordered channels, the ID is an incrementing & wrapping "sequence number", which
     /// can be used to determine when an unreliable message is older or newer than a previously
-    /// received reliable message. (e.g., so you can ignore old transform replications)
+    /// received reliable message. (To simply ignore old transform replications, use an
+    /// `UnreliableSequenced` channel instead.)
+    ///
+    /// On unreliable channels, this id is the packet sequence, which is unrelated to the id
+    /// `send_message` returned to the sender.
     ///
     /// Returns `None`` when all received messages are handled. Call `receive_packets` before this.
     pub fn receive_message_with_id(&mut self, channel_index: usize) -> Option<(u16, M)> {
         self.connection.as_mut()?.receive_message(channel_index)
     }
 
+    /// Take the ids (returned by `send_message`) of the messages sent on this channel which
+    /// were acked since the last call.
+    ///
+    /// Reliable messages are acked once delivered. Unreliable messages are acked when the packet
+    /// they were sent in is acked, e.g. to delta compress snapshots against the last one the
+    /// server received. Messages sent to a local server are acked straight away.
+    ///
+    /// Unreliable message ids are local to the sender, and don't match the ids the receiver
+    /// gets from `receive_message_with_id` (see `send_message`).
+    ///
+    /// Up to `message_send_queue_size` ids are kept, so call this regularly.
+    pub fn poll_acked(&mut self, channel_index: usize) -> impl Iterator<Item = u16> + '_ {
+        self.connection
+            .iter_mut()
+            .flat_map(move |connection| connection.poll_acked(channel_index))
+    }
+
+    /// Take the ids of the messages sent on this channel which were lost since the last call.
+    ///
+    /// Unreliable messages are lost when their packet isn't acked in time, or when they expire
+    /// before they're sent (see `ChannelConfig::message_time_to_live`). `ReliableUnordered`
+    /// messages are resent until they're acked or expire. `ReliableOrdered` messages are never
+    /// lost: the channel fails instead.
+    pub fn poll_lost(&mut self, channel_index: usize) -> impl Iterator<Item = u16> + '_ {
+        self.connection
+            .iter_mut()
+            .flat_map(move |connection| connection.poll_lost(channel_index))
+    }
+
     /// Check if this client is currently successfully connected.
     ///
     /// This means the client has finished the handshake and is
@@ -248,7 +448,7 @@ impl<M: NetworkMessage> Client<M> {
             ClientState::Connected => true,
             ClientState::Connecting => false,
             ClientState::Disconnected => false,
-            ClientState::Error => false,
+            ClientState::Error(_) => false,
         }
     }
 
@@ -263,7 +463,7 @@ impl<M: NetworkMessage> Client<M> {
             ClientState::Connected => false,
             ClientState::Connecting => false,
             ClientState::Disconnected => true,
-            ClientState::Error => true,
+            ClientState::Error(_) => true,
         }
     }
 
@@ -301,39 +501,7 @@ impl<M: NetworkMessage> Client<M> {
             return None;
         }
 
-        let endpoint = self.endpoint;
-        assert!(!endpoint.is_null());
-
-        unsafe {
-            let mut sent_bandwidth = 0.0;
-            let mut received_bandwidth = 0.0;
-            let mut acked_bandwidth = 0.0;
-            reliable_endpoint_bandwidth(
-                endpoint,
-                &mut sent_bandwidth,
-                &mut received_bandwidth,
-                &mut acked_bandwidth,
-            );
-
-            let counters = reliable_endpoint_counters(endpoint);
-            let num_packets_sent =
-                *counters.offset(RELIABLE_ENDPOINT_COUNTER_NUM_PACKETS_SENT as _);
-            let num_packets_received =
-                *counters.offset(RELIABLE_ENDPOINT_COUNTER_NUM_PACKETS_RECEIVED as _);
-            let num_packets_acked =
-                *counters.offset(RELIABLE_ENDPOINT_COUNTER_NUM_PACKETS_ACKED as _);
-
-            Some(NetworkInfo {
-                rtt: reliable_endpoint_rtt(endpoint),
-                packet_loss: reliable_endpoint_packet_loss(endpoint),
-                sent_bandwidth,
-                received_bandwidth,
-                acked_bandwidth,
-                num_packets_sent,
-                num_packets_received,
-                num_packets_acked,
-            })
-        }
+        self.endpoint.as_ref().map(NetworkInfo::from_endpoint)
     }
 
     /// Get the counters for channel `channel_index`.
@@ -344,14 +512,33 @@ impl<M: NetworkMessage> Client<M> {
     // TODO: client_index()
 
     pub fn connection_failed(&self) -> bool {
-        matches!(self.client_state, ClientState::Error)
+        matches!(self.client_state, ClientState::Error(_))
     }
 
-    pub fn bound_port(&self) -> Option<u16> {
-        self.bound_port
+    /// Why the client failed to connect or lost its connection, if it did.
+    pub fn error(&self) -> Option<ClientError> {
+        match self.client_state {
+            ClientState::Error(error) => Some(error),
+            _ => None,
+        }
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.client_state
+    }
+
+    /// Take the next state change, in the order they happened.
+    ///
+    /// State changes are seen by `connect`, `advance_time` and `disconnect`, so a client which
+    /// goes through several states in one `advance_time` only reports the last one.
+    pub fn poll_event(&mut self) -> Option<ClientEvent> {
+        self.events.pop_front()
     }
 
-    // TODO: loopback
+    /// The transport used to connect to the server.
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
 
     /// Called regardless of connection security
     fn connect_internal(&mut self) {
@@ -364,113 +551,49 @@ impl<M: NetworkMessage> Client<M> {
             .as_ref()
             .map(|config| NetworkSimulator::new(config.max_simulator_packets, self.time));
 
-        let mut reliable_config = self.config.new_reliable_config(
-            self as *const _ as *mut _,
-            "client endpoint",
-            None,
-            transmit_packet::<M>,
-            process_packet::<M>,
-        );
-        unsafe {
-            let endpoint = reliable_endpoint_create(&mut reliable_config, self.time);
-            self.endpoint = endpoint;
-            reliable_endpoint_reset(endpoint);
-        }
+        let reliable_config = self.config.new_reliable_config("client endpoint", None);
+        self.endpoint = Some(ReliableEndpoint::new(reliable_config, self.time));
     }
 
-    /// Initialize the `client` field (with the `address` field).
-    fn create_client(&mut self) {
-        self.destroy_client();
-        let mut netcode_config =
-            gf_init_default!(netcode_client_config_t, netcode_default_client_config);
-        netcode_config.callback_context = self as *mut _ as *mut c_void;
-        netcode_config.state_change_callback = Some(state_change_callback::<M>);
-        netcode_config.send_loopback_packet_callback = None; // TODO
-        let address = CString::new(self.address.as_str()).unwrap();
-        self.client = unsafe {
-            netcode_client_create(address.as_ptr() as *mut i8, &netcode_config, self.time)
-        };
-
-        if !self.client.is_null() {
-            self.bound_port = Some(unsafe { netcode_client_get_port(self.client) });
-        }
-    }
-
-    fn destroy_client(&mut self) {
-        if self.client.is_null() {
-            return;
-        }
-        self.bound_port = None;
-        unsafe { netcode_client_destroy(self.client) };
-        self.client = std::ptr::null_mut();
-        self.network_simulator = None;
-    }
-
-    fn state_change_callback(&mut self, previous: ClientState, current: ClientState) {
-        // we could consider removing this callback entirely since it's just wasted performance
-        log::debug!(
-            "client state changed from: {:?} to {:?}",
-            &previous,
-            &current
-        );
-    }
-
-    unsafe fn transmit_packet(
-        &mut self,
-        _packet_sequence: u16,
-        packet_data: *mut u8,
-        packet_bytes: i32,
-    ) {
-        // TODO: move the unsafety out of connection and handle it here... duh
-
+    fn transmit_packet(&mut self, packet_data: &[u8]) {
         if let Some(network_simulator) = &mut self.network_simulator {
             if network_simulator.active() {
                 // intercept the packet and defer sending until `advance_time`
-                let packet_data =
-                    unsafe { slice::from_raw_parts(packet_data, packet_bytes as usize) };
                 network_simulator.send_packet(0, packet_data);
 
                 return;
             }
         }
 
-        netcode_client_send_packet(self.client, packet_data, packet_bytes);
-    }
-
-    unsafe fn process_packet(
-        &mut self,
-        packet_sequence: u16,
-        packet_data: *mut u8,
-        packet_bytes: i32,
-    ) -> i32 {
-        let result = self
-            .connection
-            .as_mut()
-            .expect("client not connected")
-            .process_packet(packet_sequence, packet_data, packet_bytes as _);
-        if result {
-            1
-        } else {
-            0
-        }
+        self.transport.send_packet(packet_data);
     }
 
     pub fn disconnect(&mut self) {
-        if !matches!(self.client_state, ClientState::Error) {
+        if !matches!(self.client_state, ClientState::Error(_)) {
             // set to disconnected state - but do not overwrite the state if it is `Error`
             // (this ensures the user can detect error states vs disconnected)
-            self.client_state = ClientState::Disconnected;
+            self.set_state(ClientState::Disconnected);
         }
-        self.destroy_client();
+        self.transport.disconnect();
         self.destroy_internal();
         self.client_id = 0;
+        self.client_index = usize::MAX;
+        self.local = false;
     }
 
-    fn destroy_internal(&mut self) {
-        if !self.endpoint.is_null() {
-            unsafe { reliable_endpoint_destroy(self.endpoint) };
-            self.endpoint = std::ptr::null_mut();
+    fn set_state(&mut self, state: ClientState) {
+        if state == self.client_state {
+            return;
         }
+        self.events.push_back(ClientEvent::StateChanged {
+            previous: self.client_state,
+            current: state,
+        });
+        self.client_state = state;
+    }
+
+    fn destroy_internal(&mut self) {
+        self.endpoint = None;
         self.network_simulator = None;
         self.connection = None;
         for i in &mut self.packet_buffer {
@@ -478,100 +601,3 @@ impl<M: NetworkMessage> Client<M> {
         }
     }
 }
-
-fn generate_insecure_connect_token(
-    config: &ClientServerConfig,
-    private_key: &[u8; PRIVATE_KEY_BYTES],
-    client_id: u64,
-    server_addresses: &[&str],
-) -> Option<[u8; NETCODE_CONNECT_TOKEN_BYTES as _]> {
-    let mut server_address_strings = Vec::new();
-    let mut server_address_string_pointers = Vec::new();
-    for addr in server_addresses {
-        server_address_strings.push(CString::new(*addr).unwrap());
-        let ptr = server_address_strings.last().unwrap().as_ptr();
-        server_address_string_pointers.push(ptr);
-    }
-
-    let mut user_data = [0u8; 256];
-    let mut connect_token = [0u8; NETCODE_CONNECT_TOKEN_BYTES as _];
-
-    let ok = unsafe {
-        netcode_generate_connect_token(
-            server_addresses.len() as i32,
-            server_address_string_pointers.as_ptr() as *mut *const i8,
-            server_address_string_pointers.as_ptr() as *mut *const i8,
-            config.timeout,
-            config.timeout,
-            client_id,
-            config.protocol_id,
-            private_key.as_ptr() as *mut u8,
-            user_data.as_mut_ptr(),
-            connect_token.as_mut_ptr(),
-        ) == (NETCODE_OK as i32)
-    };
-
-    if !ok {
-        return None;
-    }
-
-    Some(connect_token)
-}
-
-fn client_state_from_netcode_state(state: i32) -> ClientState {
-    if state < NETCODE_CLIENT_STATE_DISCONNECTED as i32 {
-        ClientState::Error
-    } else if state == NETCODE_CLIENT_STATE_DISCONNECTED as i32 {
-        ClientState::Disconnected
-    } else if state == NETCODE_CLIENT_STATE_SENDING_CONNECTION_REQUEST as i32
-        || state == NETCODE_CLIENT_STATE_SENDING_CONNECTION_RESPONSE as i32
-    {
-        ClientState::Connecting
-    } else {
-        ClientState::Connected
-    }
-}
-
-unsafe extern "C" fn transmit_packet<M: NetworkMessage>(
-    context: *mut c_void,
-    _index: i32,
-    packet_sequence: u16,
-    packet_data: *mut u8,
-    packet_bytes: i32,
-) {
-    let client = context as *mut Client<M>;
-    client
-        .as_mut()
-        .unwrap()
-        .transmit_packet(packet_sequence, packet_data, packet_bytes);
-}
-
-unsafe extern "C" fn process_packet<M: NetworkMessage>(
-    context: *mut c_void,
-    _index: i32,
-    packet_sequence: u16,
-    packet_data: *mut u8,
-    packet_bytes: i32,
-) -> i32 {
-    let client = context as *mut Client<M>;
-    client
-        .as_mut()
-        .unwrap()
-        .process_packet(packet_sequence, packet_data, packet_bytes)
-}
-
-extern "C" fn state_change_callback<M: NetworkMessage>(
-    context: *mut c_void,
-    previous: i32,
-    current: i32,
-) {
-    let client = context as *mut Client<M>;
-    let previous = ClientState::try_from_netcode_client_state_i32(previous).unwrap();
-    let current = ClientState::try_from_netcode_client_state_i32(current).unwrap();
-    unsafe {
-        client
-            .as_mut()
-            .unwrap()
-            .state_change_callback(previous, current);
-    }
-}
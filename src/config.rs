@@ -1,8 +1,10 @@
-use crate::bindings::*;
-use crate::gf_init_default;
+use crate::channel::{
+    CONSERVATIVE_CHANNEL_HEADER_BITS, CONSERVATIVE_FRAGMENT_HEADER_BITS,
+    CONSERVATIVE_MESSAGE_HEADER_BITS, CONSERVATIVE_PACKET_HEADER_BITS,
+};
 use crate::network_simulator::NetworkSimulatorConfig;
-use std::ffi::c_void;
-use std::ffi::CString;
+use crate::reliable::ReliableConfig;
+use std::fmt;
 
 const YOJIMBO_DEFAULT_TIMEOUT: i32 = 5;
 
@@ -66,12 +68,135 @@ impl ClientServerConfig {
             rtt_smoothing_factor: 0.0025,
         }
     }
+
+    /// Check that the config doesn't contain any impossible values.
+    ///
+    /// `Client::new` and `Server::new` panic if this fails.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let max_packet_size = self.connection.max_packet_size;
+        if max_packet_size == 0 {
+            return Err(ConfigError::MaxPacketSize);
+        }
+        if self.connection.channels.is_empty() {
+            return Err(ConfigError::NoChannels);
+        }
+        if self.packet_fragment_size == 0
+            || self.max_packet_fragments == 0
+            || self.max_packet_fragments > 256
+            || self.max_packet_fragments * self.packet_fragment_size < max_packet_size
+        {
+            return Err(ConfigError::PacketFragments);
+        }
+
+        for (channel_index, channel) in self.connection.channels.iter().enumerate() {
+            let invalid = |reason| {
+                Err(ConfigError::Channel {
+                    channel_index,
+                    reason,
+                })
+            };
+
+            if channel.max_messages_per_packet == 0 {
+                return invalid("max_messages_per_packet must be at least 1");
+            }
+            if channel.priority == 0 {
+                return invalid("priority must be at least 1");
+            }
+            if matches!(channel.message_time_to_live, Some(ttl) if ttl.is_nan() || ttl <= 0.0) {
+                return invalid("message_time_to_live must be positive");
+            }
+            // every message takes at least one bit
+            if channel.max_messages_per_packet > 8 * max_packet_size {
+                return invalid("max_messages_per_packet can't fit in max_packet_size");
+            }
+            if channel.sent_packet_buffer_size == 0
+                || channel.message_send_queue_size == 0
+                || channel.message_receive_queue_size == 0
+            {
+                return invalid("buffer and queue sizes must be at least 1");
+            }
+            if channel.sent_packet_buffer_size > u16::MAX as usize
+                || channel.message_send_queue_size > u16::MAX as usize
+                || channel.message_receive_queue_size > u16::MAX as usize
+            {
+                return invalid("buffer and queue sizes must be at most 65535");
+            }
+            // a packet can't carry more messages than either end can queue
+            if channel.max_messages_per_packet
+                > channel
+                    .message_send_queue_size
+                    .min(channel.message_receive_queue_size)
+            {
+                return invalid("max_messages_per_packet is larger than the message queues");
+            }
+            if channel.blocks_enabled() {
+                if channel.max_block_size == 0 {
+                    return invalid("max_block_size must be at least 1");
+                }
+                // the first fragment is sent along with its message
+                if channel.block_fragment_size == 0
+                    || channel.block_fragment_size * 8
+                        + CONSERVATIVE_FRAGMENT_HEADER_BITS
+                        + CONSERVATIVE_MESSAGE_HEADER_BITS
+                        > channel.max_channel_bits(max_packet_size)
+                {
+                    return invalid("block_fragment_size must be at least 1 and fit in a packet");
+                }
+                if channel.max_fragments_per_block() > u16::MAX as usize {
+                    return invalid("max_block_size needs more than 65535 fragments");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An impossible value in a `ClientServerConfig`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// `max_packet_size` must be at least 1.
+    MaxPacketSize,
+    /// At least one channel is required.
+    NoChannels,
+    /// `max_packet_fragments` must be between 1 and 256, and enough fragments
+    /// of `packet_fragment_size` to hold `max_packet_size`.
+    PacketFragments,
+    /// The config for `channel_index` is invalid.
+    Channel {
+        channel_index: usize,
+        reason: &'static str,
+    },
 }
 
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MaxPacketSize => write!(f, "max_packet_size must be at least 1"),
+            ConfigError::NoChannels => write!(f, "at least one channel is required"),
+            ConfigError::PacketFragments => write!(
+                f,
+                "max_packet_fragments must be between 1 and 256 and cover max_packet_size"
+            ),
+            ConfigError::Channel {
+                channel_index,
+                reason,
+            } => write!(f, "channel {}: {}", channel_index, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 #[derive(Debug, Clone)]
 pub struct ConnectionConfig {
     pub max_packet_size: usize,
     pub channels: Vec<ChannelConfig>,
+    /// Limit on the bytes of message data sent per second (bytes). Packets are skipped while
+    /// the connection is over the limit; this doesn't count packet headers or acks.
+    ///
+    /// None (the default) means no limit.
+    pub max_bytes_per_second: Option<usize>,
 }
 
 impl ConnectionConfig {
@@ -80,59 +205,30 @@ impl ConnectionConfig {
         ConnectionConfig {
             max_packet_size: 8 * 1024,
             channels,
+            max_bytes_per_second: None,
         }
     }
 }
 
-pub(crate) type ReliableTransmitPacketFn = unsafe extern "C" fn(
-    context: *mut c_void,
-    index: i32,
-    packet_sequence: u16,
-    packet_data: *mut u8,
-    packet_bytes: i32,
-);
-
-pub(crate) type ReliableProcessPacketFn = unsafe extern "C" fn(
-    context: *mut c_void,
-    index: i32,
-    packet_sequence: u16,
-    packet_data: *mut u8,
-    packet_bytes: i32,
-) -> i32;
-
 impl ClientServerConfig {
     pub(crate) fn new_reliable_config(
         &self,
-        context: *mut c_void,
         name: &str,
         client_index: Option<usize>,
-        transmit_packet: ReliableTransmitPacketFn,
-        process_packet: ReliableProcessPacketFn,
-    ) -> reliable_config_t {
-        let mut reliable_config = gf_init_default!(reliable_config_t, reliable_default_config);
-
-        let name = CString::new(name).unwrap();
-        reliable_config.name[..name.to_bytes_with_nul().len()]
-            .copy_from_slice(unsafe { &*(name.to_bytes_with_nul() as *const [u8] as *const [i8]) });
-        reliable_config.context = context;
-        reliable_config.index = client_index.unwrap_or(0) as _;
-        reliable_config.max_packet_size = self.connection.max_packet_size as _;
-        reliable_config.fragment_above = self.fragment_packets_above as _;
-        reliable_config.max_fragments = self.max_packet_fragments as _;
-        reliable_config.fragment_size = self.packet_fragment_size as _;
-        reliable_config.ack_buffer_size = self.acked_packets_buffer_size as _;
-        reliable_config.received_packets_buffer_size = self.received_packets_buffer_size as _;
-        reliable_config.fragment_reassembly_buffer_size = self.packet_reassembly_buffer_size as _;
-        reliable_config.rtt_smoothing_factor = self.rtt_smoothing_factor;
-        reliable_config.transmit_packet_function = Some(transmit_packet);
-        reliable_config.process_packet_function = Some(process_packet);
-
-        // don't override `reliable`'s default allocator
-        // reliable_config.allocator_context = std::ptr::null_mut();
-        // reliable_config.allocate_function = None;
-        // reliable_config.free_function = None;
-
-        reliable_config
+    ) -> ReliableConfig {
+        ReliableConfig {
+            name: name.to_string(),
+            index: client_index.unwrap_or(0),
+            max_packet_size: self.connection.max_packet_size,
+            fragment_above: self.fragment_packets_above,
+            max_fragments: self.max_packet_fragments,
+            fragment_size: self.packet_fragment_size,
+            ack_buffer_size: self.acked_packets_buffer_size,
+            received_packets_buffer_size: self.received_packets_buffer_size,
+            fragment_reassembly_buffer_size: self.packet_reassembly_buffer_size,
+            rtt_smoothing_factor: self.rtt_smoothing_factor,
+            ..Default::default()
+        }
     }
 }
 
@@ -144,15 +240,53 @@ pub struct ChannelConfig {
     pub message_receive_queue_size: usize,
     /// Maximum number of messages per packet.
     ///
-    /// Note that this currently has a limitation of 256 due to the way that
-    /// messages are serialized ([message count - 1] is serialized as a byte). If you
-    /// feel like implementing dynamic integer serialization, go for it! PRs welcome!
+    /// The message count is variable length encoded, so in practice the
+    /// number of messages per packet is limited by `max_packet_size`. Must
+    /// be at least 1, at most `8 * max_packet_size`, and no larger than
+    /// either message queue.
     pub max_messages_per_packet: usize,
     /// Maximum amount of message data to write to the packet for this channel (bytes). Specifying None means the channel can use up to the rest of the bytes remaining in the packet.
     pub packet_budget: Option<usize>,
+    /// This channel's share of each packet, relative to the other channels with messages to
+    /// send. Space a channel doesn't use is shared by the others. Must be at least 1, and
+    /// defaults to 1.
+    pub priority: u32,
     pub message_resend_time: f64,
+    /// Maximum time a message can wait in the send queue (seconds), e.g. for inputs which are
+    /// useless once they're late.
+    ///
+    /// Unreliable messages which don't fit in a packet stay queued for later packets (see
+    /// `Client::send_message_with_priority`), and are dropped once they're this old. Without a
+    /// time to live, a message too big for the space left in a packet is dropped, but messages
+    /// left over once the packet is full (or has `max_messages_per_packet` messages) still wait
+    /// for the next packet.
+    ///
+    /// `ReliableUnordered` messages which haven't been acked after this long are dropped too, and
+    /// reported by `Client::poll_lost`.
+    ///
+    /// On `ReliableOrdered` channels, a message which hasn't been acked after this long puts the
+    /// channel into `ChannelErrorLevel::MessageExpired`, since later messages can't be received
+    /// without it. **This disconnects the peer**, so only set a time to live on ordered channels
+    /// if a message that late means the connection is lost anyway.
+    ///
+    /// None (the default) means messages never expire.
+    pub message_time_to_live: Option<f64>,
+    /// Minimum time between resends of an unacked block fragment (seconds).
     pub block_fragment_resend_time: f64,
-    // TODO: blocks: pub max_block_size: usize, pub block_fragment_size: usize, pub disable_blocks: bool,
+    /// Maximum size of a block attached to a message (bytes).
+    pub max_block_size: usize,
+    /// Blocks are split into fragments of this size (bytes). One fragment is sent per packet.
+    ///
+    /// A fragment, along with the packet, channel, fragment and message headers, must fit in
+    /// `max_packet_size` (and `packet_budget`, if set).
+    pub block_fragment_size: usize,
+    /// Disable blocks on this channel.
+    ///
+    /// Sending a message with a block attached on a channel with blocks
+    /// disabled puts the channel into `ChannelErrorLevel::BlocksDisabled`.
+    ///
+    /// Blocks are only supported on `ReliableOrdered` channels.
+    pub disable_blocks: bool,
 }
 
 impl ChannelConfig {
@@ -164,18 +298,34 @@ impl ChannelConfig {
             message_receive_queue_size: 1024,
             max_messages_per_packet: 256,
             packet_budget: None,
+            priority: 1,
             message_resend_time: 0.1,
+            message_time_to_live: None,
             block_fragment_resend_time: 0.25,
-            // TODO: blocks:
-            // disable_blocks: false,
-            // max_block_size: 256 * 1024,
-            // block_fragment_size: 1024,
+            max_block_size: 256 * 1024,
+            block_fragment_size: 1024,
+            disable_blocks: false,
+        }
+    }
+
+    pub fn max_fragments_per_block(&self) -> usize {
+        (self.max_block_size as f64 / self.block_fragment_size as f64).ceil() as _
+    }
+
+    /// The most bits this channel can write to a packet of `max_packet_size` bytes.
+    pub(crate) fn max_channel_bits(&self, max_packet_size: usize) -> usize {
+        let bits = (max_packet_size * 8)
+            .saturating_sub(CONSERVATIVE_PACKET_HEADER_BITS + CONSERVATIVE_CHANNEL_HEADER_BITS);
+        match self.packet_budget {
+            Some(packet_budget) => bits.min(packet_budget * 8),
+            None => bits,
         }
     }
 
-    // pub fn max_fragments_per_block(&self) -> usize {
-    //     self.max_block_size / self.block_fragment_size
-    // }
+    /// True if messages with blocks attached can be sent on this channel.
+    pub(crate) fn blocks_enabled(&self) -> bool {
+        !self.disable_blocks && matches!(self.kind, ChannelType::ReliableOrdered)
+    }
 }
 
 /// Determines the reliability and ordering guarantees for a channel.
@@ -183,4 +333,71 @@ impl ChannelConfig {
 pub enum ChannelType {
     ReliableOrdered,
     UnreliableUnordered,
+    /// Like `ReliableOrdered`, but each message is received as soon as it arrives, instead of
+    /// waiting for the messages sent before it. Blocks are not supported.
+    ReliableUnordered,
+    /// Like `UnreliableUnordered`, but messages older than the newest message received are
+    /// dropped, so the receiver never sees out of date data (e.g. for position updates).
+    UnreliableSequenced,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let config = ClientServerConfig::new(2);
+        assert_eq!(config.validate(), Ok(()));
+
+        let mut no_channels = config.clone();
+        no_channels.connection.channels.clear();
+        assert_eq!(no_channels.validate(), Err(ConfigError::NoChannels));
+
+        let mut large_packets = config.clone();
+        large_packets.connection.max_packet_size = 64 * 1024;
+        assert_eq!(large_packets.validate(), Err(ConfigError::PacketFragments));
+
+        let mut many_messages = config.clone();
+        many_messages.connection.channels[1].max_messages_per_packet = 4096;
+        many_messages.connection.channels[1].message_send_queue_size = 4096;
+        many_messages.connection.channels[1].message_receive_queue_size = 4096;
+        assert_eq!(many_messages.validate(), Ok(()));
+
+        // more messages than the receive queue can hold
+        many_messages.connection.channels[1].message_receive_queue_size = 4095;
+        assert!(many_messages.validate().is_err());
+
+        let mut no_messages = config.clone();
+        no_messages.connection.channels[1].max_messages_per_packet = 0;
+        assert!(matches!(
+            no_messages.validate(),
+            Err(ConfigError::Channel {
+                channel_index: 1,
+                ..
+            })
+        ));
+
+        let mut too_many_messages = config.clone();
+        too_many_messages.connection.channels[0].max_messages_per_packet =
+            8 * config.connection.max_packet_size + 1;
+        assert!(too_many_messages.validate().is_err());
+
+        let mut large_fragments = config.clone();
+        large_fragments.connection.channels[0].block_fragment_size =
+            config.connection.max_packet_size + 1;
+        assert!(large_fragments.validate().is_err());
+
+        // no room for the headers
+        large_fragments.connection.channels[0].block_fragment_size =
+            config.connection.max_packet_size;
+        assert!(large_fragments.validate().is_err());
+        large_fragments.connection.channels[0].block_fragment_size =
+            config.connection.max_packet_size - 32;
+        assert_eq!(large_fragments.validate(), Ok(()));
+
+        // block settings are ignored when blocks are disabled
+        large_fragments.connection.channels[0].disable_blocks = true;
+        assert_eq!(large_fragments.validate(), Ok(()));
+    }
 }
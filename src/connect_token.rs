@@ -0,0 +1,215 @@
+//! Connect tokens, which let clients connect to a server without knowing its private key.
+//!
+//! A connect token is generated by a trusted service (e.g. your login or
+//! matchmaking service), which shares the private key with the servers, and is
+//! handed to the client, which connects with `Client::connect`. Tokens expire,
+//! and can only be used with the listed servers.
+
+use std::ffi::CString;
+use std::fmt;
+
+use crate::bindings::{
+    netcode_generate_connect_token, NETCODE_MAX_SERVERS_PER_CONNECT, NETCODE_OK,
+};
+use crate::{CONNECT_TOKEN_BYTES, PRIVATE_KEY_BYTES, USER_DATA_BYTES};
+
+const DEFAULT_EXPIRE_SECONDS: i32 = 30;
+const DEFAULT_TIMEOUT_SECONDS: i32 = 5;
+
+/// Generates connect tokens for a client.
+///
+/// ```ignore
+/// let connect_token = ConnectTokenBuilder::new(&private_key, protocol_id, client_id)
+///     .server_address("203.0.113.1:40000")
+///     .expire_seconds(60)
+///     .build()?;
+/// ```
+#[derive(Clone)]
+pub struct ConnectTokenBuilder {
+    private_key: [u8; PRIVATE_KEY_BYTES],
+    protocol_id: u64,
+    client_id: u64,
+    public_server_addresses: Vec<String>,
+    internal_server_addresses: Vec<String>,
+    expire_seconds: i32,
+    timeout_seconds: i32,
+    user_data: [u8; USER_DATA_BYTES],
+}
+
+impl ConnectTokenBuilder {
+    /// Start building a token for `client_id`, for servers using `private_key` and `protocol_id`.
+    pub fn new(
+        private_key: &[u8; PRIVATE_KEY_BYTES],
+        protocol_id: u64,
+        client_id: u64,
+    ) -> ConnectTokenBuilder {
+        ConnectTokenBuilder {
+            private_key: *private_key,
+            protocol_id,
+            client_id,
+            public_server_addresses: Vec::new(),
+            internal_server_addresses: Vec::new(),
+            expire_seconds: DEFAULT_EXPIRE_SECONDS,
+            timeout_seconds: DEFAULT_TIMEOUT_SECONDS,
+            user_data: [0u8; USER_DATA_BYTES],
+        }
+    }
+
+    /// Add a server the client may connect to, in the order the client should try them.
+    pub fn server_address(self, address: &str) -> ConnectTokenBuilder {
+        self.server_address_with_internal(address, address)
+    }
+
+    /// Add a server which the client reaches on `public_address`, but which is bound to
+    /// `internal_address` (e.g. behind NAT or a load balancer).
+    pub fn server_address_with_internal(
+        mut self,
+        public_address: &str,
+        internal_address: &str,
+    ) -> ConnectTokenBuilder {
+        self.public_server_addresses
+            .push(public_address.to_string());
+        self.internal_server_addresses
+            .push(internal_address.to_string());
+        self
+    }
+
+    /// Seconds until the token can no longer be used to connect. Defaults to 30.
+    ///
+    /// Negative values mean the token never expires.
+    pub fn expire_seconds(mut self, expire_seconds: i32) -> ConnectTokenBuilder {
+        self.expire_seconds = expire_seconds;
+        self
+    }
+
+    /// Seconds without packets before the connection times out. Defaults to 5.
+    ///
+    /// Negative values disable timeouts (for debugging only).
+    pub fn timeout_seconds(mut self, timeout_seconds: i32) -> ConnectTokenBuilder {
+        self.timeout_seconds = timeout_seconds;
+        self
+    }
+
+    /// Data for the server about the client (e.g. an account id), which the client can't read
+    /// or change. Defaults to all zeroes.
+    pub fn user_data(mut self, user_data: &[u8; USER_DATA_BYTES]) -> ConnectTokenBuilder {
+        self.user_data = *user_data;
+        self
+    }
+
+    pub fn build(&self) -> Result<[u8; CONNECT_TOKEN_BYTES], ConnectTokenError> {
+        let num_server_addresses = self.public_server_addresses.len();
+        if num_server_addresses == 0 {
+            return Err(ConnectTokenError::NoServerAddresses);
+        }
+        if num_server_addresses > NETCODE_MAX_SERVERS_PER_CONNECT as usize {
+            return Err(ConnectTokenError::TooManyServerAddresses);
+        }
+
+        let to_c_strings = |addresses: &[String]| {
+            addresses
+                .iter()
+                .map(|address| {
+                    CString::new(address.as_str())
+                        .map_err(|_| ConnectTokenError::InvalidServerAddress(address.clone()))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let public_server_addresses = to_c_strings(&self.public_server_addresses)?;
+        let internal_server_addresses = to_c_strings(&self.internal_server_addresses)?;
+        let mut public_server_address_pointers: Vec<_> = public_server_addresses
+            .iter()
+            .map(|address| address.as_ptr())
+            .collect();
+        let mut internal_server_address_pointers: Vec<_> = internal_server_addresses
+            .iter()
+            .map(|address| address.as_ptr())
+            .collect();
+
+        let mut user_data = self.user_data;
+        let mut connect_token = [0u8; CONNECT_TOKEN_BYTES];
+
+        let ok = unsafe {
+            netcode_generate_connect_token(
+                num_server_addresses as i32,
+                public_server_address_pointers.as_mut_ptr(),
+                internal_server_address_pointers.as_mut_ptr(),
+                self.expire_seconds,
+                self.timeout_seconds,
+                self.client_id,
+                self.protocol_id,
+                self.private_key.as_ptr() as *mut u8,
+                user_data.as_mut_ptr(),
+                connect_token.as_mut_ptr(),
+            ) == (NETCODE_OK as i32)
+        };
+
+        if !ok {
+            return Err(ConnectTokenError::GenerateFailed);
+        }
+
+        Ok(connect_token)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectTokenError {
+    /// At least one server address is required.
+    NoServerAddresses,
+    /// A token can list at most 32 servers.
+    TooManyServerAddresses,
+    /// The server address contains a nul byte.
+    InvalidServerAddress(String),
+    /// `netcode` failed to generate the token, most likely because a server address
+    /// couldn't be parsed.
+    GenerateFailed,
+}
+
+impl fmt::Display for ConnectTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectTokenError::NoServerAddresses => {
+                write!(f, "at least one server address is required")
+            }
+            ConnectTokenError::TooManyServerAddresses => write!(
+                f,
+                "at most {} server addresses are allowed",
+                NETCODE_MAX_SERVERS_PER_CONNECT
+            ),
+            ConnectTokenError::InvalidServerAddress(address) => {
+                write!(f, "invalid server address: {:?}", address)
+            }
+            ConnectTokenError::GenerateFailed => write!(f, "failed to generate connect token"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectTokenError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_invalid_server_addresses() {
+        let builder = ConnectTokenBuilder::new(&[0u8; PRIVATE_KEY_BYTES], 0, 1);
+        assert_eq!(builder.build(), Err(ConnectTokenError::NoServerAddresses));
+
+        let mut too_many = builder.clone();
+        for port in 0..=NETCODE_MAX_SERVERS_PER_CONNECT {
+            too_many = too_many.server_address(&format!("127.0.0.1:{}", 40000 + port));
+        }
+        assert_eq!(
+            too_many.build(),
+            Err(ConnectTokenError::TooManyServerAddresses)
+        );
+
+        let nul = builder.server_address_with_internal("127.0.0.1:40000", "127.0.0.1\0:40000");
+        assert_eq!(
+            nul.build(),
+            Err(ConnectTokenError::InvalidServerAddress(
+                "127.0.0.1\0:40000".to_string()
+            ))
+        );
+    }
+}
@@ -1,11 +1,11 @@
-use std::{io::Cursor, slice};
-
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{collections::vec_deque::Drain, sync::Arc};
 
 use crate::{
+    bitpacker::{BitReader, BitWriter},
     channel::{
-        Channel, ChannelCounters, ChannelErrorLevel, ChannelPacketData,
-        CONSERVATIVE_CHANNEL_HEADER_BITS, CONSERVATIVE_PACKET_HEADER_BITS,
+        Channel, ChannelCounters, ChannelErrorLevel, ChannelPacketData, ReadPacketError, SendError,
+        SerializedMessage, CONSERVATIVE_CHANNEL_HEADER_BITS, CONSERVATIVE_PACKET_HEADER_BITS,
+        DEFAULT_MESSAGE_PRIORITY,
     },
     config::ConnectionConfig,
     message::NetworkMessage,
@@ -26,6 +26,17 @@ pub(crate) struct Connection<M> {
     config: ConnectionConfig,
     channels: Vec<Channel<M>>,
     error_level: ConnectionErrorLevel,
+    /// Stands in for the packet sequence when sending local messages.
+    local_sequence: u16,
+
+    time: f64,
+    /// Bytes that can be sent before going over `max_bytes_per_second`. Negative while over.
+    send_allowance: f64,
+    /// The channel which gets the first share of the next packet.
+    first_channel: usize,
+    /// Bits each channel was due in previous packets but couldn't use (e.g. because its next
+    /// message or block fragment is bigger than its share), carried over to the next packet.
+    channel_deficit: Vec<usize>,
 }
 
 impl<M: NetworkMessage> Connection<M> {
@@ -34,22 +45,47 @@ impl<M: NetworkMessage> Connection<M> {
 
         let mut channels = Vec::with_capacity(config.channels.len());
         for (channel_index, channel_config) in config.channels.iter().enumerate() {
-            channels.push(Channel::new(channel_config.clone(), channel_index, time));
+            channels.push(Channel::new(
+                channel_config.clone(),
+                channel_index,
+                config.max_packet_size,
+                time,
+            ));
         }
 
+        let channel_deficit = vec![0; channels.len()];
+
         Connection {
             config,
             channels,
             error_level: ConnectionErrorLevel::None,
+            local_sequence: 0,
+            time,
+            send_allowance: 0.0,
+            first_channel: 0,
+            channel_deficit,
         }
     }
 
     pub(crate) fn advance_time(&mut self, new_time: f64) {
+        if let Some(max_bytes_per_second) = self.config.max_bytes_per_second {
+            let elapsed = (new_time - self.time).max(0.0);
+            // allow at most one full packet to build up while idle
+            self.send_allowance = f64::min(
+                self.send_allowance + max_bytes_per_second as f64 * elapsed,
+                self.config.max_packet_size as f64,
+            );
+        }
+        self.time = new_time;
+
         for channel in &mut self.channels {
             channel.advance_time(new_time);
 
             if channel.error_level() != ChannelErrorLevel::None {
-                self.error_level = ConnectionErrorLevel::Channel;
+                // don't overwrite a more specific error (e.g. `ReadPacketFailed`)
+                if self.error_level == ConnectionErrorLevel::None {
+                    self.error_level = ConnectionErrorLevel::Channel;
+                }
                 return; // VERIFY: should this definitely be a return?
             }
         }
@@ -59,20 +95,15 @@ impl<M: NetworkMessage> Connection<M> {
         self.error_level
     }
 
-    pub(crate) unsafe fn process_acks(&mut self, acks: *mut u16, num_acks: i32) {
-        for i in 0..(num_acks as isize) {
+    pub(crate) fn process_acks(&mut self, acks: &[u16]) {
+        for &ack in acks {
             for channel in &mut self.channels {
-                channel.process_ack(*acks.offset(i));
+                channel.process_ack(ack);
             }
         }
     }
 
-    pub(crate) unsafe fn process_packet(
-        &mut self,
-        packet_sequence: u16,
-        packet_data: *const u8,
-        packet_bytes: usize,
-    ) -> bool {
+    pub(crate) fn process_packet(&mut self, packet_sequence: u16, packet_data: &[u8]) -> bool {
         if self.error_level() != ConnectionErrorLevel::None {
             log::debug!("failed to read packet because connection is in error state");
             return false;
@@ -82,18 +113,23 @@ impl<M: NetworkMessage> Connection<M> {
 
         {
             /* yojimbo Connection::ReadPacket */
-            assert!(!packet_data.is_null());
-            assert!(packet_bytes > 0);
-
-            packet
-                .deserialize(&self.config, packet_data, packet_bytes)
-                .expect("failed to deserialize");
-            // TODO: error handling
+            if let Err(err) = packet.deserialize(&self.config, packet_data) {
+                log::error!("failed to read packet: {}", err);
+                match err {
+                    ReadPacketError::Message { channel_index, .. } => self.channels[channel_index]
+                        .set_error_level(ChannelErrorLevel::FailedToSerialize),
+                    ReadPacketError::BlocksDisabled(channel_index) => self.channels[channel_index]
+                        .set_error_level(ChannelErrorLevel::BlocksDisabled),
+                    _ => {}
+                }
+                self.error_level = ConnectionErrorLevel::ReadPacketFailed;
+                return false;
+            }
         }
 
         for entry in packet.channel_data {
             let channel_index = entry.channel_index;
-            if channel_index > self.channels.len() {
+            if channel_index >= self.channels.len() {
                 log::error!(
                     "server received packet for channel that does not exist: {}",
                     entry.channel_index
@@ -117,10 +153,15 @@ impl<M: NetworkMessage> Connection<M> {
     /// Generate a packet, writing to packet_data.
     ///
     /// Returns the *number of bytes* written (not bits, which are tracked in the function body).
+    /// Returns 0 if there is nothing to send, or the connection is over `max_bytes_per_second`.
+    ///
+    /// Channels with messages to send share the packet by `ChannelConfig::priority`, like
+    /// deficit round robin: each channel in turn gets its share of the space the previous
+    /// channels left, and the first channel rotates each packet. A channel which can't use its
+    /// share carries it over to the next packet, for messages bigger than its share.
     ///
-    /// Caller should call `reliable_endpoint_send_packet` after this if bytes were written.
-    /// Reliable will then call the `transmit_packet` callback as appropriate (possibly
-    /// fragmenting the generated packet).
+    /// Caller should pass the written bytes to `ReliableEndpoint::send_packet` after this,
+    /// which returns the packets to transmit (possibly fragmenting the generated packet).
     pub(crate) fn generate_packet(
         &mut self,
         packet_sequence: u16,
@@ -130,15 +171,54 @@ impl<M: NetworkMessage> Connection<M> {
             return 0;
         }
 
+        if self.config.max_bytes_per_second.is_some() && self.send_allowance <= 0.0 {
+            return 0;
+        }
+
         // REFACTOR: consider caching
         let mut channel_data = Vec::new();
 
         assert!(!packet_data.is_empty());
         let mut available_bits = packet_data.len() * 8 - CONSERVATIVE_PACKET_HEADER_BITS;
+        let max_deficit = available_bits;
+
+        let num_channels = self.channels.len();
+        let first_channel = self.first_channel;
+        self.first_channel = (first_channel + 1) % num_channels;
+
+        let mut remaining_priority: usize = self
+            .channels
+            .iter()
+            .zip(&self.config.channels)
+            .filter(|(channel, _)| channel.has_messages_to_send())
+            .map(|(_, config)| config.priority as usize)
+            .sum();
+
+        for i in 0..num_channels {
+            let channel_index = (first_channel + i) % num_channels;
+            let channel = &mut self.channels[channel_index];
+            if !channel.has_messages_to_send() {
+                self.channel_deficit[channel_index] = 0;
+                continue;
+            }
+
+            let priority = self.config.channels[channel_index].priority as usize;
+            let share = available_bits * priority / remaining_priority;
+            remaining_priority -= priority;
+            let due_bits = share + self.channel_deficit[channel_index];
+            // leave room for the channel header
+            let channel_bits =
+                due_bits.min(available_bits.saturating_sub(CONSERVATIVE_CHANNEL_HEADER_BITS));
 
-        for channel in &mut self.channels {
             let (packet_data, packet_data_bits) =
-                channel.packet_data(packet_sequence, available_bits);
+                channel.packet_data(packet_sequence, channel_bits);
+
+            self.channel_deficit[channel_index] = if channel.has_messages_to_send() {
+                due_bits.saturating_sub(packet_data_bits).min(max_deficit)
+            } else {
+                0
+            };
+
             if packet_data_bits > 0 {
                 #[cfg(feature = "soak_debugging_asserts")]
                 {
@@ -158,17 +238,41 @@ impl<M: NetworkMessage> Connection<M> {
 
         if !channel_data.is_empty() {
             let packet = ConnectionPacket::new(channel_data);
-            packet
-                .serialize(&self.config, packet_data)
-                .expect("failed to deserialize")
-            // TODO: error handling
+            let bytes_written = packet.serialize(&self.config, packet_data);
+            if self.config.max_bytes_per_second.is_some() {
+                self.send_allowance -= bytes_written as f64;
+            }
+            bytes_written
         } else {
             0
         }
     }
 
+    /// Move the messages waiting to be sent straight to the receive queues of `receiver`, a
+    /// connection in the same process, instead of writing them to a packet.
+    ///
+    /// Messages are moved rather than read back from packets, and are delivered in one go
+    /// (there is no packet size limit), but otherwise the channels behave as usual.
+    pub(crate) fn send_local_messages(&mut self, receiver: &mut Connection<M>) {
+        assert_eq!(self.channels.len(), receiver.channels.len());
+
+        let packet_sequence = self.local_sequence;
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+
+        for (channel, receiver_channel) in self.channels.iter_mut().zip(&mut receiver.channels) {
+            let packet_data = channel.local_packet_data(packet_sequence);
+            if !packet_data.messages.is_empty() {
+                receiver_channel.process_packet_data(packet_data, packet_sequence);
+            }
+        }
+    }
+
     pub(crate) fn reset(&mut self) {
         self.error_level = ConnectionErrorLevel::None;
+        self.local_sequence = 0;
+        self.send_allowance = 0.0;
+        self.first_channel = 0;
+        self.channel_deficit.fill(0);
         for channel in &mut self.channels {
             channel.reset();
         }
@@ -186,8 +290,53 @@ impl<M: NetworkMessage> Connection<M> {
         self.channels[channel].has_messages_to_send()
     }
 
-    pub(crate) fn send_message(&mut self, channel_index: usize, message: M) {
-        self.channels[channel_index].send_message(message);
+    pub(crate) fn send_message(&mut self, channel_index: usize, message: M) -> Option<u16> {
+        self.send_message_with_priority(channel_index, message, DEFAULT_MESSAGE_PRIORITY)
+    }
+
+    pub(crate) fn send_message_with_priority(
+        &mut self,
+        channel_index: usize,
+        message: M,
+        priority: f32,
+    ) -> Option<u16> {
+        self.channels
+            .get_mut(channel_index)?
+            .send_message(message, priority)
+    }
+
+    /// Send a message which is shared with other connections, so it is only serialized once.
+    pub(crate) fn send_shared_message(
+        &mut self,
+        channel_index: usize,
+        message: &Arc<M>,
+        serialized: &SerializedMessage,
+    ) {
+        if let Some(channel) = self.channels.get_mut(channel_index) {
+            channel.send_shared_message(message, serialized);
+        }
+    }
+
+    pub(crate) fn poll_acked(&mut self, channel_index: usize) -> Drain<'_, u16> {
+        self.channels[channel_index].poll_acked()
+    }
+
+    pub(crate) fn poll_lost(&mut self, channel_index: usize) -> Drain<'_, u16> {
+        self.channels[channel_index].poll_lost()
+    }
+
+    pub(crate) fn try_send_message(
+        &mut self,
+        channel_index: usize,
+        message: M,
+    ) -> Result<u16, SendError<M>> {
+        if self.error_level != ConnectionErrorLevel::None {
+            return Err(SendError::ConnectionFailed(message));
+        }
+        match self.channels.get_mut(channel_index) {
+            Some(channel) => channel.try_send_message(message),
+            None => Err(SendError::InvalidChannel(message)),
+        }
     }
 
     pub(crate) fn receive_message(&mut self, channel_index: usize) -> Option<(u16, M)> {
@@ -199,52 +348,44 @@ struct ConnectionPacket<M> {
     channel_data: Vec<ChannelPacketData<M>>,
 }
 
-impl<M: NetworkMessage> ConnectionPacket<M> {
+impl<M> ConnectionPacket<M> {
     fn new(channel_data: Vec<ChannelPacketData<M>>) -> ConnectionPacket<M> {
         ConnectionPacket { channel_data }
     }
+}
 
-    fn serialize(&self, config: &ConnectionConfig, dest: &mut [u8]) -> Result<usize, M::Error> {
-        assert!(self.channel_data.len() < u16::MAX as usize);
+impl ConnectionPacket<SerializedMessage> {
+    fn serialize(&self, config: &ConnectionConfig, dest: &mut [u8]) -> usize {
+        let num_channels = config.channels.len() as u32;
+        assert!(self.channel_data.len() <= num_channels as usize);
 
-        let mut writer = Cursor::new(dest);
+        let mut writer = BitWriter::new(dest);
         writer
-            .write_u16::<LittleEndian>(self.channel_data.len() as _)
+            .write_int(self.channel_data.len() as u32, 0, num_channels)
             .unwrap();
-        assert!((writer.position() as usize) < CONSERVATIVE_PACKET_HEADER_BITS);
+        assert!(writer.bits_written() <= CONSERVATIVE_PACKET_HEADER_BITS);
 
         if self.channel_data.is_empty() {
-            return Ok(writer.position() as _);
+            return writer.bytes_written();
         }
 
         for channel_data in &self.channel_data {
-            channel_data.serialize(config, &mut writer)?;
+            channel_data.serialize(config, &mut writer);
         }
 
-        Ok(writer.position() as _)
+        writer.bytes_written()
     }
+}
 
-    unsafe fn deserialize(
+impl<M: NetworkMessage> ConnectionPacket<M> {
+    fn deserialize(
         &mut self,
         config: &ConnectionConfig,
-        packet_data: *const u8,
-        packet_bytes: usize,
-    ) -> Result<(), M::Error> {
-        /*
-           SAFETY: packet_data comes from a netcode_connection_payload_packet_t
-
-           netcode_connection_payload_packet_t is ultimately allocated in three places:
-             - read from decrypted buffer
-                - in which case all the bytes should be initialized
-             - loopback (both server and client send packets)
-                - packet_data is initialized if the sent packet is initialized
-        */
-        assert!(!packet_data.is_null());
-        debug_assert!(packet_bytes < isize::MAX as usize);
-        let src = slice::from_raw_parts(packet_data, packet_bytes);
-
-        let mut reader = Cursor::new(src);
-        let channels = reader.read_u16::<LittleEndian>().unwrap() as usize;
+        src: &[u8],
+    ) -> Result<(), ReadPacketError<M::Error>> {
+        let mut reader = BitReader::new(src);
+        // each channel writes at most one entry per packet
+        let channels = reader.read_int(0, config.channels.len() as u32)?;
 
         for _ in 0..channels {
             let data = ChannelPacketData::deserialize(config, &mut reader)?;
@@ -255,8 +396,20 @@ impl<M: NetworkMessage> ConnectionPacket<M> {
     }
 }
 
+/// Deserialize `data` as a connection packet, returning whether it was valid.
+///
+/// Only exposed for the fuzz targets in `fuzz/`.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub fn fuzz_read_packet<M: NetworkMessage>(config: &ConnectionConfig, data: &[u8]) -> bool {
+    let mut packet = ConnectionPacket::<M>::new(Vec::new());
+    packet.deserialize(config, data).is_ok()
+}
+
 #[cfg(test)]
 mod test {
+    use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
     use crate::config::{ChannelType, ClientServerConfig};
 
     use super::*;
@@ -282,6 +435,36 @@ mod test {
         }
     }
 
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct BlockTestMessage {
+        value: u64,
+        block: Option<Vec<u8>>,
+    }
+
+    impl NetworkMessage for BlockTestMessage {
+        type Error = std::io::Error;
+
+        fn serialize<W: std::io::Write>(&self, mut writer: W) -> Result<(), Self::Error> {
+            writer.write_u64::<LittleEndian>(self.value)?;
+
+            Ok(())
+        }
+
+        fn deserialize<R: std::io::Read>(mut reader: R) -> Result<Self, Self::Error> {
+            let value = reader.read_u64::<LittleEndian>()?;
+
+            Ok(BlockTestMessage { value, block: None })
+        }
+
+        fn block(&self) -> Option<&[u8]> {
+            self.block.as_deref()
+        }
+
+        fn attach_block(&mut self, block: Vec<u8>) {
+            self.block = Some(block);
+        }
+    }
+
     #[test]
     fn test_send_receive_unreliable_messages() {
         let mut time = 100.0;
@@ -580,11 +763,788 @@ mod test {
         );
     }
 
-    fn pump_connection_update(
+    #[test]
+    fn test_send_receive_reliable_block_messages() {
+        let mut time = 100.0;
+        let delta_time = 0.016;
+
+        let config = ClientServerConfig::new(1);
+        let mut config = config.connection;
+        config.channels[0].max_messages_per_packet = 8;
+        config.channels[0].kind = ChannelType::ReliableOrdered;
+        config.channels[0].max_block_size = 8 * 1024;
+        config.channels[0].block_fragment_size = 1000;
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::new(config.clone(), time);
+
+        let mut sender_sequence = 0;
+        let mut receiver_sequence = 0;
+
+        // every fourth message has a block attached, of varying size (and number of fragments)
+        let expected_block = |value: u64| -> Option<Vec<u8>> {
+            if value % 4 != 0 {
+                return None;
+            }
+            let block_size = 1 + (value as usize * 997) % config.channels[0].max_block_size;
+            Some((0..block_size).map(|i| (i as u64 + value) as u8).collect())
+        };
+
+        let messages_sent = 64;
+        for value in 0..messages_sent {
+            let block = expected_block(value);
+            sender.send_message(0, BlockTestMessage { value, block });
+        }
+
+        let mut expect_value = 0;
+        let mut iter = 0;
+        let max_iter = 10_000;
+        loop {
+            pump_connection_update(
+                &config,
+                &mut time,
+                &mut sender,
+                &mut receiver,
+                &mut sender_sequence,
+                &mut receiver_sequence,
+                delta_time,
+                0.5,
+            );
+
+            loop {
+                let Some((id, message)) = receiver.receive_message(0) else { break };
+                assert_eq!(id as u64, expect_value);
+                assert_eq!(message.value, expect_value, "iter: {}", iter);
+                assert_eq!(
+                    message.block,
+                    expected_block(expect_value),
+                    "iter: {}",
+                    iter
+                );
+                expect_value += 1;
+            }
+
+            if expect_value == messages_sent {
+                break;
+            }
+
+            if iter > max_iter {
+                panic!("exceeded maximum iterations allowed: {}", iter);
+            }
+
+            iter += 1;
+        }
+
+        assert_eq!(
+            receiver.channel_counters(0).received,
+            messages_sent as usize
+        );
+    }
+
+    #[test]
+    fn test_send_receive_local_messages() {
+        let time = 100.0;
+
+        let config = ClientServerConfig::new(2);
+        let mut config = config.connection;
+        config.channels[0].kind = ChannelType::ReliableOrdered;
+        config.channels[1].kind = ChannelType::UnreliableUnordered;
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::new(config.clone(), time);
+
+        // more messages than fit in a packet, and a block larger than a packet
+        let messages_sent = 512;
+        for value in 0..messages_sent {
+            let block = if value == 10 {
+                Some(vec![7u8; 4 * config.max_packet_size])
+            } else {
+                None
+            };
+            sender.send_message(0, BlockTestMessage { value, block });
+            sender.send_message(1, BlockTestMessage { value, block: None });
+        }
+
+        sender.send_local_messages(&mut receiver);
+
+        assert!(!sender.has_messages_to_send(0));
+        let mut packet = vec![0u8; config.max_packet_size];
+        assert_eq!(sender.generate_packet(0, &mut packet[..]), 0);
+
+        for channel in 0..2 {
+            for value in 0..messages_sent {
+                let (id, message) = receiver.receive_message(channel).unwrap();
+                assert_eq!(message.value, value);
+                if channel == 0 {
+                    assert_eq!(id as u64, value);
+                    assert_eq!(message.block.is_some(), value == 10);
+                } else {
+                    // unreliable messages take the id of the (local) packet
+                    assert_eq!(id, 0);
+                }
+            }
+            assert!(receiver.receive_message(channel).is_none());
+            assert_eq!(
+                sender.channel_counters(channel).sent,
+                messages_sent as usize
+            );
+            assert_eq!(
+                receiver.channel_counters(channel).received,
+                messages_sent as usize
+            );
+        }
+
+        // reliable messages keep their ids across calls
+        sender.send_message(
+            0,
+            BlockTestMessage {
+                value: 0,
+                block: None,
+            },
+        );
+        sender.send_local_messages(&mut receiver);
+        let (id, _) = receiver.receive_message(0).unwrap();
+        assert_eq!(id as u64, messages_sent);
+        assert_eq!(receiver.error_level(), ConnectionErrorLevel::None);
+    }
+
+    #[test]
+    fn test_poll_acked_messages() {
+        let mut time = 100.0;
+
+        let mut config = ClientServerConfig::new(2).connection;
+        config.channels[0].kind = ChannelType::ReliableOrdered;
+        config.channels[1].kind = ChannelType::UnreliableUnordered;
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::new(config.clone(), time);
+        let mut sender_sequence = 0;
+        let mut receiver_sequence = 0;
+
+        for value in 0..10 {
+            for channel_index in 0..2 {
+                let message_id = sender.send_message(channel_index, TestMessage { value });
+                assert_eq!(message_id, Some(value as u16));
+            }
+        }
+
+        for _ in 0..4 {
+            pump_connection_update(
+                &config,
+                &mut time,
+                &mut sender,
+                &mut receiver,
+                &mut sender_sequence,
+                &mut receiver_sequence,
+                0.1,
+                0.0,
+            );
+            for channel_index in 0..2 {
+                while receiver.receive_message(channel_index).is_some() {}
+            }
+        }
+
+        for channel_index in 0..2 {
+            let mut acked: Vec<u16> = sender.poll_acked(channel_index).collect();
+            acked.sort();
+            assert_eq!(acked, (0..10).collect::<Vec<u16>>());
+            assert_eq!(sender.poll_acked(channel_index).count(), 0);
+            assert_eq!(sender.poll_lost(channel_index).count(), 0);
+        }
+
+        // local messages are acked as they're delivered
+        sender.send_message(0, TestMessage { value: 10 });
+        sender.send_message(1, TestMessage { value: 10 });
+        sender.send_local_messages(&mut receiver);
+        for channel_index in 0..2 {
+            assert_eq!(sender.poll_acked(channel_index).collect::<Vec<_>>(), [10]);
+        }
+    }
+
+    #[test]
+    fn test_send_shared_local_messages() {
+        let time = 100.0;
+
+        let config = ClientServerConfig::new(2);
+        let mut config = config.connection;
+        config.channels[0].kind = ChannelType::ReliableOrdered;
+        config.channels[1].kind = ChannelType::UnreliableUnordered;
+
+        let mut senders = [
+            Connection::new(config.clone(), time),
+            Connection::new(config.clone(), time),
+        ];
+        let mut receivers = [
+            Connection::new(config.clone(), time),
+            Connection::new(config.clone(), time),
+        ];
+
+        let message = Arc::new(BlockTestMessage {
+            value: 3,
+            block: Some(vec![7u8; 64]),
+        });
+        let serialized = SerializedMessage::new(message.as_ref()).unwrap();
+        for sender in &mut senders {
+            sender.send_shared_message(0, &message, &serialized);
+        }
+        let message = Arc::new(BlockTestMessage {
+            value: 4,
+            block: None,
+        });
+        let serialized = SerializedMessage::new(message.as_ref()).unwrap();
+        for sender in &mut senders {
+            sender.send_shared_message(1, &message, &serialized);
+        }
+        drop(message);
+
+        // the first receiver gets a copy read back from the serialized message, and the
+        // second receiver gets the original
+        for (sender, receiver) in senders.iter_mut().zip(&mut receivers) {
+            sender.send_local_messages(receiver);
+
+            let (_, message) = receiver.receive_message(0).unwrap();
+            assert_eq!(
+                message,
+                BlockTestMessage {
+                    value: 3,
+                    block: Some(vec![7u8; 64]),
+                }
+            );
+            let (_, message) = receiver.receive_message(1).unwrap();
+            assert_eq!(
+                message,
+                BlockTestMessage {
+                    value: 4,
+                    block: None,
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_send_block_message_invalid_size() {
+        let mut config = ClientServerConfig::new(1).connection;
+        config.channels[0].kind = ChannelType::ReliableOrdered;
+        let max_block_size = config.channels[0].max_block_size;
+
+        for block_size in [0, max_block_size + 1] {
+            let mut sender = Connection::new(config.clone(), 100.0);
+            let message = BlockTestMessage {
+                value: 0,
+                block: Some(vec![0u8; block_size]),
+            };
+
+            assert_eq!(
+                sender.try_send_message(0, message.clone()),
+                Err(SendError::InvalidBlockSize(message.clone()))
+            );
+            sender.advance_time(100.1);
+            assert_eq!(sender.error_level(), ConnectionErrorLevel::None);
+
+            // errors the channel instead of panicking
+            assert_eq!(sender.send_message(0, message), None);
+            sender.advance_time(100.2);
+            assert_eq!(sender.error_level(), ConnectionErrorLevel::Channel);
+        }
+    }
+
+    #[test]
+    fn test_send_block_message_too_large() {
+        let mut time = 100.0;
+
+        let mut config = ClientServerConfig::new(1).connection;
+        config.channels[0].kind = ChannelType::ReliableOrdered;
+        config.max_packet_size = 256;
+        // a full fragment just fits, without its message
+        config.channels[0].block_fragment_size = 256 - 18;
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::new(config.clone(), time);
+        let too_large = BlockTestMessage {
+            value: 0,
+            block: Some(vec![0u8; 256 - 18]),
+        };
+
+        assert_eq!(
+            sender.try_send_message(0, too_large.clone()),
+            Err(SendError::MessageTooLarge(too_large.clone()))
+        );
+        assert_eq!(sender.error_level(), ConnectionErrorLevel::None);
+
+        // a smaller block leaves room for the message
+        let message = BlockTestMessage {
+            value: 1,
+            block: Some(vec![1u8; 200]),
+        };
+        assert!(sender.try_send_message(0, message.clone()).is_ok());
+
+        let mut sender_sequence = 0;
+        let mut receiver_sequence = 0;
+        for _ in 0..10 {
+            pump_connection_update(
+                &config,
+                &mut time,
+                &mut sender,
+                &mut receiver,
+                &mut sender_sequence,
+                &mut receiver_sequence,
+                0.1,
+                0.0,
+            );
+        }
+        assert_eq!(receiver.receive_message(0), Some((0, message)));
+
+        // errors the channel instead of stalling it
+        assert_eq!(sender.send_message(0, too_large), None);
+        sender.advance_time(time + 0.1);
+        assert_eq!(sender.error_level(), ConnectionErrorLevel::Channel);
+    }
+
+    #[test]
+    fn test_send_block_message_blocks_disabled() {
+        let config = ClientServerConfig::new(1);
+        let mut config = config.connection;
+        config.channels[0].disable_blocks = true;
+
+        let mut sender = Connection::new(config, 100.0);
+
+        let message = BlockTestMessage {
+            value: 0,
+            block: Some(vec![0u8; 32]),
+        };
+
+        // try_send_message hands the message back, and the connection carries on
+        assert_eq!(
+            sender.try_send_message(0, message.clone()),
+            Err(SendError::BlocksDisabled(message.clone()))
+        );
+        sender.advance_time(100.1);
+        assert_eq!(sender.error_level(), ConnectionErrorLevel::None);
+
+        sender.send_message(0, message.clone());
+        // the channel failed, and then the connection
+        assert_eq!(
+            sender.try_send_message(0, message.clone()),
+            Err(SendError::ConnectionFailed(message.clone()))
+        );
+        sender.advance_time(100.2);
+
+        assert_eq!(sender.error_level(), ConnectionErrorLevel::Channel);
+        assert_eq!(
+            sender.try_send_message(0, message.clone()),
+            Err(SendError::ConnectionFailed(message))
+        );
+    }
+
+    #[test]
+    fn test_try_send_message_queue_full() {
+        let mut config = ClientServerConfig::new(2).connection;
+        config.channels[0].kind = ChannelType::ReliableOrdered;
+        config.channels[1].kind = ChannelType::UnreliableUnordered;
+        for channel in &mut config.channels {
+            channel.message_send_queue_size = 4;
+        }
+
+        let mut sender = Connection::new(config, 100.0);
+
+        for channel_index in 0..2 {
+            for value in 0..4 {
+                assert_eq!(
+                    sender.try_send_message(channel_index, TestMessage { value }),
+                    Ok(value as u16)
+                );
+            }
+            let message = TestMessage { value: 4 };
+            assert_eq!(
+                sender.try_send_message(channel_index, message),
+                Err(SendError::QueueFull(message))
+            );
+        }
+        assert_eq!(
+            sender.try_send_message(2, TestMessage { value: 0 }),
+            Err(SendError::InvalidChannel(TestMessage { value: 0 }))
+        );
+        assert_eq!(sender.send_message(2, TestMessage { value: 0 }), None);
+
+        // unlike `send_message`, a full queue is not an error
+        sender.advance_time(100.1);
+        assert_eq!(sender.error_level(), ConnectionErrorLevel::None);
+    }
+
+    #[test]
+    fn test_message_failed_to_serialize() {
+        /// Only odd values can be serialized.
+        #[derive(Debug, PartialEq, Eq)]
+        struct OddMessage(u64);
+
+        impl NetworkMessage for OddMessage {
+            type Error = std::io::Error;
+
+            fn serialize<W: std::io::Write>(&self, mut writer: W) -> Result<(), Self::Error> {
+                if self.0 % 2 == 0 {
+                    return Err(std::io::ErrorKind::InvalidInput.into());
+                }
+                writer.write_u64::<LittleEndian>(self.0)
+            }
+
+            fn deserialize<R: std::io::Read>(mut reader: R) -> Result<Self, Self::Error> {
+                Ok(OddMessage(reader.read_u64::<LittleEndian>()?))
+            }
+        }
+
+        let config = ClientServerConfig::new(1).connection;
+        let mut sender = Connection::new(config, 100.0);
+
+        assert_eq!(sender.try_send_message(0, OddMessage(1)), Ok(0));
+        assert_eq!(
+            sender.try_send_message(0, OddMessage(2)),
+            Err(SendError::FailedToSerialize(OddMessage(2)))
+        );
+        sender.advance_time(100.1);
+        assert_eq!(sender.error_level(), ConnectionErrorLevel::None);
+
+        sender.send_message(0, OddMessage(2));
+        sender.advance_time(100.2);
+        assert_eq!(sender.error_level(), ConnectionErrorLevel::Channel);
+    }
+
+    #[test]
+    fn test_invalid_message_priorities_are_clamped() {
+        let mut time = 100.0;
+
+        let mut config = ClientServerConfig::new(1).connection;
+        config.channels[0].kind = ChannelType::UnreliableUnordered;
+        config.channels[0].max_messages_per_packet = 1;
+        config.channels[0].message_time_to_live = Some(10.0);
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::new(config.clone(), time);
+        let mut sender_sequence = 0;
+        let mut receiver_sequence = 0;
+
+        let priorities = [f32::NAN, 1.0, -1.0, f32::INFINITY];
+        for (value, priority) in priorities.into_iter().enumerate() {
+            let message = TestMessage {
+                value: value as u64,
+            };
+            sender.send_message_with_priority(0, message, priority);
+        }
+
+        let mut received = Vec::new();
+        for _ in 0..priorities.len() {
+            pump_connection_update(
+                &config,
+                &mut time,
+                &mut sender,
+                &mut receiver,
+                &mut sender_sequence,
+                &mut receiver_sequence,
+                0.1,
+                0.0,
+            );
+            while let Some((_, message)) = receiver.receive_message(0) {
+                received.push(message.value);
+            }
+        }
+
+        // NaN and negative priorities are 0, so those messages go last, oldest first
+        assert_eq!(received, [3, 1, 0, 2]);
+    }
+
+    #[test]
+    fn test_channels_share_packets_by_priority() {
+        let time = 100.0;
+
+        let mut config = ClientServerConfig::new(3).connection;
+        config.channels[1].priority = 3;
+        config.channels[2].kind = ChannelType::UnreliableUnordered;
+        for channel in &mut config.channels {
+            channel.max_messages_per_packet = 1024;
+        }
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::<TestMessage>::new(config.clone(), time);
+
+        // a single message on the first channel, and more than fits in a packet on the others
+        sender.send_message(0, TestMessage { value: 0 });
+        for value in 0..1000 {
+            sender.send_message(1, TestMessage { value });
+            sender.send_message(2, TestMessage { value });
+        }
+
+        let mut packet = vec![0u8; config.max_packet_size];
+        let bytes_written = sender.generate_packet(0, &mut packet[..]);
+        assert!(receiver.process_packet(0, &packet[..bytes_written]));
+
+        let received: Vec<_> = (0..3)
+            .map(|channel| std::iter::from_fn(|| receiver.receive_message(channel)).count())
+            .collect();
+        // the space the first channel doesn't use is split 3:1 between the others
+        assert_eq!(received[0], 1);
+        assert!(
+            (700..800).contains(&received[1]) && (200..300).contains(&received[2]),
+            "{:?}",
+            received
+        );
+    }
+
+    #[test]
+    fn test_extreme_channel_priorities() {
+        let mut time = 100.0;
+
+        let mut config = ClientServerConfig::new(2).connection;
+        config.max_packet_size = 256;
+        config.channels[0].priority = u32::MAX;
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::<TestMessage>::new(config.clone(), time);
+
+        let mut sender_sequence = 0;
+        let mut receiver_sequence = 0;
+
+        // the second channel's share of each packet is less than a message header
+        for value in 0..200 {
+            sender.send_message(0, TestMessage { value });
+        }
+        for value in 0..10 {
+            sender.send_message(1, TestMessage { value });
+        }
+
+        let mut received = [0, 0];
+        for _ in 0..200 {
+            pump_connection_update(
+                &config,
+                &mut time,
+                &mut sender,
+                &mut receiver,
+                &mut sender_sequence,
+                &mut receiver_sequence,
+                0.1,
+                0.0,
+            );
+            for (channel, count) in received.iter_mut().enumerate() {
+                *count += std::iter::from_fn(|| receiver.receive_message(channel)).count();
+            }
+        }
+        assert_eq!(received, [200, 10]);
+    }
+
+    #[test]
+    fn test_max_bytes_per_second() {
+        let mut time = 100.0;
+
+        let mut config = ClientServerConfig::new(1).connection;
+        config.max_bytes_per_second = Some(1000);
+
+        let mut sender = Connection::new(config.clone(), time);
+        for value in 0..64 {
+            sender.send_message(0, TestMessage { value });
+        }
+
+        let mut packet = vec![0u8; config.max_packet_size];
+        // nothing can be sent until the allowance builds up
+        assert_eq!(sender.generate_packet(0, &mut packet[..]), 0);
+
+        time += 0.1;
+        sender.advance_time(time);
+        let bytes_written = sender.generate_packet(1, &mut packet[..]);
+        assert!(bytes_written > 500);
+
+        // over the limit until enough time passes to pay for the packet
+        time += 0.1;
+        sender.advance_time(time);
+        assert_eq!(sender.generate_packet(2, &mut packet[..]), 0);
+
+        time += bytes_written as f64 / 1000.0;
+        sender.advance_time(time);
+        assert!(sender.generate_packet(3, &mut packet[..]) > 0);
+    }
+
+    #[test]
+    fn test_reliable_desync_sets_connection_error() {
+        let mut time = 100.0;
+        let delta_time = 0.016;
+
+        let config = ClientServerConfig::new(1);
+        let mut config = config.connection;
+        config.channels[0].kind = ChannelType::ReliableOrdered;
+
+        // the sender only limits itself by its own receive queue size, so a
+        // receiver configured with a smaller queue can be overrun
+        let mut receiver_config = config.clone();
+        receiver_config.channels[0].message_receive_queue_size = 16;
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::<TestMessage>::new(receiver_config, time);
+
+        for i in 0..64 {
+            sender.send_message(0, TestMessage { value: i });
+        }
+
+        let mut packet = vec![0u8; config.max_packet_size];
+        let bytes_written = sender.generate_packet(0, &mut packet[..]);
+        assert!(bytes_written > 0);
+
+        receiver.process_packet(0, &packet[..bytes_written]);
+
+        time += delta_time;
+        receiver.advance_time(time);
+
+        assert_eq!(receiver.error_level(), ConnectionErrorLevel::Channel);
+    }
+
+    #[test]
+    fn test_send_more_than_256_messages_per_packet() {
+        let time = 100.0;
+        let message_count = 600;
+
+        let config = ClientServerConfig::new(1);
+        let mut config = config.connection;
+        config.channels[0].max_messages_per_packet = 1024;
+
+        let mut sender = Connection::new(config.clone(), time);
+        let mut receiver = Connection::<TestMessage>::new(config.clone(), time);
+
+        for i in 0..message_count {
+            sender.send_message(0, TestMessage { value: i });
+        }
+
+        let mut packet = vec![0u8; config.max_packet_size];
+        let bytes_written = sender.generate_packet(0, &mut packet[..]);
+        assert!(bytes_written > 0);
+
+        let processed = receiver.process_packet(0, &packet[..bytes_written]);
+        assert!(processed);
+
+        for i in 0..message_count {
+            let (_, message) = receiver.receive_message(0).unwrap();
+            assert_eq!(message.value, i);
+        }
+        assert!(receiver.receive_message(0).is_none());
+    }
+
+    fn generate_test_packet(config: &ConnectionConfig, time: f64) -> Vec<u8> {
+        let mut sender = Connection::new(config.clone(), time);
+        for i in 0..4 {
+            sender.send_message(0, TestMessage { value: i });
+        }
+
+        let mut packet = vec![0u8; config.max_packet_size];
+        let bytes_written = sender.generate_packet(0, &mut packet[..]);
+        assert!(bytes_written > 0);
+        packet.truncate(bytes_written);
+
+        packet
+    }
+
+    #[test]
+    fn test_truncated_packet_sets_read_packet_failed() {
+        let time = 100.0;
+
+        let config = ClientServerConfig::new(1);
+        let config = config.connection;
+
+        let packet = generate_test_packet(&config, time);
+
+        for len in 0..packet.len() {
+            let mut receiver = Connection::<TestMessage>::new(config.clone(), time);
+
+            let processed = receiver.process_packet(0, &packet[..len]);
+            assert!(!processed);
+
+            receiver.advance_time(time + 0.016);
+            assert_eq!(
+                receiver.error_level(),
+                ConnectionErrorLevel::ReadPacketFailed
+            );
+        }
+
+        // cutting into the last message fails in `NetworkMessage::deserialize`
+        // (5 bytes lands inside the message with or without `serialize_check`)
+        let mut receiver = Connection::<TestMessage>::new(config.clone(), time);
+        receiver.process_packet(0, &packet[..packet.len() - 5]);
+        assert_eq!(
+            receiver.channels[0].error_level(),
+            ChannelErrorLevel::FailedToSerialize
+        );
+
+        let mut receiver = Connection::<TestMessage>::new(config.clone(), time);
+        let processed = receiver.process_packet(0, &packet);
+        assert!(processed);
+        assert_eq!(receiver.error_level(), ConnectionErrorLevel::None);
+    }
+
+    fn write_test_packet<F: FnOnce(&mut BitWriter)>(write: F) -> Vec<u8> {
+        let mut packet = vec![0u8; 64];
+        let mut writer = BitWriter::new(&mut packet[..]);
+        write(&mut writer);
+        let bytes_written = writer.bytes_written();
+        packet.truncate(bytes_written);
+
+        packet
+    }
+
+    #[test]
+    fn test_invalid_packet_sets_read_packet_failed() {
+        let time = 100.0;
+
+        let config = ClientServerConfig::new(3);
+        let mut config = config.connection;
+        config.channels[0].max_messages_per_packet = 6;
+
+        // channel entries missing
+        let missing_channel_data = write_test_packet(|writer| {
+            writer.write_int(2, 0, 3).unwrap();
+        });
+
+        // channel index out of range
+        let bad_channel_index = write_test_packet(|writer| {
+            writer.write_int(1, 0, 3).unwrap();
+            writer.write_bits(3, 2).unwrap();
+        });
+
+        // more messages than allowed per packet
+        let bad_message_count = write_test_packet(|writer| {
+            writer.write_int(1, 0, 3).unwrap();
+            writer.write_int(0, 0, 2).unwrap();
+            writer.write_bool(false).unwrap();
+            writer.write_bool(true).unwrap();
+            writer.write_bits(6, 3).unwrap();
+        });
+
+        // a block fragment for a channel without blocks, whose block limits are invalid
+        config.channels[1].disable_blocks = true;
+        config.channels[1].max_block_size = 0;
+        config.channels[1].block_fragment_size = 0;
+        let block_on_disabled_channel = write_test_packet(|writer| {
+            writer.write_int(1, 0, 3).unwrap();
+            writer.write_int(1, 0, 2).unwrap();
+            writer.write_bool(true).unwrap();
+            writer.write_bits(0, 32).unwrap();
+        });
+
+        for packet in [
+            missing_channel_data,
+            bad_channel_index,
+            bad_message_count,
+            block_on_disabled_channel,
+        ] {
+            let mut receiver = Connection::<TestMessage>::new(config.clone(), time);
+
+            let processed = receiver.process_packet(0, &packet);
+            assert!(!processed);
+            assert_eq!(
+                receiver.error_level(),
+                ConnectionErrorLevel::ReadPacketFailed
+            );
+        }
+    }
+
+    fn pump_connection_update<M: NetworkMessage>(
         config: &ConnectionConfig,
         time: &mut f64,
-        sender: &mut Connection<TestMessage>,
-        receiver: &mut Connection<TestMessage>,
+        sender: &mut Connection<M>,
+        receiver: &mut Connection<M>,
         sender_sequence: &mut u16,
         receiver_sequence: &mut u16,
         delta_time: f64,
@@ -595,20 +1555,16 @@ mod test {
         let mut bytes_written = sender.generate_packet(*sender_sequence, &mut packet[..]);
         if bytes_written > 0 {
             if rand::random::<f32>() > packet_loss {
-                unsafe {
-                    receiver.process_packet(*sender_sequence, packet.as_ptr(), bytes_written);
-                    sender.process_acks(sender_sequence, 1);
-                }
+                receiver.process_packet(*sender_sequence, &packet[..bytes_written]);
+                sender.process_acks(&[*sender_sequence]);
             }
         }
 
         bytes_written = receiver.generate_packet(*receiver_sequence, &mut packet[..]);
         if bytes_written > 0 {
             if rand::random::<f32>() > packet_loss {
-                unsafe {
-                    sender.process_packet(*receiver_sequence, packet.as_ptr(), bytes_written);
-                    receiver.process_acks(receiver_sequence, 1);
-                }
+                sender.process_packet(*receiver_sequence, &packet[..bytes_written]);
+                receiver.process_acks(&[*receiver_sequence]);
             }
         }
 
@@ -1,17 +1,23 @@
 use std::error::Error;
 
 pub mod bindings;
+pub mod bitpacker;
 pub mod channel;
 pub mod client;
 pub mod config;
+pub mod connect_token;
 pub mod connection;
 pub mod message;
 pub mod network_info;
 pub mod network_simulator;
+pub mod reliable;
+mod sequence_buffer;
 pub mod server;
+pub mod transport;
 
 pub const PRIVATE_KEY_BYTES: usize = bindings::NETCODE_KEY_BYTES as usize;
 pub const CONNECT_TOKEN_BYTES: usize = bindings::NETCODE_CONNECT_TOKEN_BYTES as usize;
+pub const USER_DATA_BYTES: usize = bindings::NETCODE_USER_DATA_BYTES as usize;
 
 #[derive(Debug, Copy, Clone)]
 #[repr(i32)]
@@ -30,9 +36,6 @@ pub fn initialize() -> Result<(), Box<dyn Error>> {
         if bindings::netcode_init() != bindings::NETCODE_OK as _ {
             return Err("failed to initialize netcode".into());
         }
-        if bindings::reliable_init() != bindings::RELIABLE_OK as _ {
-            return Err("failed to initialize reliable".into());
-        }
         // Ideally: (netcode does this, low priority) bindings::sodium_init() (OK if != -1)
     }
     Ok(())
@@ -44,13 +47,11 @@ pub fn initialize() -> Result<(), Box<dyn Error>> {
 pub fn set_bindings_log_level(level: BindingsLogLevel) {
     unsafe {
         bindings::netcode_log_level(level as _);
-        bindings::reliable_log_level(level as _);
     }
 }
 
 pub fn shutdown() {
     unsafe {
-        bindings::reliable_term();
         bindings::netcode_term();
     }
 }
@@ -3,12 +3,14 @@ use std::{
     io::{Read, Write},
 };
 
+use crate::bitpacker::{BitReader, BitWriter};
+
 /// A message that can be sent and received from the network.
 ///
-/// NOTE: Clone should be a temporary requirement. This is a stop-gap solution
-/// that simplifies porting reliable channels; I have a design in mind that
-/// should eliminate the Clone requirement but want to get it working first.
-pub trait NetworkMessage: Clone + 'static
+/// Messages don't need to be `Clone`: channels write packets straight from
+/// their send queues, so a reliable message is kept until it is acked without
+/// ever being copied.
+pub trait NetworkMessage: 'static
 where
     Self: Sized,
 {
@@ -17,4 +19,37 @@ where
     fn serialize<W: Write>(&self, writer: W) -> Result<(), Self::Error>;
 
     fn deserialize<R: Read>(reader: R) -> Result<Self, Self::Error>;
+
+    /// Serialize this message at the bit level.
+    ///
+    /// This is what actually gets called when writing a message to a packet.
+    /// The default forwards to `serialize`, which writes whole bytes; override
+    /// this and `deserialize_bits` to pack fields more tightly, e.g. with
+    /// `BitWriter::write_int` for values with a known range.
+    fn serialize_bits(&self, writer: &mut BitWriter) -> Result<(), Self::Error> {
+        self.serialize(writer)
+    }
+
+    /// Deserialize a message written by `serialize_bits`.
+    fn deserialize_bits(reader: &mut BitReader) -> Result<Self, Self::Error> {
+        Self::deserialize(reader)
+    }
+
+    /// The block attached to this message, if any.
+    ///
+    /// Blocks are for large payloads (e.g. level data or save games) that
+    /// don't fit in a single packet. Instead of being written by `serialize`,
+    /// the block is split into fragments and reassembled on the receiver,
+    /// where it is handed back to the message with `attach_block`.
+    ///
+    /// Block messages can only be sent on `ReliableOrdered` channels. See
+    /// `ChannelConfig::max_block_size` and `ChannelConfig::block_fragment_size`.
+    fn block(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Attach a block received from the network to this (deserialized) message.
+    ///
+    /// Only called for messages which had a block attached when sent.
+    fn attach_block(&mut self, _block: Vec<u8>) {}
 }
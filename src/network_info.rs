@@ -1,3 +1,5 @@
+use crate::reliable::ReliableEndpoint;
+
 #[derive(Debug, Clone)]
 pub struct NetworkInfo {
     /// Round trip time estimate (milliseconds).
@@ -17,3 +19,20 @@ pub struct NetworkInfo {
     /// Number of packets acked.
     pub num_packets_acked: u64,
 }
+
+impl NetworkInfo {
+    pub(crate) fn from_endpoint(endpoint: &ReliableEndpoint) -> NetworkInfo {
+        let (sent_bandwidth, received_bandwidth, acked_bandwidth) = endpoint.bandwidth();
+        let counters = endpoint.counters();
+        NetworkInfo {
+            rtt: endpoint.rtt(),
+            packet_loss: endpoint.packet_loss(),
+            sent_bandwidth,
+            received_bandwidth,
+            acked_bandwidth,
+            num_packets_sent: counters.num_packets_sent,
+            num_packets_received: counters.num_packets_received,
+            num_packets_acked: counters.num_packets_acked,
+        }
+    }
+}
@@ -0,0 +1,972 @@
+//! Packet acks, fragmentation and network statistics.
+//!
+//! A port of the [reliable](https://github.com/networkprotocol/reliable) C
+//! library. Instead of calling back into the client or server, a
+//! `ReliableEndpoint` returns the packets to transmit and the acked packet
+//! sequences by value, which keeps the client and server free of aliasing
+//! raw pointers.
+//!
+//! The wire format is the same as reliable's.
+
+use std::mem;
+
+use crate::sequence_buffer::SequenceBuffer;
+
+/// Maximum size of the packet header written in front of every packet (bytes).
+pub const MAX_PACKET_HEADER_BYTES: usize = 9;
+/// Size of the header written in front of every fragment (bytes).
+pub const FRAGMENT_HEADER_BYTES: usize = 5;
+
+/// The fragment id and count are sent as a byte each.
+const MAX_FRAGMENTS_LIMIT: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct ReliableConfig {
+    /// Name of the endpoint, used for logging.
+    pub name: String,
+    /// Index of the endpoint, used for logging (e.g. the client index on the server).
+    pub index: usize,
+    /// Maximum size of a packet passed to `send_packet` (bytes).
+    pub max_packet_size: usize,
+    /// Packets above this size (bytes) are split apart into fragments and reassembled on the other side.
+    pub fragment_above: usize,
+    /// Maximum number of fragments a packet can be split up into. At most 256.
+    pub max_fragments: usize,
+    /// Size of each packet fragment (bytes).
+    pub fragment_size: usize,
+    /// Maximum number of acks buffered between calls to `take_acks`.
+    pub ack_buffer_size: usize,
+    /// Number of entries in the sent packet sequence buffer.
+    pub sent_packets_buffer_size: usize,
+    /// Number of entries in the received packet sequence buffer.
+    pub received_packets_buffer_size: usize,
+    /// Number of packet entries in the fragmentation reassembly buffer.
+    pub fragment_reassembly_buffer_size: usize,
+    /// Round-Trip Time (RTT) smoothing factor over time.
+    pub rtt_smoothing_factor: f32,
+    /// Packet loss smoothing factor over time.
+    pub packet_loss_smoothing_factor: f32,
+    /// Bandwidth smoothing factor over time.
+    pub bandwidth_smoothing_factor: f32,
+    /// Size of the underlying transport's packet header (bytes), counted towards bandwidth.
+    pub packet_header_size: usize,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        ReliableConfig {
+            name: "endpoint".to_string(),
+            index: 0,
+            max_packet_size: 16 * 1024,
+            fragment_above: 1024,
+            max_fragments: 16,
+            fragment_size: 1024,
+            ack_buffer_size: 256,
+            sent_packets_buffer_size: 256,
+            received_packets_buffer_size: 256,
+            fragment_reassembly_buffer_size: 64,
+            rtt_smoothing_factor: 0.0025,
+            packet_loss_smoothing_factor: 0.1,
+            bandwidth_smoothing_factor: 0.1,
+            packet_header_size: 28, // note: UDP over IPv4 = 20 + 8 bytes, UDP over IPv6 = 40 + 8 bytes
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReliableCounters {
+    pub num_packets_sent: u64,
+    pub num_packets_received: u64,
+    pub num_packets_acked: u64,
+    pub num_packets_stale: u64,
+    pub num_packets_invalid: u64,
+    pub num_packets_too_large_to_send: u64,
+    pub num_packets_too_large_to_receive: u64,
+    pub num_fragments_sent: u64,
+    pub num_fragments_received: u64,
+    pub num_fragments_invalid: u64,
+}
+
+struct SentPacketData {
+    time: f64,
+    acked: bool,
+    packet_bytes: usize,
+}
+
+struct ReceivedPacketData {
+    time: f64,
+    packet_bytes: usize,
+}
+
+struct FragmentReassemblyData {
+    num_fragments_received: usize,
+    num_fragments_total: usize,
+    /// The packet header is written just before `MAX_PACKET_HEADER_BYTES`, followed by the fragments.
+    packet_data: Vec<u8>,
+    packet_header_bytes: usize,
+    packet_bytes: usize,
+    fragment_received: Vec<bool>,
+}
+
+/// Acks packets, splits large packets into fragments and estimates RTT, packet loss and bandwidth.
+pub struct ReliableEndpoint {
+    config: ReliableConfig,
+    time: f64,
+    rtt: f32,
+    packet_loss: f32,
+    sent_bandwidth_kbps: f32,
+    received_bandwidth_kbps: f32,
+    acked_bandwidth_kbps: f32,
+    sequence: u16,
+    acks: Vec<u16>,
+    sent_packets: SequenceBuffer<SentPacketData>,
+    received_packets: SequenceBuffer<ReceivedPacketData>,
+    fragment_reassembly: SequenceBuffer<FragmentReassemblyData>,
+    counters: ReliableCounters,
+}
+
+impl ReliableEndpoint {
+    pub fn new(config: ReliableConfig, time: f64) -> ReliableEndpoint {
+        assert!(config.max_packet_size > 0);
+        assert!(config.fragment_above > 0);
+        assert!(config.fragment_size > 0);
+        assert!(config.max_fragments > 0 && config.max_fragments <= MAX_FRAGMENTS_LIMIT);
+        assert!(config.max_fragments * config.fragment_size >= config.max_packet_size);
+        assert!(config.ack_buffer_size > 0);
+        assert!(config.sent_packets_buffer_size > 0);
+        assert!(config.received_packets_buffer_size > 0);
+        assert!(config.fragment_reassembly_buffer_size > 0);
+
+        ReliableEndpoint {
+            time,
+            rtt: 0.0,
+            packet_loss: 0.0,
+            sent_bandwidth_kbps: 0.0,
+            received_bandwidth_kbps: 0.0,
+            acked_bandwidth_kbps: 0.0,
+            sequence: 0,
+            acks: Vec::with_capacity(config.ack_buffer_size),
+            sent_packets: SequenceBuffer::new(config.sent_packets_buffer_size),
+            received_packets: SequenceBuffer::new(config.received_packets_buffer_size),
+            fragment_reassembly: SequenceBuffer::new(config.fragment_reassembly_buffer_size),
+            counters: ReliableCounters::default(),
+            config,
+        }
+    }
+
+    /// The sequence number the next call to `send_packet` will use.
+    pub fn next_packet_sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Add a packet header to `packet_data`, fragmenting it if needed.
+    ///
+    /// Returns the packets to transmit, which is empty if `packet_data` is
+    /// larger than `max_packet_size`.
+    pub fn send_packet(&mut self, packet_data: &[u8]) -> Vec<Vec<u8>> {
+        assert!(!packet_data.is_empty());
+
+        if packet_data.len() > self.config.max_packet_size {
+            log::error!(
+                "[{}] packet too large to send. packet is {} bytes, maximum is {}",
+                self.config.name,
+                packet_data.len(),
+                self.config.max_packet_size
+            );
+            self.counters.num_packets_too_large_to_send += 1;
+            return Vec::new();
+        }
+
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let (ack, ack_bits) = self.generate_ack_bits();
+
+        let time = self.time;
+        let packet_bytes = self.config.packet_header_size + packet_data.len();
+        self.sent_packets.insert_with(sequence, || SentPacketData {
+            time,
+            acked: false,
+            packet_bytes,
+        });
+
+        let mut packet_header = Vec::with_capacity(MAX_PACKET_HEADER_BYTES);
+        write_packet_header(&mut packet_header, sequence, ack, ack_bits);
+
+        let mut packets = Vec::new();
+        if packet_data.len() <= self.config.fragment_above {
+            log::debug!(
+                "[{}] sending packet {} without fragmentation",
+                self.config.name,
+                sequence
+            );
+
+            let mut packet = packet_header;
+            packet.extend_from_slice(packet_data);
+            packets.push(packet);
+        } else {
+            let fragments = packet_data.chunks(self.config.fragment_size);
+            let num_fragments = fragments.len();
+
+            log::debug!(
+                "[{}] sending packet {} as {} fragments",
+                self.config.name,
+                sequence,
+                num_fragments
+            );
+
+            debug_assert!(num_fragments >= 1);
+            debug_assert!(num_fragments <= self.config.max_fragments);
+
+            for (fragment_id, fragment) in fragments.enumerate() {
+                let mut packet = Vec::with_capacity(
+                    FRAGMENT_HEADER_BYTES + MAX_PACKET_HEADER_BYTES + fragment.len(),
+                );
+                packet.push(1);
+                packet.extend_from_slice(&sequence.to_le_bytes());
+                packet.push(fragment_id as u8);
+                packet.push((num_fragments - 1) as u8);
+                if fragment_id == 0 {
+                    packet.extend_from_slice(&packet_header);
+                }
+                packet.extend_from_slice(fragment);
+                packets.push(packet);
+
+                self.counters.num_fragments_sent += 1;
+            }
+        }
+
+        self.counters.num_packets_sent += 1;
+
+        packets
+    }
+
+    /// Read a packet sent by another endpoint's `send_packet`.
+    ///
+    /// Once a whole packet is available, `process_packet` is called with its
+    /// sequence and payload. Return true from `process_packet` to ack the packet.
+    pub fn receive_packet<F: FnMut(u16, &[u8]) -> bool>(
+        &mut self,
+        packet_data: &[u8],
+        mut process_packet: F,
+    ) {
+        if packet_data.len()
+            > self.config.max_packet_size + MAX_PACKET_HEADER_BYTES + FRAGMENT_HEADER_BYTES
+        {
+            log::error!(
+                "[{}] packet too large to receive. packet is at least {} bytes, maximum is {}",
+                self.config.name,
+                packet_data.len() - (MAX_PACKET_HEADER_BYTES + FRAGMENT_HEADER_BYTES),
+                self.config.max_packet_size
+            );
+            self.counters.num_packets_too_large_to_receive += 1;
+            return;
+        }
+
+        let Some(&prefix_byte) = packet_data.first() else { return };
+
+        if prefix_byte & 1 == 0 {
+            self.receive_regular_packet(packet_data, &mut process_packet);
+        } else {
+            self.receive_fragment(packet_data, &mut process_packet);
+        }
+    }
+
+    /// Take the sequences of the packets acked since the last call.
+    pub fn take_acks(&mut self) -> Vec<u16> {
+        mem::take(&mut self.acks)
+    }
+
+    /// Update the packet loss and bandwidth estimates.
+    pub fn update(&mut self, time: f64) {
+        self.time = time;
+
+        {
+            let sample_size = self.config.sent_packets_buffer_size / 2;
+            let base_sequence = self
+                .sent_packets
+                .sequence_pointer()
+                .wrapping_sub(self.config.sent_packets_buffer_size as u16);
+            let mut num_dropped = 0;
+            for i in 0..sample_size {
+                let sequence = base_sequence.wrapping_add(i as u16);
+                if let Some(sent_packet) = self.sent_packets.get(sequence) {
+                    if !sent_packet.acked {
+                        num_dropped += 1;
+                    }
+                }
+            }
+            let packet_loss = num_dropped as f32 / sample_size.max(1) as f32 * 100.0;
+            self.packet_loss = smooth(
+                self.packet_loss,
+                packet_loss,
+                self.config.packet_loss_smoothing_factor,
+            );
+        }
+
+        {
+            let samples = self.sent_packet_samples();
+            if let Some(bandwidth) = bandwidth_kbps(
+                samples.map(|sent_packet| (sent_packet.time, sent_packet.packet_bytes)),
+            ) {
+                self.sent_bandwidth_kbps = smooth(
+                    self.sent_bandwidth_kbps,
+                    bandwidth,
+                    self.config.bandwidth_smoothing_factor,
+                );
+            }
+        }
+
+        {
+            let sample_size = self.config.received_packets_buffer_size / 2;
+            let base_sequence = self
+                .received_packets
+                .sequence_pointer()
+                .wrapping_sub(self.config.received_packets_buffer_size as u16);
+            let received_packets = &self.received_packets;
+            let samples = (0..sample_size)
+                .filter_map(|i| received_packets.get(base_sequence.wrapping_add(i as u16)));
+            if let Some(bandwidth) = bandwidth_kbps(
+                samples.map(|received_packet| (received_packet.time, received_packet.packet_bytes)),
+            ) {
+                self.received_bandwidth_kbps = smooth(
+                    self.received_bandwidth_kbps,
+                    bandwidth,
+                    self.config.bandwidth_smoothing_factor,
+                );
+            }
+        }
+
+        {
+            let samples = self.sent_packet_samples();
+            if let Some(bandwidth) = bandwidth_kbps(
+                samples
+                    .filter(|sent_packet| sent_packet.acked)
+                    .map(|sent_packet| (sent_packet.time, sent_packet.packet_bytes)),
+            ) {
+                self.acked_bandwidth_kbps = smooth(
+                    self.acked_bandwidth_kbps,
+                    bandwidth,
+                    self.config.bandwidth_smoothing_factor,
+                );
+            }
+        }
+    }
+
+    /// Reset the endpoint to its initial state, forgetting all sent and received packets.
+    pub fn reset(&mut self) {
+        self.sequence = 0;
+        self.acks.clear();
+        self.sent_packets.reset();
+        self.received_packets.reset();
+        self.fragment_reassembly.reset();
+        self.rtt = 0.0;
+        self.packet_loss = 0.0;
+        self.sent_bandwidth_kbps = 0.0;
+        self.received_bandwidth_kbps = 0.0;
+        self.acked_bandwidth_kbps = 0.0;
+        self.counters = ReliableCounters::default();
+    }
+
+    /// Round trip time estimate (milliseconds).
+    pub fn rtt(&self) -> f32 {
+        self.rtt
+    }
+
+    /// Packet loss percent.
+    pub fn packet_loss(&self) -> f32 {
+        self.packet_loss
+    }
+
+    /// Sent, received and acked bandwidth (kbps).
+    pub fn bandwidth(&self) -> (f32, f32, f32) {
+        (
+            self.sent_bandwidth_kbps,
+            self.received_bandwidth_kbps,
+            self.acked_bandwidth_kbps,
+        )
+    }
+
+    pub fn counters(&self) -> &ReliableCounters {
+        &self.counters
+    }
+
+    fn receive_regular_packet<F: FnMut(u16, &[u8]) -> bool>(
+        &mut self,
+        packet_data: &[u8],
+        process_packet: &mut F,
+    ) {
+        self.counters.num_packets_received += 1;
+
+        let Some((header, packet_header_bytes)) = read_packet_header(packet_data) else {
+            log::error!(
+                "[{}] ignoring invalid packet. could not read packet header",
+                self.config.name
+            );
+            self.counters.num_packets_invalid += 1;
+            return;
+        };
+
+        let sequence = header.sequence;
+        if !self.received_packets.can_insert(sequence) {
+            log::debug!("[{}] ignoring stale packet {}", self.config.name, sequence);
+            self.counters.num_packets_stale += 1;
+            return;
+        }
+
+        log::debug!("[{}] processing packet {}", self.config.name, sequence);
+        if !process_packet(sequence, &packet_data[packet_header_bytes..]) {
+            log::error!("[{}] process packet failed", self.config.name);
+            return;
+        }
+
+        let time = self.time;
+        let packet_bytes = self.config.packet_header_size + packet_data.len();
+        self.received_packets
+            .insert_with(sequence, || ReceivedPacketData { time, packet_bytes });
+        self.fragment_reassembly.advance(sequence);
+
+        let mut ack_bits = header.ack_bits;
+        for i in 0..32u16 {
+            if ack_bits & 1 != 0 {
+                let ack_sequence = header.ack.wrapping_sub(i);
+                if let Some(sent_packet) = self.sent_packets.get_mut(ack_sequence) {
+                    if !sent_packet.acked && self.acks.len() < self.config.ack_buffer_size {
+                        log::debug!("[{}] acked packet {}", self.config.name, ack_sequence);
+                        sent_packet.acked = true;
+                        self.acks.push(ack_sequence);
+                        self.counters.num_packets_acked += 1;
+
+                        let rtt = (self.time - sent_packet.time) as f32 * 1000.0;
+                        if self.rtt == 0.0 && rtt > 0.0 {
+                            self.rtt = rtt;
+                        } else {
+                            self.rtt = smooth(self.rtt, rtt, self.config.rtt_smoothing_factor);
+                        }
+                    }
+                }
+            }
+            ack_bits >>= 1;
+        }
+    }
+
+    fn receive_fragment<F: FnMut(u16, &[u8]) -> bool>(
+        &mut self,
+        packet_data: &[u8],
+        process_packet: &mut F,
+    ) {
+        let Some(fragment) = read_fragment_header(
+            packet_data,
+            self.config.max_fragments,
+            self.config.fragment_size,
+        ) else {
+            log::error!(
+                "[{}] ignoring invalid fragment. could not read fragment header",
+                self.config.name
+            );
+            self.counters.num_fragments_invalid += 1;
+            return;
+        };
+
+        let sequence = fragment.sequence;
+        if self.fragment_reassembly.get(sequence).is_none() {
+            let packet_buffer_size =
+                MAX_PACKET_HEADER_BYTES + self.config.max_fragments * self.config.fragment_size;
+            let num_fragments_total = fragment.num_fragments;
+            let inserted =
+                self.fragment_reassembly
+                    .insert_with(sequence, || FragmentReassemblyData {
+                        num_fragments_received: 0,
+                        num_fragments_total,
+                        packet_data: vec![0; packet_buffer_size],
+                        packet_header_bytes: 0,
+                        packet_bytes: 0,
+                        fragment_received: vec![false; num_fragments_total],
+                    });
+            if !inserted {
+                log::error!(
+                    "[{}] ignoring invalid fragment. could not insert in reassembly buffer (stale)",
+                    self.config.name
+                );
+                self.counters.num_fragments_invalid += 1;
+                return;
+            }
+            self.received_packets.advance(sequence);
+        }
+
+        let reassembly_data = self.fragment_reassembly.get_mut(sequence).unwrap();
+
+        if fragment.num_fragments != reassembly_data.num_fragments_total {
+            log::error!(
+                "[{}] ignoring invalid fragment. fragment count mismatch. expected {}, got {}",
+                self.config.name,
+                reassembly_data.num_fragments_total,
+                fragment.num_fragments
+            );
+            self.counters.num_fragments_invalid += 1;
+            return;
+        }
+
+        if reassembly_data.fragment_received[fragment.fragment_id] {
+            log::error!(
+                "[{}] ignoring fragment {} of packet {}. fragment already received",
+                self.config.name,
+                fragment.fragment_id,
+                sequence
+            );
+            return;
+        }
+
+        log::debug!(
+            "[{}] received fragment {} of packet {} ({}/{})",
+            self.config.name,
+            fragment.fragment_id,
+            sequence,
+            reassembly_data.num_fragments_received + 1,
+            reassembly_data.num_fragments_total
+        );
+
+        reassembly_data.num_fragments_received += 1;
+        reassembly_data.fragment_received[fragment.fragment_id] = true;
+
+        let fragment_data = &packet_data[fragment.header_bytes..];
+
+        if let Some(header) = fragment.packet_header {
+            let mut packet_header = Vec::with_capacity(MAX_PACKET_HEADER_BYTES);
+            write_packet_header(
+                &mut packet_header,
+                header.sequence,
+                header.ack,
+                header.ack_bits,
+            );
+            let header_start = MAX_PACKET_HEADER_BYTES - packet_header.len();
+            reassembly_data.packet_data[header_start..MAX_PACKET_HEADER_BYTES]
+                .copy_from_slice(&packet_header);
+            reassembly_data.packet_header_bytes = packet_header.len();
+        }
+
+        if fragment.fragment_id == fragment.num_fragments - 1 {
+            reassembly_data.packet_bytes =
+                (fragment.num_fragments - 1) * self.config.fragment_size + fragment_data.len();
+        }
+
+        let fragment_start =
+            MAX_PACKET_HEADER_BYTES + fragment.fragment_id * self.config.fragment_size;
+        reassembly_data.packet_data[fragment_start..fragment_start + fragment_data.len()]
+            .copy_from_slice(fragment_data);
+
+        self.counters.num_fragments_received += 1;
+
+        if reassembly_data.num_fragments_received == reassembly_data.num_fragments_total {
+            log::debug!(
+                "[{}] completed reassembly of packet {}",
+                self.config.name,
+                sequence
+            );
+
+            let reassembly_data = self.fragment_reassembly.take(sequence).unwrap();
+            let packet_start = MAX_PACKET_HEADER_BYTES - reassembly_data.packet_header_bytes;
+            let packet_end = MAX_PACKET_HEADER_BYTES + reassembly_data.packet_bytes;
+            self.receive_regular_packet(
+                &reassembly_data.packet_data[packet_start..packet_end],
+                process_packet,
+            );
+        }
+    }
+
+    fn generate_ack_bits(&self) -> (u16, u32) {
+        let ack = self.received_packets.sequence_pointer().wrapping_sub(1);
+        let mut ack_bits = 0;
+        for i in 0..32u16 {
+            if self.received_packets.exists(ack.wrapping_sub(i)) {
+                ack_bits |= 1 << i;
+            }
+        }
+        (ack, ack_bits)
+    }
+
+    fn sent_packet_samples(&self) -> impl Iterator<Item = &SentPacketData> {
+        let sample_size = self.config.sent_packets_buffer_size / 2;
+        let base_sequence = self
+            .sent_packets
+            .sequence_pointer()
+            .wrapping_sub(self.config.sent_packets_buffer_size as u16);
+        (0..sample_size)
+            .filter_map(move |i| self.sent_packets.get(base_sequence.wrapping_add(i as u16)))
+    }
+}
+
+fn smooth(current: f32, sample: f32, smoothing_factor: f32) -> f32 {
+    if (current - sample).abs() > 0.00001 {
+        current + (sample - current) * smoothing_factor
+    } else {
+        sample
+    }
+}
+
+/// Average bandwidth over `(time, bytes)` samples, or None if they don't span any time.
+fn bandwidth_kbps<I: Iterator<Item = (f64, usize)>>(samples: I) -> Option<f32> {
+    let mut bytes = 0;
+    let mut start_time = f64::MAX;
+    let mut finish_time = 0.0f64;
+    for (time, packet_bytes) in samples {
+        bytes += packet_bytes;
+        start_time = start_time.min(time);
+        finish_time = finish_time.max(time);
+    }
+    if finish_time > start_time {
+        Some((bytes as f64 / (finish_time - start_time) * 8.0 / 1000.0) as f32)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PacketHeader {
+    sequence: u16,
+    ack: u16,
+    ack_bits: u32,
+}
+
+struct FragmentHeader {
+    sequence: u16,
+    fragment_id: usize,
+    num_fragments: usize,
+    /// Bytes to skip to get to the fragment data, including the packet header on fragment 0.
+    header_bytes: usize,
+    /// The packet header, only sent with fragment 0.
+    packet_header: Option<PacketHeader>,
+}
+
+/// Write a packet header, leaving out the parts of `ack_bits` which are all ones.
+fn write_packet_header(buffer: &mut Vec<u8>, sequence: u16, ack: u16, ack_bits: u32) {
+    let mut prefix_byte = 0u8;
+    for i in 0..4 {
+        if (ack_bits >> (8 * i)) & 0xFF != 0xFF {
+            prefix_byte |= 1 << (i + 1);
+        }
+    }
+
+    let sequence_difference = sequence.wrapping_sub(ack);
+    if sequence_difference <= 255 {
+        prefix_byte |= 1 << 5;
+    }
+
+    buffer.push(prefix_byte);
+    buffer.extend_from_slice(&sequence.to_le_bytes());
+
+    if sequence_difference <= 255 {
+        buffer.push(sequence_difference as u8);
+    } else {
+        buffer.extend_from_slice(&ack.to_le_bytes());
+    }
+
+    for i in 0..4 {
+        if prefix_byte & (1 << (i + 1)) != 0 {
+            buffer.push((ack_bits >> (8 * i)) as u8);
+        }
+    }
+}
+
+/// Returns the header and the number of bytes it took, or None if the header is invalid.
+fn read_packet_header(packet_data: &[u8]) -> Option<(PacketHeader, usize)> {
+    if packet_data.len() < 3 {
+        return None;
+    }
+
+    let prefix_byte = packet_data[0];
+    if prefix_byte & 1 != 0 {
+        return None;
+    }
+
+    let sequence = u16::from_le_bytes([packet_data[1], packet_data[2]]);
+    let mut offset = 3;
+
+    let ack = if prefix_byte & (1 << 5) != 0 {
+        let sequence_difference = *packet_data.get(offset)?;
+        offset += 1;
+        sequence.wrapping_sub(sequence_difference as u16)
+    } else {
+        let bytes = packet_data.get(offset..offset + 2)?;
+        offset += 2;
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    };
+
+    let mut ack_bits = u32::MAX;
+    for i in 0..4 {
+        if prefix_byte & (1 << (i + 1)) != 0 {
+            let byte = *packet_data.get(offset)?;
+            offset += 1;
+            ack_bits &= !(0xFF << (8 * i));
+            ack_bits |= (byte as u32) << (8 * i);
+        }
+    }
+
+    let header = PacketHeader {
+        sequence,
+        ack,
+        ack_bits,
+    };
+    Some((header, offset))
+}
+
+fn read_fragment_header(
+    packet_data: &[u8],
+    max_fragments: usize,
+    fragment_size: usize,
+) -> Option<FragmentHeader> {
+    if packet_data.len() < FRAGMENT_HEADER_BYTES {
+        return None;
+    }
+
+    if packet_data[0] != 1 {
+        return None;
+    }
+
+    let sequence = u16::from_le_bytes([packet_data[1], packet_data[2]]);
+    let fragment_id = packet_data[3] as usize;
+    let num_fragments = packet_data[4] as usize + 1;
+
+    if num_fragments > max_fragments || fragment_id >= num_fragments {
+        return None;
+    }
+
+    let mut header_bytes = FRAGMENT_HEADER_BYTES;
+    let mut packet_header = None;
+    if fragment_id == 0 {
+        let (header, packet_header_bytes) = read_packet_header(&packet_data[header_bytes..])?;
+        if header.sequence != sequence {
+            return None;
+        }
+        header_bytes += packet_header_bytes;
+        packet_header = Some(header);
+    }
+
+    let fragment_bytes = packet_data.len() - header_bytes;
+    if fragment_bytes > fragment_size {
+        return None;
+    }
+    if fragment_id != num_fragments - 1 && fragment_bytes != fragment_size {
+        return None;
+    }
+
+    Some(FragmentHeader {
+        sequence,
+        fragment_id,
+        num_fragments,
+        header_bytes,
+        packet_header,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_config(name: &str) -> ReliableConfig {
+        ReliableConfig {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Deliver every packet in `packets` to `receiver`, returning the payloads it processed.
+    fn deliver(receiver: &mut ReliableEndpoint, packets: Vec<Vec<u8>>) -> Vec<(u16, Vec<u8>)> {
+        let mut processed = Vec::new();
+        for packet in packets {
+            receiver.receive_packet(&packet, |sequence, payload| {
+                processed.push((sequence, payload.to_vec()));
+                true
+            });
+        }
+        processed
+    }
+
+    #[test]
+    fn test_packet_header() {
+        let cases = [
+            (0, 0, 0),
+            (10000, 100, u32::MAX),
+            (10000, 9900, 0xFF00FF00),
+            (5, 65530, 0x12345678),
+            (200, 0, 0x00FFFFFF),
+        ];
+        for (sequence, ack, ack_bits) in cases {
+            let mut buffer = Vec::new();
+            write_packet_header(&mut buffer, sequence, ack, ack_bits);
+            assert!(buffer.len() <= MAX_PACKET_HEADER_BYTES);
+
+            let (header, header_bytes) = read_packet_header(&buffer).unwrap();
+            assert_eq!(header_bytes, buffer.len());
+            assert_eq!(
+                header,
+                PacketHeader {
+                    sequence,
+                    ack,
+                    ack_bits
+                }
+            );
+
+            // every truncation of the header is invalid
+            for len in 0..buffer.len() {
+                assert!(read_packet_header(&buffer[..len]).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_acks() {
+        let mut time = 100.0;
+        let mut sender = ReliableEndpoint::new(test_config("sender"), time);
+        let mut receiver = ReliableEndpoint::new(test_config("receiver"), time);
+
+        let mut acked = vec![false; 200];
+        for i in 0..acked.len() {
+            let packets = sender.send_packet(&[i as u8; 8]);
+            deliver(&mut receiver, packets);
+            let reply = receiver.send_packet(&[0; 8]);
+
+            time += 0.01;
+            sender.update(time);
+            receiver.update(time);
+
+            deliver(&mut sender, reply);
+            for ack in sender.take_acks() {
+                acked[ack as usize] = true;
+            }
+        }
+
+        assert!(acked.iter().all(|acked| *acked));
+        assert!(sender.take_acks().is_empty());
+        assert!((sender.rtt() - 10.0).abs() < 0.01);
+        assert_eq!(sender.packet_loss(), 0.0);
+        assert_eq!(sender.counters().num_packets_acked, acked.len() as u64);
+    }
+
+    #[test]
+    fn test_acks_packet_loss() {
+        let mut time = 100.0;
+        let mut sender = ReliableEndpoint::new(test_config("sender"), time);
+        let mut receiver = ReliableEndpoint::new(test_config("receiver"), time);
+
+        let mut acked = vec![false; 200];
+        for i in 0..acked.len() {
+            let packets = sender.send_packet(&[i as u8; 8]);
+            if i % 2 == 0 {
+                deliver(&mut receiver, packets);
+            }
+            let reply = receiver.send_packet(&[0; 8]);
+
+            time += 0.01;
+            sender.update(time);
+            receiver.update(time);
+
+            deliver(&mut sender, reply);
+            for ack in sender.take_acks() {
+                acked[ack as usize] = true;
+            }
+        }
+
+        for (sequence, acked) in acked.iter().enumerate() {
+            assert_eq!(*acked, sequence % 2 == 0, "sequence: {}", sequence);
+        }
+        assert!(sender.packet_loss() > 0.0);
+    }
+
+    #[test]
+    fn test_unprocessed_packets_are_not_acked() {
+        let time = 100.0;
+        let mut sender = ReliableEndpoint::new(test_config("sender"), time);
+        let mut receiver = ReliableEndpoint::new(test_config("receiver"), time);
+
+        for packet in sender.send_packet(&[1; 8]) {
+            receiver.receive_packet(&packet, |_, _| false);
+        }
+
+        let packets = receiver.send_packet(&[0; 8]);
+        deliver(&mut sender, packets);
+        assert!(sender.take_acks().is_empty());
+    }
+
+    #[test]
+    fn test_fragments() {
+        let time = 100.0;
+        let config = ReliableConfig {
+            max_packet_size: 4 * 1024,
+            max_fragments: 4,
+            ..test_config("endpoint")
+        };
+        let mut sender = ReliableEndpoint::new(config.clone(), time);
+        let mut receiver = ReliableEndpoint::new(config, time);
+
+        // (packet size, expected number of fragments)
+        let packet_sizes = [
+            (1, 1),
+            (1024, 1),
+            (1025, 2),
+            (2048, 2),
+            (3000, 3),
+            (4 * 1024, 4),
+        ];
+        for (i, (size, expect_fragments)) in packet_sizes.into_iter().enumerate() {
+            let packet_data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let mut packets = sender.send_packet(&packet_data);
+            assert_eq!(packets.len(), expect_fragments);
+
+            // fragments can arrive in any order, and more than once
+            if packets.len() > 1 {
+                packets.reverse();
+                packets.insert(1, packets[0].clone());
+            }
+
+            let processed = deliver(&mut receiver, packets);
+            assert_eq!(processed, vec![(i as u16, packet_data)]);
+        }
+
+        assert_eq!(receiver.counters().num_fragments_invalid, 0);
+        assert_eq!(
+            receiver.counters().num_packets_received,
+            packet_sizes.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_packet_too_large_to_send() {
+        let mut endpoint = ReliableEndpoint::new(test_config("endpoint"), 100.0);
+        let packet_data = vec![0; endpoint.config.max_packet_size + 1];
+        assert!(endpoint.send_packet(&packet_data).is_empty());
+        assert_eq!(endpoint.next_packet_sequence(), 0);
+        assert_eq!(endpoint.counters().num_packets_too_large_to_send, 1);
+    }
+
+    #[test]
+    fn test_invalid_packets() {
+        let time = 100.0;
+        let mut sender = ReliableEndpoint::new(test_config("sender"), time);
+        let mut receiver = ReliableEndpoint::new(test_config("receiver"), time);
+
+        let packet_data = vec![7; 2000];
+        let mut packets = sender.send_packet(&[7; 8]);
+        packets.extend(sender.send_packet(&packet_data));
+
+        // truncated packet headers and fragments are rejected
+        let (_, packet_header_bytes) = read_packet_header(&packets[0]).unwrap();
+        for len in 0..packet_header_bytes {
+            deliver(&mut receiver, vec![packets[0][..len].to_vec()]);
+        }
+        for len in 0..packets[1].len() {
+            deliver(&mut receiver, vec![packets[1][..len].to_vec()]);
+        }
+
+        // a fragment which disagrees about the fragment count is rejected
+        deliver(&mut receiver, vec![packets[2].clone()]);
+        let mut bad_fragment = packets[1].clone();
+        bad_fragment[4] += 1;
+        deliver(&mut receiver, vec![bad_fragment]);
+
+        let processed = deliver(&mut receiver, packets);
+        assert_eq!(processed, vec![(0, vec![7; 8]), (1, packet_data)]);
+        assert!(receiver.counters().num_packets_invalid > 0);
+        assert!(receiver.counters().num_fragments_invalid > 0);
+    }
+}
@@ -74,6 +74,21 @@ impl<T> SequenceBuffer<T> {
         true
     }
 
+    /// Returns true if an entry for `sequence` could be inserted (i.e. it is not too old).
+    pub(crate) fn can_insert(&self, sequence: u16) -> bool {
+        !sequence_less_than(sequence, self.sequence.wrapping_sub(self.capacity() as u16))
+    }
+
+    /// Advance the sequence pointer past `sequence` without inserting an
+    /// entry, removing any old entries in between.
+    pub(crate) fn advance(&mut self, sequence: u16) {
+        let next_sequence = sequence.wrapping_add(1);
+        if sequence_greater_than(next_sequence, self.sequence) {
+            self.remove_entries(self.sequence, sequence);
+            self.sequence = next_sequence;
+        }
+    }
+
     /// Take an entry from the buffer with matching `sequence`.
     ///
     /// Returns None if the sequence entry does not exist, else returns
@@ -1,27 +1,69 @@
-use std::ffi::{c_void, CString};
+use std::collections::VecDeque;
 use std::mem::size_of;
-use std::ptr::null_mut;
-use std::slice;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-use crate::channel::ChannelCounters;
+use crate::bindings::NetcodeAddress;
+use crate::channel::{ChannelCounters, SendError, SerializedMessage};
+use crate::client::Client;
 use crate::config::ClientServerConfig;
 use crate::connection::{Connection, ConnectionErrorLevel};
 use crate::message::NetworkMessage;
 use crate::network_info::NetworkInfo;
 use crate::network_simulator::NetworkSimulator;
-use crate::{bindings::*, gf_init_default, PRIVATE_KEY_BYTES};
-
-pub struct Server<M: NetworkMessage> {
-    private_key: [u8; PRIVATE_KEY_BYTES],
-    address: String,
+use crate::reliable::ReliableEndpoint;
+use crate::transport::{NetcodeTransport, Transport, TransportEvent};
+use crate::{PRIVATE_KEY_BYTES, USER_DATA_BYTES};
 
+pub struct Server<M: NetworkMessage, T: Transport = NetcodeTransport> {
     /// Base client/server config.
     config: ClientServerConfig,
 
     /// Current server time in seconds.
     time: f64,
 
-    runtime: *mut ServerRuntime<M>,
+    transport: T,
+
+    /// Per-client state, only present while the server is running.
+    runtime: Option<ServerRuntime<M>>,
+
+    /// Events for `poll_event`, kept across restarts until they're polled.
+    events: VecDeque<ServerEvent>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerEvent {
+    /// A client connected in slot `client_index`.
+    ClientConnected {
+        client_index: usize,
+        client_id: u64,
+        /// None for loopback clients, and transports without addresses.
+        address: Option<SocketAddr>,
+    },
+    /// The client in slot `client_index` disconnected. Always follows a `ClientConnected` event
+    /// for the same slot.
+    ClientDisconnected {
+        client_index: usize,
+        reason: DisconnectReason,
+    },
+    /// The connection to the client in slot `client_index` failed, and the client will be
+    /// disconnected (with `DisconnectReason::ConnectionError`).
+    ClientErrored {
+        client_index: usize,
+        error: ConnectionErrorLevel,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client disconnected or timed out (the transport doesn't tell us which).
+    Disconnected,
+    /// The client was disconnected with `Server::disconnect_client`.
+    DisconnectedByServer,
+    /// The connection failed, see `ServerEvent::ClientErrored`.
+    ConnectionError,
+    /// The server was stopped.
+    ServerStopped,
 }
 
 impl<M: NetworkMessage> Server<M> {
@@ -31,64 +73,407 @@ impl<M: NetworkMessage> Server<M> {
         config: ClientServerConfig,
         time: f64,
     ) -> Server<M> {
+        let transport = NetcodeTransport::new(private_key, address, config.protocol_id);
+        Server::with_transport(transport, config, time)
+    }
+
+    pub fn client_address(&self, client_index: usize) -> Option<NetcodeAddress> {
+        self.transport.client_address(client_index)
+    }
+
+    pub fn bound_port(&self) -> Option<u16> {
+        self.transport.bound_port()
+    }
+
+    /// The user data the client connected with (see `ConnectTokenBuilder::user_data`), or None
+    /// if the client isn't connected.
+    pub fn client_user_data(&self, client_index: usize) -> Option<&[u8; USER_DATA_BYTES]> {
+        self.transport.client_user_data(client_index)
+    }
+
+    /// Connect a client running in the same process (e.g. the host of a listen server) in slot
+    /// `client_index`, bypassing the network.
+    ///
+    /// The client connects with `Client::connect_loopback`, and packets are exchanged with
+    /// `pump_loopback_client`. Otherwise it is treated like any other client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `client_index` is out of bounds or already connected.
+    pub fn connect_loopback_client(
+        &mut self,
+        client_index: usize,
+        client_id: u64,
+        user_data: &[u8; USER_DATA_BYTES],
+    ) {
+        let Some(runtime) = &mut self.runtime else { return };
+        self.transport
+            .connect_loopback_client(client_index, client_id, user_data);
+        runtime.handle_transport_events(&mut self.transport, &mut self.events);
+    }
+
+    /// Disconnect the loopback client in slot `client_index`.
+    ///
+    /// The `Client` isn't notified, so disconnect it too.
+    pub fn disconnect_loopback_client(&mut self, client_index: usize) {
+        let Some(runtime) = &mut self.runtime else { return };
+        if self.transport.is_loopback_client(client_index) {
+            runtime.client_disconnect_reason[client_index] =
+                Some(DisconnectReason::DisconnectedByServer);
+        }
+        self.transport.disconnect_loopback_client(client_index);
+        runtime.handle_transport_events(&mut self.transport, &mut self.events);
+    }
+
+    pub fn is_loopback_client(&self, client_index: usize) -> bool {
+        self.running() && self.transport.is_loopback_client(client_index)
+    }
+
+    /// Like `connect_loopback_client`, but messages are handed to and from the client directly,
+    /// without being serialized or sent in packets (e.g. for single player games which run the
+    /// server locally).
+    ///
+    /// The client connects with `Client::connect_local`, and messages are exchanged with
+    /// `pump_loopback_client`. Channels behave as usual, except that there are no packet size
+    /// limits, and blocks stay attached to their message (`NetworkMessage::attach_block` isn't
+    /// called).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `client_index` is out of bounds or already connected.
+    pub fn connect_local_client(
+        &mut self,
+        client_index: usize,
+        client_id: u64,
+        user_data: &[u8; USER_DATA_BYTES],
+    ) {
+        self.connect_loopback_client(client_index, client_id, user_data);
+        if let Some(runtime) = &mut self.runtime {
+            runtime.client_local[client_index] = self.transport.is_loopback_client(client_index);
+        }
+    }
+
+    /// True if the client in slot `client_index` was connected with `connect_local_client`.
+    pub fn is_local_client(&self, client_index: usize) -> bool {
+        self.runtime
+            .as_ref()
+            .map(|runtime| runtime.client_local[client_index])
+            .unwrap_or(false)
+    }
+
+    /// Exchange packets (or messages, for local clients) between the server and its loopback
+    /// `client`.
+    ///
+    /// Call after `send_packets` on both, and before `receive_packets`.
+    pub fn pump_loopback_client(&mut self, client: &mut Client<M>) {
+        let Some(client_index) = client.loopback_client_index() else { return };
+        if !self.is_loopback_client(client_index) {
+            return;
+        }
+        let Some(runtime) = &mut self.runtime else { return };
+
+        if runtime.client_local[client_index] != client.is_local() {
+            log::error!(
+                "loopback client {} must be local on both the server and the client",
+                client_index
+            );
+            return;
+        }
+
+        if client.is_local() {
+            let Some(client_connection) = client.connection_mut() else { return };
+            let connection = &mut runtime.client_connection[client_index];
+            client_connection.send_local_messages(connection);
+            connection.send_local_messages(client_connection);
+            return;
+        }
+
+        let client_transport = client.transport_mut();
+        while self.transport.receive_loopback_packet(
+            client_index,
+            |packet_data, packet_sequence| {
+                client_transport.process_loopback_packet(packet_data, packet_sequence)
+            },
+        ) {}
+        while client_transport.receive_loopback_packet(|packet_data, packet_sequence| {
+            self.transport
+                .process_loopback_packet(client_index, packet_data, packet_sequence)
+        }) {}
+    }
+}
+
+impl<M: NetworkMessage, T: Transport> Server<M, T> {
+    /// Create a server which accepts clients over `transport`.
+    pub fn with_transport(transport: T, config: ClientServerConfig, time: f64) -> Server<M, T> {
         assert_ne!(
             size_of::<M>(),
             0,
             "Zero sized message types are not supported"
         );
+        if let Err(err) = config.validate() {
+            panic!("invalid config: {}", err);
+        }
 
         Server {
-            private_key: *private_key,
-            address,
             config,
             time,
-            runtime: null_mut(),
+            transport,
+            runtime: None,
+            events: VecDeque::new(),
         }
     }
 
     pub fn start(&mut self, max_clients: usize) {
-        if !self.runtime.is_null() {
+        if self.running() {
             // TODO: is it better to return an error?
             self.stop();
         }
-        self.runtime = ServerRuntime::<M>::new(
-            &self.config,
-            &self.private_key,
-            &self.address,
-            max_clients,
-            self.time,
-        );
+
+        self.transport.start(max_clients, self.time);
+        if !self.transport.running() {
+            return;
+        }
+
+        self.runtime = Some(ServerRuntime::new(&self.config, max_clients, self.time));
     }
 
     pub fn stop(&mut self) {
-        if !self.runtime.is_null() {
-            unsafe {
-                ServerRuntime::drop(self.runtime);
-                self.runtime = null_mut();
-            }
-        }
+        let Some(mut runtime) = self.runtime.take() else {
+            self.transport.stop();
+            return;
+        };
+        runtime
+            .client_disconnect_reason
+            .fill(Some(DisconnectReason::ServerStopped));
+        self.transport.stop();
+        runtime.handle_transport_events(&mut self.transport, &mut self.events);
+    }
+
+    /// Take the next client connect, disconnect or error event, in the order they happened.
+    ///
+    /// Events are queued by `advance_time`, `disconnect_client`, `stop` etc.
+    pub fn poll_event(&mut self) -> Option<ServerEvent> {
+        self.events.pop_front()
     }
 
     pub fn advance_time(&mut self, new_time: f64) {
-        advance_time(self.runtime, new_time)
+        self.time = new_time;
+
+        let Some(runtime) = &mut self.runtime else { return };
+        let transport = &mut self.transport;
+
+        transport.update(new_time);
+        runtime.handle_transport_events(transport, &mut self.events);
+
+        for client_index in 0..runtime.client_connection.len() {
+            let connection = &mut runtime.client_connection[client_index];
+
+            connection.advance_time(new_time);
+
+            if connection.error_level() != ConnectionErrorLevel::None {
+                log::error!(
+                    "client {} connection is in error state. disconnecting client",
+                    client_index
+                );
+                // TODO: on disconnect, clear send queue https://github.com/networkprotocol/yojimbo/issues/129
+                if transport.is_client_connected(client_index) {
+                    self.events.push_back(ServerEvent::ClientErrored {
+                        client_index,
+                        error: connection.error_level(),
+                    });
+                    runtime.client_disconnect_reason[client_index] =
+                        Some(DisconnectReason::ConnectionError);
+                    transport.disconnect_client(client_index);
+                }
+                continue;
+            }
+
+            let endpoint = &mut runtime.client_endpoint[client_index];
+            endpoint.update(new_time);
+            connection.process_acks(&endpoint.take_acks());
+
+            if let Some(network_simulator) = &mut runtime.network_simulator {
+                network_simulator.advance_time(new_time);
+            }
+        }
+
+        // reset any clients disconnected above
+        runtime.handle_transport_events(transport, &mut self.events);
+
+        if let Some(network_simulator) = &mut runtime.network_simulator {
+            if network_simulator.active() {
+                for (client_index, packet_data) in network_simulator.receive_packets() {
+                    transport.send_packet(client_index, packet_data);
+                }
+            }
+        }
     }
 
     pub fn send_packets(&mut self) {
-        send_packets(&self.config, self.runtime)
+        let Some(runtime) = &mut self.runtime else { return };
+        assert_eq!(
+            runtime.packet_buffer.len(),
+            self.config.connection.max_packet_size
+        );
+
+        for client_index in 0..runtime.client_connection.len() {
+            if !self.transport.is_client_connected(client_index)
+                || runtime.client_local[client_index]
+            {
+                continue;
+            }
+
+            let endpoint = &mut runtime.client_endpoint[client_index];
+            let connection = &mut runtime.client_connection[client_index];
+
+            let packet_sequence = endpoint.next_packet_sequence();
+            let written_bytes =
+                connection.generate_packet(packet_sequence, &mut runtime.packet_buffer[..]);
+
+            assert!(written_bytes <= self.config.connection.max_packet_size);
+
+            if written_bytes > 0 {
+                let packets = endpoint.send_packet(&runtime.packet_buffer[..written_bytes]);
+                for packet in packets {
+                    runtime.transmit_packet(&mut self.transport, client_index, &packet);
+                }
+            }
+        }
     }
 
     pub fn receive_packets(&mut self) {
-        receive_packets(self.runtime);
+        let Some(runtime) = &mut self.runtime else { return };
+
+        let clients = runtime
+            .client_endpoint
+            .iter_mut()
+            .zip(runtime.client_connection.iter_mut())
+            .enumerate();
+
+        for (client_index, (endpoint, connection)) in clients {
+            while self.transport.receive_packet(client_index, |packet_data| {
+                endpoint.receive_packet(packet_data, |sequence, payload| {
+                    connection.process_packet(sequence, payload)
+                });
+            }) {}
+        }
     }
 
-    pub fn send_message(&mut self, client_index: usize, channel_index: usize, message: M) {
-        unsafe {
-            if let Some(runtime) = self.runtime.as_mut() {
-                runtime.client_connection[client_index].send_message(channel_index, message);
+    /// Queue a message to be sent to a client on a channel.
+    ///
+    /// Returns the message id, which `poll_acked` reports once the message is delivered, or
+    /// `None` if the message was dropped (see `try_send_message`).
+    ///
+    /// On reliable channels, the receiver gets the same id from `receive_message_with_id`. On
+    /// unreliable channels the id only means something to the sender: the receiver gets the
+    /// packet sequence instead. To agree on a message (e.g. the last acked snapshot), put your
+    /// own id in the message.
+    pub fn send_message(
+        &mut self,
+        client_index: usize,
+        channel_index: usize,
+        message: M,
+    ) -> Option<u16> {
+        self.runtime
+            .as_mut()?
+            .client_connection
+            .get_mut(client_index)?
+            .send_message(channel_index, message)
+    }
+
+    /// Like `send_message`, but messages on unreliable channels are sent by priority. See
+    /// `Client::send_message_with_priority`.
+    pub fn send_message_with_priority(
+        &mut self,
+        client_index: usize,
+        channel_index: usize,
+        message: M,
+        priority: f32,
+    ) -> Option<u16> {
+        self.runtime
+            .as_mut()?
+            .client_connection
+            .get_mut(client_index)?
+            .send_message_with_priority(channel_index, message, priority)
+    }
+
+    /// Send `message` to every connected client.
+    ///
+    /// See `send_message_to`.
+    pub fn broadcast_message(&mut self, channel_index: usize, message: M) {
+        self.broadcast_message_except(&[], channel_index, message);
+    }
+
+    /// Send `message` to every connected client, except the clients in `exclude` (e.g. the
+    /// client the message came from).
+    ///
+    /// See `send_message_to`.
+    pub fn broadcast_message_except(
+        &mut self,
+        exclude: &[usize],
+        channel_index: usize,
+        message: M,
+    ) {
+        let clients =
+            (0..self.max_clients()).filter(|client_index| !exclude.contains(client_index));
+        self.send_message_to(clients, channel_index, message);
+    }
+
+    /// Send `message` to each connected client in `clients`. Other client indices are skipped.
+    ///
+    /// The message is serialized once, and the bytes are shared by every client's send queue.
+    /// Otherwise this is like calling `send_message` for each client; a full send queue puts
+    /// that client's connection in an error state. A message which fails to serialize is
+    /// logged and dropped.
+    pub fn send_message_to<I: IntoIterator<Item = usize>>(
+        &mut self,
+        clients: I,
+        channel_index: usize,
+        message: M,
+    ) {
+        let Some(runtime) = &mut self.runtime else { return };
+
+        let serialized = match SerializedMessage::new(&message) {
+            Ok(serialized) => serialized,
+            Err(err) => {
+                log::error!("failed to serialize message: {:?}", err);
+                return;
+            }
+        };
+        let message = Arc::new(message);
+
+        for client_index in clients {
+            if client_index < runtime.client_connection.len()
+                && self.transport.is_client_connected(client_index)
+            {
+                runtime.client_connection[client_index].send_shared_message(
+                    channel_index,
+                    &message,
+                    &serialized,
+                );
             }
         }
     }
 
+    /// Like `send_message`, but hands the message back if it can't be sent, instead of dropping
+    /// it (or disconnecting the client, if the send queue is full or the channel doesn't allow
+    /// blocks).
+    pub fn try_send_message(
+        &mut self,
+        client_index: usize,
+        channel_index: usize,
+        message: M,
+    ) -> Result<u16, SendError<M>> {
+        let Some(runtime) = &mut self.runtime else { return Err(SendError::NotConnected(message)) };
+        if client_index >= runtime.client_connection.len() {
+            return Err(SendError::InvalidClient(message));
+        }
+        if !self.transport.is_client_connected(client_index) {
+            return Err(SendError::NotConnected(message));
+        }
+        runtime.client_connection[client_index].try_send_message(channel_index, message)
+    }
+
     /// Receive a message.
     ///
     /// On an ordered channel, these messages are always returned in the order sent; on an
@@ -98,15 +483,9 @@ impl<M: NetworkMessage> Server<M> {
     ///
     /// Returns `None` when all received messages are handled. Call `receive_packets` before this.
     pub fn receive_message(&mut self, client_index: usize, channel_index: usize) -> Option<M> {
-        unsafe {
-            if let Some(runtime) = self.runtime.as_mut() {
-                runtime.client_connection[client_index]
-                    .receive_message(channel_index)
-                    .map(|(_id, message)| message)
-            } else {
-                None
-            }
-        }
+        self.runtime.as_mut()?.client_connection[client_index]
+            .receive_message(channel_index)
+            .map(|(_id, message)| message)
     }
 
     /// Receive a message, along with it's ID.
@@ -116,7 +495,11 @@ impl<M: NetworkMessage> Server<M> {
     ///
     /// For unordered channels, the ID is an incrementing & wrapping "sequence number", which
     /// can be used to determine when an unreliable message is older or newer than a previously
-    /// received reliable message. (e.g., so you can ignore old transform replications)
+    /// received reliable message. (To simply ignore old transform replications, use an
+    /// `UnreliableSequenced` channel instead.)
+    ///
+    /// On unreliable channels, this id is the packet sequence, which is unrelated to the id
+    /// `send_message` returned to the sender.
     ///
     /// Returns `None`` when all received messages are handled. Call `receive_packets` before this.
     pub fn receive_message_with_id(
@@ -124,85 +507,82 @@ impl<M: NetworkMessage> Server<M> {
         client_index: usize,
         channel_index: usize,
     ) -> Option<(u16, M)> {
-        unsafe {
-            if let Some(runtime) = self.runtime.as_mut() {
-                runtime.client_connection[client_index].receive_message(channel_index)
-            } else {
-                None
-            }
-        }
+        self.runtime.as_mut()?.client_connection[client_index].receive_message(channel_index)
+    }
+
+    /// Take the ids (returned by `send_message`) of the messages sent to a client on this
+    /// channel which were acked since the last call. See `Client::poll_acked`.
+    ///
+    /// Messages sent with `send_message_to` and the broadcast functions use up ids too, and are
+    /// reported here as well.
+    pub fn poll_acked(
+        &mut self,
+        client_index: usize,
+        channel_index: usize,
+    ) -> impl Iterator<Item = u16> + '_ {
+        self.runtime.iter_mut().flat_map(move |runtime| {
+            runtime.client_connection[client_index].poll_acked(channel_index)
+        })
+    }
+
+    /// Take the ids of the messages sent to a client on this channel which were lost since the
+    /// last call. See `Client::poll_lost`.
+    pub fn poll_lost(
+        &mut self,
+        client_index: usize,
+        channel_index: usize,
+    ) -> impl Iterator<Item = u16> + '_ {
+        self.runtime.iter_mut().flat_map(move |runtime| {
+            runtime.client_connection[client_index].poll_lost(channel_index)
+        })
     }
 
     /// Get the maxmimum number of clients.
     ///
     /// Returns 0 if the server is not running.
     pub fn max_clients(&self) -> usize {
-        unsafe {
-            self.runtime
-                .as_ref()
-                .map(|client| client.max_clients)
-                .unwrap_or(0)
-        }
+        self.runtime
+            .as_ref()
+            .map(|runtime| runtime.client_connection.len())
+            .unwrap_or(0)
     }
 
     pub fn client_id(&self, client_index: usize) -> Option<u64> {
-        unsafe {
-            if let Some(runtime) = self.runtime.as_mut() {
-                if is_client_connected(runtime.server, client_index) {
-                    Some(netcode_server_client_id(runtime.server, client_index as _))
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
+        if !self.running() {
+            return None;
         }
+        self.transport.client_id(client_index)
     }
 
     pub fn is_client_connected(&self, client_index: usize) -> bool {
-        unsafe {
-            if let Some(runtime) = self.runtime.as_mut() {
-                is_client_connected(runtime.server, client_index)
-            } else {
-                false
-            }
-        }
+        self.running() && self.transport.is_client_connected(client_index)
     }
 
     pub fn disconnect_client(&mut self, client_index: usize) {
-        unsafe {
-            if let Some(runtime) = self.runtime.as_mut() {
-                if is_client_connected(runtime.server, client_index) {
-                    let endpoint = runtime.client_endpoint[client_index];
-                    let connection = &mut runtime.client_connection[client_index];
-                    disconnect_client(runtime.server, client_index, endpoint, connection);
-                    #[allow(clippy::drop_ref)]
-                    drop(runtime); // SAFETY: the disconnect callback will fire on `runtime`
-                }
-            }
+        let Some(runtime) = &mut self.runtime else { return };
+        if self.transport.is_client_connected(client_index) {
+            // TODO: on disconnect, clear send queue https://github.com/networkprotocol/yojimbo/issues/129
+            runtime.client_disconnect_reason[client_index] =
+                Some(DisconnectReason::DisconnectedByServer);
+            self.transport.disconnect_client(client_index);
+            runtime.handle_transport_events(&mut self.transport, &mut self.events);
         }
     }
 
     pub fn can_send_message(&self, client_index: usize, channel_index: usize) -> bool {
-        unsafe {
-            self.runtime
-                .as_mut()
-                .map(|runtime| {
-                    runtime.client_connection[client_index].can_send_message(channel_index)
-                })
-                .unwrap_or(false)
-        }
+        self.runtime
+            .as_ref()
+            .map(|runtime| runtime.client_connection[client_index].can_send_message(channel_index))
+            .unwrap_or(false)
     }
 
     pub fn has_messages_to_send(&self, client_index: usize, channel_index: usize) -> bool {
-        unsafe {
-            self.runtime
-                .as_mut()
-                .map(|runtime| {
-                    runtime.client_connection[client_index].has_messages_to_send(channel_index)
-                })
-                .unwrap_or(false)
-        }
+        self.runtime
+            .as_ref()
+            .map(|runtime| {
+                runtime.client_connection[client_index].has_messages_to_send(channel_index)
+            })
+            .unwrap_or(false)
     }
 
     /// Get the counters for client `client_index` and channel `channel_index`.
@@ -211,22 +591,16 @@ impl<M: NetworkMessage> Server<M> {
     ///
     /// Panics if the server is not running, or one of client and channel_index is out of bounds.
     pub fn channel_counters(&self, client_index: usize, channel_index: usize) -> &ChannelCounters {
-        unsafe {
-            self.runtime
-                .as_mut()
-                .map(|runtime| {
-                    runtime.client_connection[client_index].channel_counters(channel_index)
-                })
-                .unwrap()
-        }
+        self.runtime
+            .as_ref()
+            .map(|runtime| runtime.client_connection[client_index].channel_counters(channel_index))
+            .unwrap()
     }
 
     pub fn network_simulator_mut(&mut self) -> Option<&mut NetworkSimulator> {
-        unsafe {
-            self.runtime
-                .as_mut()
-                .and_then(|runtime| runtime.network_simulator.as_mut())
-        }
+        self.runtime
+            .as_mut()
+            .and_then(|runtime| runtime.network_simulator.as_mut())
     }
 
     // TODO: nice place for doc comments here
@@ -241,517 +615,143 @@ impl<M: NetworkMessage> Server<M> {
     ///
     /// Returns None if the client is not connected.
     pub fn snapshot_network_info(&self, client_index: usize) -> Option<NetworkInfo> {
-        unsafe {
-            self.runtime
-                .as_ref()
-                .and_then(|runtime| runtime.snapshot_network_info(client_index))
-        }
-    }
-
-    pub fn client_address(&self, client_index: usize) -> Option<NetcodeAddress> {
         if !self.is_client_connected(client_index) {
             return None;
         }
-        unsafe {
-            let raw = self.runtime.as_ref().map(|runtime| {
-                netcode_server_client_address(runtime.server, client_index as i32)
-            })?;
-            if raw.is_null() {
-                None
-            } else {
-                Some(NetcodeAddress::new(raw))
-            }
-        }
+
+        let runtime = self.runtime.as_ref()?;
+        Some(NetworkInfo::from_endpoint(
+            &runtime.client_endpoint[client_index],
+        ))
     }
 
     pub fn connected_client_count(&self) -> usize {
-        let count = unsafe {
-            self.runtime
-                .as_ref()
-                .map(|runtime| netcode_server_num_connected_clients(runtime.server))
-                .unwrap_or(0)
-        };
-        assert!(count >= 0);
-        count as usize
+        if !self.running() {
+            return 0;
+        }
+        self.transport.connected_client_count()
     }
 
     pub fn running(&self) -> bool {
-        unsafe { self.runtime.as_ref().is_some() }
+        self.runtime.is_some()
     }
 
-    pub fn bound_port(&self) -> Option<u16> {
-        unsafe { self.runtime.as_ref().map(|runtime| runtime.bound_port) }
+    /// The transport clients connect through.
+    pub fn transport(&self) -> &T {
+        &self.transport
     }
 }
 
-impl<M: NetworkMessage> Drop for Server<M> {
+impl<M: NetworkMessage, T: Transport> Drop for Server<M, T> {
     fn drop(&mut self) {
-        // this will take care of shutting down the server and freeing `runtime`
+        // disconnect everyone before the transport goes away
         self.stop();
     }
 }
 
 struct ServerRuntime<M: NetworkMessage> {
-    /// Maximum number of clients supported.
-    max_clients: usize,
-
-    /// The netcode server.
-    server: *mut netcode_server_t,
-    /// The port the netcode server is listening on.
-    bound_port: u16,
-
     /// The network simulator used to simulate packet loss, latency, jitter etc. Optional.
     network_simulator: Option<NetworkSimulator>,
 
     /// Array of per-client connection classes. This is how messages are exchanged with clients.
     client_connection: Vec<Connection<M>>,
-    /// Array of per-client reliable.io endpoints.
-    client_endpoint: Vec<*mut reliable_endpoint_t>,
+    /// Array of per-client reliable endpoints.
+    client_endpoint: Vec<ReliableEndpoint>,
+    /// True for each local client (see `Server::connect_local_client`).
+    client_local: Vec<bool>,
+    /// True for each client we queued a `ClientConnected` event for.
+    client_announced: Vec<bool>,
+    /// Why each client is being disconnected, if the server disconnected it.
+    client_disconnect_reason: Vec<Option<DisconnectReason>>,
 
     packet_buffer: Vec<u8>,
 }
 
 impl<M: NetworkMessage> ServerRuntime<M> {
-    fn new(
-        config: &ClientServerConfig,
-        private_key: &[u8; PRIVATE_KEY_BYTES],
-        address: &str,
-        max_clients: usize,
-        time: f64,
-    ) -> *mut ServerRuntime<M> {
-        assert!(max_clients < i32::MAX as usize);
-
+    fn new(config: &ClientServerConfig, max_clients: usize, time: f64) -> ServerRuntime<M> {
         let network_simulator = config
             .network_simulator
             .as_ref()
             .map(|config| NetworkSimulator::new(config.max_simulator_packets, time));
 
-        let runtime = Box::new(ServerRuntime {
-            max_clients,
+        let mut client_connection = Vec::with_capacity(max_clients);
+        let mut client_endpoint = Vec::with_capacity(max_clients);
+        for i in 0..max_clients {
+            client_connection.push(Connection::new(config.connection.clone(), time));
 
-            server: null_mut(),
-            bound_port: 0,
+            let reliable_config_name = format!("server_endpoint{}", i);
+            let reliable_config = config.new_reliable_config(&reliable_config_name, Some(i));
+            client_endpoint.push(ReliableEndpoint::new(reliable_config, time));
+        }
 
+        ServerRuntime {
             network_simulator,
 
-            client_connection: Vec::with_capacity(max_clients),
-            client_endpoint: Vec::with_capacity(max_clients),
+            client_connection,
+            client_endpoint,
+            client_local: vec![false; max_clients],
+            client_announced: vec![false; max_clients],
+            client_disconnect_reason: vec![None; max_clients],
 
             packet_buffer: vec![0u8; config.connection.max_packet_size],
-        });
-
-        /*
-            Get a stable pointer which the C callbacks can refer to:
-
-            1 box runtime so it's memory address remains constant
-            2 unwrap the box so we can "safely" take pointers to the context
-            3 use a raw pointer because we are multiple mutably aliasing `runtime`
-
-            No. 1 is simple: `runtime` can't be on the stack because the
-            pointer we hand off would be immediately invalidated when this
-            function returns.
-
-            No. 2 is required because *references* to the box's contents are
-            invalidated when the box moves (according to the Rust VM), even
-            though the physical address remains the same. Run the following in
-            miri to verify this is an error:
-
-            ```
-            fn main() {
-                let x = Box::new(0u32);
-                let ptr = &*x as *const u32;
-                let _y = x;
-                println!("{:?}", unsafe { *ptr });
-            }
-            ```
-        */
-        let runtime = Box::into_raw(runtime);
-
-        unsafe {
-            for i in 0..max_clients {
-                (*runtime)
-                    .client_connection
-                    .push(Connection::new(config.connection.clone(), time));
-
-                let reliable_config_name = format!("server_endpoint{}", i);
-                let mut reliable_config = config.new_reliable_config(
-                    runtime.cast(),
-                    &reliable_config_name,
-                    Some(i),
-                    transmit_packet::<M>,
-                    process_packet::<M>,
-                );
-
-                let endpoint = reliable_endpoint_create(&mut reliable_config, time);
-                reliable_endpoint_reset(endpoint);
-                (*runtime).client_endpoint.push(endpoint);
-            }
-
-            let nc_server = netcode_server(config, private_key, runtime, address, time);
-
-            assert!((*runtime).server.is_null());
-
-            netcode_server_start(nc_server, max_clients as i32);
-            (*runtime).bound_port = netcode_server_get_port(nc_server);
-            (*runtime).server = nc_server;
         }
-
-        runtime
-    }
-
-    /// Stop the server and drop its resources.
-    ///
-    /// Frees `runtime`.
-    ///
-    /// # Safety
-    ///
-    /// `runtime` must be a pointer returned from ServerRuntime::new().
-    ///
-    /// Specifically:
-    ///  - `runtime` pointer must come from a call to Box::into_raw
-    ///  - the inner `netcode_server_t` reference must be a valid netcode server
-    ///  - the inner `reliable_endpoint_t` references must be valid reliable endpoints
-    unsafe fn drop(runtime: *mut ServerRuntime<M>) {
-        drop(Box::from_raw(runtime));
     }
 
-    fn snapshot_network_info(&self, client_index: usize) -> Option<NetworkInfo> {
-        unsafe {
-            assert!(!self.server.is_null());
-
-            if !is_client_connected(self.server, client_index) {
-                return None;
-            }
-
-            let endpoint = self.client_endpoint[client_index];
-            assert!(!endpoint.is_null());
-
-            let mut sent_bandwidth = 0.0;
-            let mut received_bandwidth = 0.0;
-            let mut acked_bandwidth = 0.0;
-            reliable_endpoint_bandwidth(
-                endpoint,
-                &mut sent_bandwidth,
-                &mut received_bandwidth,
-                &mut acked_bandwidth,
-            );
-
-            let counters = reliable_endpoint_counters(endpoint);
-            let num_packets_sent =
-                *counters.offset(RELIABLE_ENDPOINT_COUNTER_NUM_PACKETS_SENT as _);
-            let num_packets_received =
-                *counters.offset(RELIABLE_ENDPOINT_COUNTER_NUM_PACKETS_RECEIVED as _);
-            let num_packets_acked =
-                *counters.offset(RELIABLE_ENDPOINT_COUNTER_NUM_PACKETS_ACKED as _);
-
-            Some(NetworkInfo {
-                rtt: reliable_endpoint_rtt(endpoint),
-                packet_loss: reliable_endpoint_packet_loss(endpoint),
-                sent_bandwidth,
-                received_bandwidth,
-                acked_bandwidth,
-                num_packets_sent,
-                num_packets_received,
-                num_packets_acked,
-            })
-        }
-    }
-
-    // TODO: loopback
-
-    unsafe fn transmit_packet(
+    fn transmit_packet<T: Transport>(
         &mut self,
-        client_index: i32,
-        _packet_sequence: u16,
-        packet_data: *mut u8,
-        packet_bytes: i32,
+        transport: &mut T,
+        client_index: usize,
+        packet_data: &[u8],
     ) {
-        // TODO: move the unsafety out of connection and handle it here... duh
-
         if let Some(network_simulator) = &mut self.network_simulator {
             if network_simulator.active() {
                 // intercept the packet and defer sending until `advance_time`
-                let packet_data =
-                    unsafe { slice::from_raw_parts(packet_data, packet_bytes as usize) };
-                network_simulator.send_packet(client_index as usize, packet_data);
+                network_simulator.send_packet(client_index, packet_data);
 
                 return;
             }
         }
-        netcode_server_send_packet(self.server, client_index, packet_data, packet_bytes);
+        transport.send_packet(client_index, packet_data);
     }
 
-    unsafe fn process_packet(
+    fn handle_transport_events<T: Transport>(
         &mut self,
-        client_index: i32,
-        packet_sequence: u16,
-        packet_data: *mut u8,
-        packet_bytes: i32,
-    ) -> i32 {
-        let connection = &mut self.client_connection[client_index as usize];
-        assert!(packet_bytes >= 0);
-        let result = connection.process_packet(packet_sequence, packet_data, packet_bytes as usize);
-        if result {
-            1
-        } else {
-            0
-        }
-    }
-
-    fn handle_connect_disconnect(&mut self, client_index: i32, connected: bool) {
-        if connected {
-            log::debug!("client connected: {}", client_index);
-        } else {
-            log::debug!("client disconnected: {}", client_index);
-            unsafe {
-                reliable_endpoint_reset(self.client_endpoint[client_index as usize]);
-            }
-            self.client_connection[client_index as usize].reset();
-            if let Some(network_simulator) = &mut self.network_simulator {
-                network_simulator.discard_client_packets(client_index as usize);
-            }
-        }
-    }
-}
-
-impl<M: NetworkMessage> Drop for ServerRuntime<M> {
-    fn drop(&mut self) {
-        unsafe {
-            if !self.server.is_null() {
-                netcode_server_stop(self.server);
-                netcode_server_destroy(self.server);
-                self.server = null_mut();
-            }
-
-            for endpoint in &mut self.client_endpoint {
-                reliable_endpoint_destroy(*endpoint);
-                *endpoint = null_mut();
-            }
-        }
-    }
-}
-
-fn send_packets<M: NetworkMessage>(config: &ClientServerConfig, runtime: *mut ServerRuntime<M>) {
-    if runtime.is_null() {
-        return;
-    }
-
-    unsafe {
-        let nc_server = (*runtime).server;
-        assert!(!nc_server.is_null());
-
-        for client_index in 0..(*runtime).client_connection.len() {
-            let endpoint = (*runtime).client_endpoint[client_index];
-
-            assert!(!endpoint.is_null());
-
-            if !is_client_connected(nc_server, client_index) {
-                continue;
-            }
-
-            let packet_sequence = reliable_endpoint_next_packet_sequence(endpoint);
-
-            let written_bytes = {
-                // SAFETY: taking references is fine here - no callbacks are firing
-                let packet_buffer = &mut (*runtime).packet_buffer;
-                let connection = &mut (*runtime).client_connection[client_index];
-                assert_eq!(packet_buffer.len(), config.connection.max_packet_size);
-
-                connection.generate_packet(packet_sequence, &mut packet_buffer[..])
-            };
-
-            assert!(written_bytes <= config.connection.max_packet_size);
-
-            if written_bytes > 0 {
-                // SAFETY: the send_packet causes the transmit_packet to
-                // fire, which mutably aliases `runtime`
-                reliable_endpoint_send_packet(
-                    endpoint,
-                    (*runtime).packet_buffer.as_mut_ptr(),
-                    written_bytes as i32,
-                );
-            }
-        }
-    }
-}
-
-fn receive_packets<M: NetworkMessage>(runtime: *mut ServerRuntime<M>) {
-    if runtime.is_null() {
-        return;
-    }
-
-    unsafe {
-        assert!(!(*runtime).server.is_null());
-
-        let endpoints = (*runtime).client_endpoint.iter().enumerate();
-        let nc_server = (*runtime).server;
-
-        for (client_index, endpoint) in endpoints {
-            assert!(!endpoint.is_null());
-
-            loop {
-                let mut packet_bytes: i32 = 0;
-                let mut packet_sequence: u64 = 0;
-                let packet_data = netcode_server_receive_packet(
-                    nc_server,
-                    client_index as i32,
-                    &mut packet_bytes,
-                    &mut packet_sequence,
-                );
-
-                if packet_data.is_null() {
-                    break;
+        transport: &mut T,
+        events: &mut VecDeque<ServerEvent>,
+    ) {
+        while let Some(event) = transport.poll_event() {
+            match event {
+                TransportEvent::ClientConnected(client_index) => {
+                    log::debug!("client connected: {}", client_index);
+                    // the client may have left again before we got here
+                    let Some(client_id) = transport.client_id(client_index) else { continue };
+                    self.client_announced[client_index] = true;
+                    events.push_back(ServerEvent::ClientConnected {
+                        client_index,
+                        client_id,
+                        address: transport.client_socket_address(client_index),
+                    });
                 }
-
-                // SAFETY: receive_packet causes process_packet to fire, which
-                // mutably aliases `runtime`
-                reliable_endpoint_receive_packet(*endpoint, packet_data, packet_bytes);
-                netcode_server_free_packet(nc_server, packet_data.cast());
-            }
-        }
-    }
-}
-
-fn advance_time<M: NetworkMessage>(runtime: *mut ServerRuntime<M>, new_time: f64) {
-    if runtime.is_null() {
-        return;
-    }
-
-    unsafe {
-        let nc_server = (*runtime).server;
-        assert!(!nc_server.is_null());
-
-        netcode_server_update(nc_server, new_time);
-
-        for client_index in 0..(*runtime).max_clients {
-            let connection = &mut (*runtime).client_connection[client_index];
-            let endpoint = (*runtime).client_endpoint[client_index];
-
-            connection.advance_time(new_time);
-
-            if connection.error_level() != ConnectionErrorLevel::None {
-                log::error!(
-                    "client {} connection is in error state. disconnecting client",
-                    client_index
-                );
-                disconnect_client(nc_server, client_index, endpoint, connection);
-                continue;
-            }
-
-            reliable_endpoint_update(endpoint, new_time);
-            let mut num_acks = 0;
-            let acks = reliable_endpoint_get_acks(endpoint, &mut num_acks);
-            connection.process_acks(acks, num_acks);
-            reliable_endpoint_clear_acks(endpoint);
-
-            if let Some(network_simulator) = &mut (*runtime).network_simulator {
-                network_simulator.advance_time(new_time);
-            }
-        }
-
-        if let Some(network_simulator) = &mut (*runtime).network_simulator {
-            if network_simulator.active() {
-                for (client_index, packet_data) in network_simulator.receive_packets() {
-                    netcode_server_send_packet(
-                        nc_server,
-                        client_index as i32,
-                        packet_data.as_ptr(),
-                        packet_data.len() as i32,
-                    );
+                TransportEvent::ClientDisconnected(client_index) => {
+                    log::debug!("client disconnected: {}", client_index);
+                    self.client_endpoint[client_index].reset();
+                    self.client_local[client_index] = false;
+                    self.client_connection[client_index].reset();
+                    if let Some(network_simulator) = &mut self.network_simulator {
+                        network_simulator.discard_client_packets(client_index);
+                    }
+
+                    let reason = self.client_disconnect_reason[client_index]
+                        .take()
+                        .unwrap_or(DisconnectReason::Disconnected);
+                    if std::mem::take(&mut self.client_announced[client_index]) {
+                        events.push_back(ServerEvent::ClientDisconnected {
+                            client_index,
+                            reason,
+                        });
+                    }
                 }
             }
         }
     }
 }
-
-/// Create a netcode server.
-///
-/// # Panics
-///
-/// If address contains a null byte.
-///
-/// # Safety
-///
-/// `callback_context` must be a valid, non-null server runtime.
-unsafe fn netcode_server<M: NetworkMessage>(
-    config: &ClientServerConfig,
-    private_key: &[u8; PRIVATE_KEY_BYTES],
-    callback_context: *mut ServerRuntime<M>,
-    address: &str,
-    time: f64,
-) -> *mut netcode_server_t {
-    let mut netcode_config =
-        gf_init_default!(netcode_server_config_t, netcode_default_server_config);
-    netcode_config.protocol_id = config.protocol_id;
-    netcode_config.private_key.copy_from_slice(private_key);
-
-    // do not override `netcode`'s default allocator
-    // netcode_config.allocator_context = null_mut();
-    // netcode_config.allocate_function = None;
-    // netcode_config.free_function = None;
-
-    assert!(!callback_context.is_null());
-    netcode_config.callback_context = callback_context.cast();
-    netcode_config.connect_disconnect_callback = Some(connect_disconnect_callback::<M>);
-    netcode_config.send_loopback_packet_callback = None; // TODO
-
-    let server_address = CString::new(address).unwrap();
-
-    netcode_server_create(server_address.as_ptr() as *mut _, &netcode_config, time)
-}
-
-unsafe fn disconnect_client<M>(
-    server: *mut netcode_server_t,
-    client_index: usize,
-    endpoint: *mut reliable_endpoint_t,
-    _connection: &mut Connection<M>,
-) {
-    // TODO: on disconnect, clear send queue https://github.com/networkprotocol/yojimbo/issues/129
-    assert!(!server.is_null());
-    assert!(!endpoint.is_null());
-    netcode_server_disconnect_client(server, client_index as _);
-}
-
-unsafe extern "C" fn transmit_packet<M: NetworkMessage>(
-    context: *mut c_void,
-    index: i32,
-    packet_sequence: u16,
-    packet_data: *mut u8,
-    packet_bytes: i32,
-) {
-    let runtime: *mut ServerRuntime<M> = context.cast();
-    runtime
-        .as_mut()
-        .unwrap()
-        .transmit_packet(index, packet_sequence, packet_data, packet_bytes);
-}
-
-unsafe extern "C" fn process_packet<M: NetworkMessage>(
-    context: *mut c_void,
-    index: i32,
-    packet_sequence: u16,
-    packet_data: *mut u8,
-    packet_bytes: i32,
-) -> i32 {
-    let runtime: *mut ServerRuntime<M> = context.cast();
-    runtime
-        .as_mut()
-        .unwrap()
-        .process_packet(index, packet_sequence, packet_data, packet_bytes)
-}
-
-unsafe extern "C" fn connect_disconnect_callback<M: NetworkMessage>(
-    context: *mut c_void,
-    client_index: i32,
-    connected: i32,
-) {
-    let runtime: *mut ServerRuntime<M> = context.cast();
-    runtime
-        .as_mut()
-        .unwrap()
-        .handle_connect_disconnect(client_index, connected == 1);
-}
-
-unsafe fn is_client_connected(server: *mut netcode_server_t, client_index: usize) -> bool {
-    netcode_server_client_connected(server, client_index as i32) != 0
-}
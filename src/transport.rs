@@ -0,0 +1,87 @@
+//! Network backends for `Client` and `Server`.
+//!
+//! The client and server only need to send and receive packets and know which
+//! clients are connected; everything else (channels, acks, fragmentation) is
+//! done on top of the transport. `netcode` is the default transport, and
+//! `MemoryNetwork` connects clients and servers in memory (e.g. for tests).
+
+use std::net::SocketAddr;
+
+use crate::client::ClientState;
+
+mod memory;
+mod netcode;
+
+pub use self::memory::{MemoryClientTransport, MemoryNetwork, MemoryTransport};
+pub use self::netcode::{NetcodeClientTransport, NetcodeTransport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportEvent {
+    /// A client connected in slot `client_index`.
+    ClientConnected(usize),
+    /// The client in slot `client_index` disconnected, timed out or was disconnected by the server.
+    ClientDisconnected(usize),
+}
+
+/// The server side of a network backend, which hands out a slot (client index) to each connected client.
+pub trait Transport {
+    /// Start accepting up to `max_clients` clients.
+    ///
+    /// If the transport fails to start, `running` returns false afterwards.
+    fn start(&mut self, max_clients: usize, time: f64);
+
+    /// Disconnect all clients and stop accepting new ones.
+    fn stop(&mut self);
+
+    fn running(&self) -> bool;
+
+    /// Maximum number of clients, or 0 if the transport isn't running.
+    fn max_clients(&self) -> usize;
+
+    /// Called from `Server::advance_time` before the client connections are updated.
+    fn update(&mut self, time: f64);
+
+    /// Take the next connect or disconnect event, in the order they happened.
+    fn poll_event(&mut self) -> Option<TransportEvent>;
+
+    fn is_client_connected(&self, client_index: usize) -> bool;
+
+    /// The id of the client in slot `client_index`, or None if it's not connected.
+    fn client_id(&self, client_index: usize) -> Option<u64>;
+
+    /// The address of the client in slot `client_index`, if it's connected over a network.
+    fn client_socket_address(&self, client_index: usize) -> Option<SocketAddr>;
+
+    fn connected_client_count(&self) -> usize;
+
+    fn disconnect_client(&mut self, client_index: usize);
+
+    fn send_packet(&mut self, client_index: usize, packet_data: &[u8]);
+
+    /// Call `f` with the next packet received from `client_index`.
+    ///
+    /// Returns false if there are no more packets.
+    fn receive_packet<F: FnOnce(&[u8])>(&mut self, client_index: usize, f: F) -> bool;
+}
+
+/// The client side of a network backend.
+pub trait ClientTransport {
+    /// Whatever the transport needs to find and join a server (e.g. netcode's connect token).
+    type ConnectToken;
+
+    fn connect(&mut self, connect_token: Self::ConnectToken, time: f64);
+
+    fn disconnect(&mut self);
+
+    /// Called from `Client::advance_time` while connecting or connected.
+    fn update(&mut self, time: f64);
+
+    fn state(&self) -> ClientState;
+
+    fn send_packet(&mut self, packet_data: &[u8]);
+
+    /// Call `f` with the next packet received from the server.
+    ///
+    /// Returns false if there are no more packets.
+    fn receive_packet<F: FnOnce(&[u8])>(&mut self, f: F) -> bool;
+}
@@ -0,0 +1,327 @@
+use std::{cell::RefCell, collections::VecDeque, net::SocketAddr, rc::Rc};
+
+use crate::client::{ClientError, ClientState};
+
+use super::{ClientTransport, Transport, TransportEvent};
+
+/// An in-memory network connecting one `MemoryTransport` server to any number
+/// of `MemoryClientTransport` clients.
+///
+/// Packets are never dropped or reordered (use the network simulator for
+/// that), and connecting takes one server update. Useful for tests.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    shared: Rc<RefCell<Network>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// A server transport for this network. There should only be one.
+    pub fn server_transport(&self) -> MemoryTransport {
+        MemoryTransport {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// A new client transport for this network.
+    pub fn client_transport(&self) -> MemoryClientTransport {
+        let mut network = self.shared.borrow_mut();
+        let handle = network.client_states.len();
+        network.client_states.push(ClientState::Disconnected);
+        MemoryClientTransport {
+            shared: self.shared.clone(),
+            handle,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Network {
+    running: bool,
+    /// One entry per client index while the server is running.
+    slots: Vec<Option<Slot>>,
+    /// Client handle and client id of each client waiting to connect.
+    connect_requests: VecDeque<(usize, u64)>,
+    /// State of each client transport, by handle.
+    client_states: Vec<ClientState>,
+    events: VecDeque<TransportEvent>,
+}
+
+struct Slot {
+    handle: usize,
+    client_id: u64,
+    to_server: VecDeque<Vec<u8>>,
+    to_client: VecDeque<Vec<u8>>,
+}
+
+impl Network {
+    fn client_index(&self, handle: usize) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| matches!(slot, Some(slot) if slot.handle == handle))
+    }
+
+    fn disconnect_client(&mut self, client_index: usize) {
+        let Some(slot) = self.slots[client_index].take() else { return };
+        self.client_states[slot.handle] = ClientState::Disconnected;
+        self.events
+            .push_back(TransportEvent::ClientDisconnected(client_index));
+    }
+}
+
+/// The server side of a `MemoryNetwork`.
+pub struct MemoryTransport {
+    shared: Rc<RefCell<Network>>,
+}
+
+impl Transport for MemoryTransport {
+    fn start(&mut self, max_clients: usize, _time: f64) {
+        self.stop();
+
+        let mut network = self.shared.borrow_mut();
+        network.running = true;
+        network.slots.resize_with(max_clients, || None);
+        network.events.clear();
+    }
+
+    fn stop(&mut self) {
+        let mut network = self.shared.borrow_mut();
+        if !network.running {
+            return;
+        }
+        for client_index in 0..network.slots.len() {
+            network.disconnect_client(client_index);
+        }
+        network.running = false;
+        network.slots.clear();
+    }
+
+    fn running(&self) -> bool {
+        self.shared.borrow().running
+    }
+
+    fn max_clients(&self) -> usize {
+        self.shared.borrow().slots.len()
+    }
+
+    fn update(&mut self, _time: f64) {
+        let mut network = self.shared.borrow_mut();
+        if !network.running {
+            return;
+        }
+
+        while let Some((handle, client_id)) = network.connect_requests.pop_front() {
+            let Some(client_index) = network.slots.iter().position(Option::is_none) else {
+                log::debug!("memory transport is full. denying client {}", client_id);
+                network.client_states[handle] = ClientState::Error(ClientError::ConnectionDenied);
+                continue;
+            };
+            network.slots[client_index] = Some(Slot {
+                handle,
+                client_id,
+                to_server: VecDeque::new(),
+                to_client: VecDeque::new(),
+            });
+            network.client_states[handle] = ClientState::Connected;
+            network
+                .events
+                .push_back(TransportEvent::ClientConnected(client_index));
+        }
+    }
+
+    fn poll_event(&mut self) -> Option<TransportEvent> {
+        self.shared.borrow_mut().events.pop_front()
+    }
+
+    fn is_client_connected(&self, client_index: usize) -> bool {
+        matches!(self.shared.borrow().slots.get(client_index), Some(Some(_)))
+    }
+
+    fn client_id(&self, client_index: usize) -> Option<u64> {
+        let network = self.shared.borrow();
+        let slot = network.slots.get(client_index)?.as_ref()?;
+        Some(slot.client_id)
+    }
+
+    fn client_socket_address(&self, _client_index: usize) -> Option<SocketAddr> {
+        None
+    }
+
+    fn connected_client_count(&self) -> usize {
+        let network = self.shared.borrow();
+        network.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    fn disconnect_client(&mut self, client_index: usize) {
+        self.shared.borrow_mut().disconnect_client(client_index);
+    }
+
+    fn send_packet(&mut self, client_index: usize, packet_data: &[u8]) {
+        let mut network = self.shared.borrow_mut();
+        if let Some(Some(slot)) = network.slots.get_mut(client_index) {
+            slot.to_client.push_back(packet_data.to_vec());
+        }
+    }
+
+    fn receive_packet<F: FnOnce(&[u8])>(&mut self, client_index: usize, f: F) -> bool {
+        let packet_data = {
+            let mut network = self.shared.borrow_mut();
+            let Some(Some(slot)) = network.slots.get_mut(client_index) else { return false };
+            let Some(packet_data) = slot.to_server.pop_front() else { return false };
+            packet_data
+        };
+        f(&packet_data);
+        true
+    }
+}
+
+/// The client side of a `MemoryNetwork`. The connect token is the client id.
+pub struct MemoryClientTransport {
+    shared: Rc<RefCell<Network>>,
+    /// Identifies this client in the network.
+    handle: usize,
+}
+
+impl ClientTransport for MemoryClientTransport {
+    type ConnectToken = u64;
+
+    fn connect(&mut self, client_id: u64, _time: f64) {
+        self.disconnect();
+
+        let mut network = self.shared.borrow_mut();
+        network.connect_requests.push_back((self.handle, client_id));
+        network.client_states[self.handle] = ClientState::Connecting;
+    }
+
+    fn disconnect(&mut self) {
+        let mut network = self.shared.borrow_mut();
+        let handle = self.handle;
+        network
+            .connect_requests
+            .retain(|(request_handle, _)| *request_handle != handle);
+        if let Some(client_index) = network.client_index(handle) {
+            network.disconnect_client(client_index);
+        }
+        network.client_states[handle] = ClientState::Disconnected;
+    }
+
+    fn update(&mut self, _time: f64) {}
+
+    fn state(&self) -> ClientState {
+        self.shared.borrow().client_states[self.handle]
+    }
+
+    fn send_packet(&mut self, packet_data: &[u8]) {
+        let mut network = self.shared.borrow_mut();
+        let Some(client_index) = network.client_index(self.handle) else { return };
+        if let Some(slot) = &mut network.slots[client_index] {
+            slot.to_server.push_back(packet_data.to_vec());
+        }
+    }
+
+    fn receive_packet<F: FnOnce(&[u8])>(&mut self, f: F) -> bool {
+        let packet_data = {
+            let mut network = self.shared.borrow_mut();
+            let Some(client_index) = network.client_index(self.handle) else { return false };
+            let Some(slot) = &mut network.slots[client_index] else { return false };
+            let Some(packet_data) = slot.to_client.pop_front() else { return false };
+            packet_data
+        };
+        f(&packet_data);
+        true
+    }
+}
+
+impl Drop for MemoryClientTransport {
+    fn drop(&mut self) {
+        self.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn receive_from_server(client: &mut MemoryClientTransport) -> Option<Vec<u8>> {
+        let mut received = None;
+        client.receive_packet(|packet_data| received = Some(packet_data.to_vec()));
+        received
+    }
+
+    fn receive_from_client(server: &mut MemoryTransport, client_index: usize) -> Option<Vec<u8>> {
+        let mut received = None;
+        server.receive_packet(client_index, |packet_data| {
+            received = Some(packet_data.to_vec())
+        });
+        received
+    }
+
+    #[test]
+    fn test_memory_transport() {
+        let network = MemoryNetwork::new();
+        let mut server = network.server_transport();
+        let mut first = network.client_transport();
+        let mut second = network.client_transport();
+        let mut third = network.client_transport();
+
+        server.start(2, 0.0);
+        first.connect(10, 0.0);
+        second.connect(20, 0.0);
+        third.connect(30, 0.0);
+        assert_eq!(first.state(), ClientState::Connecting);
+
+        server.update(0.0);
+        assert_eq!(first.state(), ClientState::Connected);
+        assert_eq!(second.state(), ClientState::Connected);
+        assert_eq!(
+            third.state(),
+            ClientState::Error(ClientError::ConnectionDenied)
+        );
+        assert_eq!(server.connected_client_count(), 2);
+        assert_eq!(server.client_id(0), Some(10));
+        assert_eq!(server.client_id(1), Some(20));
+        assert_eq!(
+            server.poll_event(),
+            Some(TransportEvent::ClientConnected(0))
+        );
+        assert_eq!(
+            server.poll_event(),
+            Some(TransportEvent::ClientConnected(1))
+        );
+        assert_eq!(server.poll_event(), None);
+
+        first.send_packet(&[1, 2, 3]);
+        server.send_packet(1, &[4, 5]);
+        assert_eq!(receive_from_client(&mut server, 0), Some(vec![1, 2, 3]));
+        assert_eq!(receive_from_client(&mut server, 0), None);
+        assert_eq!(receive_from_client(&mut server, 1), None);
+        assert_eq!(receive_from_server(&mut second), Some(vec![4, 5]));
+        assert_eq!(receive_from_server(&mut first), None);
+
+        first.disconnect();
+        assert_eq!(
+            server.poll_event(),
+            Some(TransportEvent::ClientDisconnected(0))
+        );
+        assert!(!server.is_client_connected(0));
+
+        server.disconnect_client(1);
+        assert_eq!(second.state(), ClientState::Disconnected);
+        assert_eq!(
+            server.poll_event(),
+            Some(TransportEvent::ClientDisconnected(1))
+        );
+
+        // the free slot is handed to the next client
+        third.connect(30, 0.0);
+        server.update(0.0);
+        assert_eq!(server.client_id(0), Some(30));
+
+        server.stop();
+        assert_eq!(third.state(), ClientState::Disconnected);
+        assert_eq!(server.max_clients(), 0);
+    }
+}
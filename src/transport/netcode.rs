@@ -0,0 +1,587 @@
+use std::collections::VecDeque;
+use std::ffi::{c_void, CString};
+use std::net::SocketAddr;
+use std::ptr::null_mut;
+use std::slice;
+
+use crate::bindings::*;
+use crate::client::{ClientError, ClientState};
+use crate::{gf_init_default, CONNECT_TOKEN_BYTES, PRIVATE_KEY_BYTES, USER_DATA_BYTES};
+
+use super::{ClientTransport, Transport, TransportEvent};
+
+/// The default server transport, a `netcode` server.
+pub struct NetcodeTransport {
+    private_key: [u8; PRIVATE_KEY_BYTES],
+    address: String,
+    protocol_id: u64,
+
+    /// The netcode server, null when not running.
+    server: *mut netcode_server_t,
+    max_clients: usize,
+    /// The port the netcode server is listening on.
+    bound_port: Option<u16>,
+
+    /// Filled by the netcode callbacks.
+    ///
+    /// This is a raw pointer (from `Box::into_raw`) so the callback context
+    /// handed to netcode stays valid when the transport moves.
+    callbacks: *mut ServerCallbacks,
+}
+
+#[derive(Default)]
+struct ServerCallbacks {
+    events: VecDeque<TransportEvent>,
+    /// Packets sent to loopback clients: client index, packet data and packet sequence.
+    loopback_packets: VecDeque<(usize, Vec<u8>, u64)>,
+}
+
+impl NetcodeTransport {
+    pub fn new(
+        private_key: &[u8; PRIVATE_KEY_BYTES],
+        address: String,
+        protocol_id: u64,
+    ) -> NetcodeTransport {
+        NetcodeTransport {
+            private_key: *private_key,
+            address,
+            protocol_id,
+            server: null_mut(),
+            max_clients: 0,
+            bound_port: None,
+            callbacks: Box::into_raw(Box::default()),
+        }
+    }
+
+    /// The port the server is listening on, or None if it is not running.
+    pub fn bound_port(&self) -> Option<u16> {
+        self.bound_port
+    }
+
+    pub fn client_address(&self, client_index: usize) -> Option<NetcodeAddress> {
+        if !self.is_client_connected(client_index) {
+            return None;
+        }
+        unsafe {
+            let raw = netcode_server_client_address(self.server, client_index as i32);
+            if raw.is_null() {
+                None
+            } else {
+                Some(NetcodeAddress::new(raw))
+            }
+        }
+    }
+
+    /// The user data from the connect token of client `client_index`.
+    pub fn client_user_data(&self, client_index: usize) -> Option<&[u8; USER_DATA_BYTES]> {
+        if !self.is_client_connected(client_index) {
+            return None;
+        }
+        // SAFETY: the user data lives in the netcode server, which outlives the borrow of self
+        unsafe {
+            let raw = netcode_server_client_user_data(self.server, client_index as i32);
+            (raw as *const [u8; USER_DATA_BYTES]).as_ref()
+        }
+    }
+
+    /// Connect a client in slot `client_index` which exchanges packets in
+    /// process instead of over the network (see `NetcodeClientTransport::connect_loopback`).
+    ///
+    /// Packets sent to the client are queued, see `receive_loopback_packet`.
+    pub fn connect_loopback_client(
+        &mut self,
+        client_index: usize,
+        client_id: u64,
+        user_data: &[u8; USER_DATA_BYTES],
+    ) {
+        assert!(self.running());
+        assert!(client_index < self.max_clients);
+        assert!(!self.is_client_connected(client_index));
+        unsafe {
+            netcode_server_connect_loopback_client(
+                self.server,
+                client_index as i32,
+                client_id,
+                user_data.as_ptr(),
+            )
+        };
+    }
+
+    pub fn disconnect_loopback_client(&mut self, client_index: usize) {
+        if !self.is_loopback_client(client_index) {
+            return;
+        }
+        unsafe {
+            netcode_server_disconnect_loopback_client(self.server, client_index as i32);
+            (*self.callbacks)
+                .loopback_packets
+                .retain(|(index, _, _)| *index != client_index);
+        }
+    }
+
+    pub fn is_loopback_client(&self, client_index: usize) -> bool {
+        self.is_client_connected(client_index)
+            && unsafe { netcode_server_client_loopback(self.server, client_index as i32) != 0 }
+    }
+
+    /// Call `f` with the next packet sent to loopback client `client_index`, and its sequence.
+    ///
+    /// Returns false if there are no more packets.
+    pub fn receive_loopback_packet<F: FnOnce(&[u8], u64)>(
+        &mut self,
+        client_index: usize,
+        f: F,
+    ) -> bool {
+        // SAFETY: netcode only touches `callbacks` inside netcode calls, which can't be running now
+        let loopback_packets = unsafe { &mut (*self.callbacks).loopback_packets };
+        let Some(position) = loopback_packets
+            .iter()
+            .position(|(index, _, _)| *index == client_index)
+        else {
+            return false;
+        };
+        let (_, packet_data, packet_sequence) = loopback_packets.remove(position).unwrap();
+        f(&packet_data, packet_sequence);
+        true
+    }
+
+    /// Hand the server a packet sent by loopback client `client_index`.
+    pub fn process_loopback_packet(
+        &mut self,
+        client_index: usize,
+        packet_data: &[u8],
+        packet_sequence: u64,
+    ) {
+        if !self.is_loopback_client(client_index) {
+            return;
+        }
+        unsafe {
+            netcode_server_process_loopback_packet(
+                self.server,
+                client_index as i32,
+                packet_data.as_ptr(),
+                packet_data.len() as i32,
+                packet_sequence,
+            )
+        };
+    }
+}
+
+impl Transport for NetcodeTransport {
+    fn start(&mut self, max_clients: usize, time: f64) {
+        assert!(max_clients < i32::MAX as usize);
+
+        self.stop();
+
+        let mut netcode_config =
+            gf_init_default!(netcode_server_config_t, netcode_default_server_config);
+        netcode_config.protocol_id = self.protocol_id;
+        netcode_config
+            .private_key
+            .copy_from_slice(&self.private_key);
+
+        // do not override `netcode`'s default allocator
+        // netcode_config.allocator_context = null_mut();
+        // netcode_config.allocate_function = None;
+        // netcode_config.free_function = None;
+
+        netcode_config.callback_context = self.callbacks.cast();
+        netcode_config.connect_disconnect_callback = Some(connect_disconnect_callback);
+        netcode_config.send_loopback_packet_callback = Some(server_send_loopback_packet_callback);
+
+        let server_address = CString::new(self.address.as_str()).unwrap();
+
+        unsafe {
+            *self.callbacks = ServerCallbacks::default();
+
+            let server =
+                netcode_server_create(server_address.as_ptr() as *mut _, &netcode_config, time);
+            if server.is_null() {
+                log::error!("failed to create netcode server on {}", self.address);
+                return;
+            }
+
+            netcode_server_start(server, max_clients as i32);
+            self.bound_port = Some(netcode_server_get_port(server));
+            self.server = server;
+        }
+        self.max_clients = max_clients;
+    }
+
+    fn stop(&mut self) {
+        if self.server.is_null() {
+            return;
+        }
+        for client_index in 0..self.max_clients {
+            self.disconnect_loopback_client(client_index);
+        }
+        unsafe {
+            netcode_server_stop(self.server);
+            netcode_server_destroy(self.server);
+        }
+        self.server = null_mut();
+        self.max_clients = 0;
+        self.bound_port = None;
+    }
+
+    fn running(&self) -> bool {
+        !self.server.is_null()
+    }
+
+    fn max_clients(&self) -> usize {
+        self.max_clients
+    }
+
+    fn update(&mut self, time: f64) {
+        if self.running() {
+            unsafe { netcode_server_update(self.server, time) };
+        }
+    }
+
+    fn poll_event(&mut self) -> Option<TransportEvent> {
+        // SAFETY: netcode only touches `callbacks` inside netcode calls, which can't be running now
+        unsafe { (*self.callbacks).events.pop_front() }
+    }
+
+    fn is_client_connected(&self, client_index: usize) -> bool {
+        self.running()
+            && client_index < self.max_clients
+            && unsafe { netcode_server_client_connected(self.server, client_index as i32) != 0 }
+    }
+
+    fn client_id(&self, client_index: usize) -> Option<u64> {
+        if self.is_client_connected(client_index) {
+            Some(unsafe { netcode_server_client_id(self.server, client_index as i32) })
+        } else {
+            None
+        }
+    }
+
+    fn client_socket_address(&self, client_index: usize) -> Option<SocketAddr> {
+        self.client_address(client_index)?.to_socket_addr()
+    }
+
+    fn connected_client_count(&self) -> usize {
+        if !self.running() {
+            return 0;
+        }
+        let count = unsafe { netcode_server_num_connected_clients(self.server) };
+        assert!(count >= 0);
+        count as usize
+    }
+
+    fn disconnect_client(&mut self, client_index: usize) {
+        assert!(self.running());
+        if self.is_loopback_client(client_index) {
+            // netcode doesn't allow disconnecting loopback clients like other clients
+            self.disconnect_loopback_client(client_index);
+            return;
+        }
+        unsafe { netcode_server_disconnect_client(self.server, client_index as i32) };
+    }
+
+    fn send_packet(&mut self, client_index: usize, packet_data: &[u8]) {
+        assert!(self.running());
+        unsafe {
+            netcode_server_send_packet(
+                self.server,
+                client_index as i32,
+                packet_data.as_ptr(),
+                packet_data.len() as i32,
+            );
+        }
+    }
+
+    fn receive_packet<F: FnOnce(&[u8])>(&mut self, client_index: usize, f: F) -> bool {
+        if !self.running() {
+            return false;
+        }
+
+        let mut packet_bytes: i32 = 0;
+        let mut packet_sequence: u64 = 0;
+        let packet_data = unsafe {
+            netcode_server_receive_packet(
+                self.server,
+                client_index as i32,
+                &mut packet_bytes,
+                &mut packet_sequence,
+            )
+        };
+        if packet_data.is_null() {
+            return false;
+        }
+
+        {
+            // SAFETY: netcode hands us `packet_bytes` initialized bytes, valid until freed
+            let packet_data = unsafe { slice::from_raw_parts(packet_data, packet_bytes as usize) };
+            f(packet_data);
+        }
+        unsafe { netcode_server_free_packet(self.server, packet_data.cast()) };
+
+        true
+    }
+}
+
+impl Drop for NetcodeTransport {
+    fn drop(&mut self) {
+        self.stop();
+        unsafe { drop(Box::from_raw(self.callbacks)) };
+    }
+}
+
+/// The default client transport, a `netcode` client.
+pub struct NetcodeClientTransport {
+    address: String,
+    /// The netcode client, null when disconnected.
+    client: *mut netcode_client_t,
+    bound_port: Option<u16>,
+
+    /// Packets sent to the server while connected in loopback mode, and their sequence.
+    ///
+    /// A raw pointer (from `Box::into_raw`) for the same reason as `NetcodeTransport::callbacks`.
+    loopback_packets: *mut VecDeque<(Vec<u8>, u64)>,
+}
+
+impl NetcodeClientTransport {
+    /// Create a transport which binds to `address` when connecting.
+    pub fn new(address: String) -> NetcodeClientTransport {
+        NetcodeClientTransport {
+            address,
+            client: null_mut(),
+            bound_port: None,
+            loopback_packets: Box::into_raw(Box::default()),
+        }
+    }
+
+    /// The port the client is bound to, or None if it is disconnected.
+    pub fn bound_port(&self) -> Option<u16> {
+        self.bound_port
+    }
+
+    /// Connect to a server in the same process as its loopback client `client_index`
+    /// (see `NetcodeTransport::connect_loopback_client`).
+    ///
+    /// The client is connected straight away. Packets sent to the server are queued,
+    /// see `receive_loopback_packet`.
+    pub fn connect_loopback(&mut self, client_index: usize, max_clients: usize, time: f64) {
+        assert!(client_index < max_clients);
+
+        if !self.create_client(time) {
+            return;
+        }
+        unsafe {
+            netcode_client_connect_loopback(self.client, client_index as i32, max_clients as i32)
+        };
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        !self.client.is_null() && unsafe { netcode_client_loopback(self.client) != 0 }
+    }
+
+    /// Call `f` with the next packet sent to the server in loopback mode, and its sequence.
+    ///
+    /// Returns false if there are no more packets.
+    pub fn receive_loopback_packet<F: FnOnce(&[u8], u64)>(&mut self, f: F) -> bool {
+        // SAFETY: netcode only touches `loopback_packets` inside netcode calls, which can't be running now
+        let Some((packet_data, packet_sequence)) =
+            (unsafe { (*self.loopback_packets).pop_front() })
+        else {
+            return false;
+        };
+        f(&packet_data, packet_sequence);
+        true
+    }
+
+    /// Hand the client a packet sent by the server in loopback mode.
+    pub fn process_loopback_packet(&mut self, packet_data: &[u8], packet_sequence: u64) {
+        if !self.is_loopback() {
+            return;
+        }
+        unsafe {
+            netcode_client_process_loopback_packet(
+                self.client,
+                packet_data.as_ptr(),
+                packet_data.len() as i32,
+                packet_sequence,
+            )
+        };
+    }
+
+    /// Create the netcode client, returns false on failure.
+    fn create_client(&mut self, time: f64) -> bool {
+        self.disconnect();
+
+        let mut netcode_config =
+            gf_init_default!(netcode_client_config_t, netcode_default_client_config);
+        netcode_config.callback_context = self.loopback_packets.cast();
+        netcode_config.state_change_callback = Some(state_change_callback);
+        netcode_config.send_loopback_packet_callback = Some(client_send_loopback_packet_callback);
+        let address = CString::new(self.address.as_str()).unwrap();
+        self.client =
+            unsafe { netcode_client_create(address.as_ptr() as *mut i8, &netcode_config, time) };
+
+        if self.client.is_null() {
+            log::error!("failed to create netcode client on {}", self.address);
+            return false;
+        }
+
+        self.bound_port = Some(unsafe { netcode_client_get_port(self.client) });
+        true
+    }
+}
+
+impl ClientTransport for NetcodeClientTransport {
+    type ConnectToken = [u8; CONNECT_TOKEN_BYTES];
+
+    fn connect(&mut self, mut connect_token: Self::ConnectToken, time: f64) {
+        if !self.create_client(time) {
+            return;
+        }
+        unsafe { netcode_client_connect(self.client, connect_token.as_mut_ptr()) };
+    }
+
+    fn disconnect(&mut self) {
+        if self.client.is_null() {
+            return;
+        }
+        self.bound_port = None;
+        unsafe {
+            netcode_client_destroy(self.client);
+            (*self.loopback_packets).clear();
+        }
+        self.client = null_mut();
+    }
+
+    fn update(&mut self, time: f64) {
+        if !self.client.is_null() {
+            unsafe { netcode_client_update(self.client, time) };
+        }
+    }
+
+    fn state(&self) -> ClientState {
+        if self.client.is_null() {
+            return ClientState::Disconnected;
+        }
+        client_state_from_netcode_state(unsafe { netcode_client_state(self.client) })
+    }
+
+    fn send_packet(&mut self, packet_data: &[u8]) {
+        assert!(!self.client.is_null());
+        unsafe {
+            netcode_client_send_packet(self.client, packet_data.as_ptr(), packet_data.len() as i32)
+        };
+    }
+
+    fn receive_packet<F: FnOnce(&[u8])>(&mut self, f: F) -> bool {
+        if self.client.is_null() {
+            return false;
+        }
+
+        let mut packet_bytes: i32 = 0;
+        let mut packet_sequence: u64 = 0;
+        let packet_data = unsafe {
+            netcode_client_receive_packet(self.client, &mut packet_bytes, &mut packet_sequence)
+        };
+        if packet_data.is_null() {
+            return false;
+        }
+
+        {
+            // SAFETY: netcode hands us `packet_bytes` initialized bytes, valid until freed
+            let packet_data = unsafe { slice::from_raw_parts(packet_data, packet_bytes as usize) };
+            f(packet_data);
+        }
+        unsafe { netcode_client_free_packet(self.client, packet_data as *mut _) };
+
+        true
+    }
+}
+
+impl Drop for NetcodeClientTransport {
+    fn drop(&mut self) {
+        self.disconnect();
+        unsafe { drop(Box::from_raw(self.loopback_packets)) };
+    }
+}
+
+fn client_state_from_netcode_state(state: i32) -> ClientState {
+    if state < NETCODE_CLIENT_STATE_DISCONNECTED as i32 {
+        ClientState::Error(client_error_from_netcode_state(state))
+    } else if state == NETCODE_CLIENT_STATE_DISCONNECTED as i32 {
+        ClientState::Disconnected
+    } else if state == NETCODE_CLIENT_STATE_SENDING_CONNECTION_REQUEST as i32
+        || state == NETCODE_CLIENT_STATE_SENDING_CONNECTION_RESPONSE as i32
+    {
+        ClientState::Connecting
+    } else {
+        ClientState::Connected
+    }
+}
+
+fn client_error_from_netcode_state(state: i32) -> ClientError {
+    match state {
+        NETCODE_CLIENT_STATE_CONNECT_TOKEN_EXPIRED => ClientError::ConnectTokenExpired,
+        NETCODE_CLIENT_STATE_INVALID_CONNECT_TOKEN => ClientError::InvalidConnectToken,
+        NETCODE_CLIENT_STATE_CONNECTION_TIMED_OUT => ClientError::ConnectionTimedOut,
+        NETCODE_CLIENT_STATE_CONNECTION_RESPONSE_TIMED_OUT => {
+            ClientError::ConnectionResponseTimedOut
+        }
+        NETCODE_CLIENT_STATE_CONNECTION_REQUEST_TIMED_OUT => ClientError::ConnectionRequestTimedOut,
+        NETCODE_CLIENT_STATE_CONNECTION_DENIED => ClientError::ConnectionDenied,
+        _ => unreachable!("unknown netcode client error state {}", state),
+    }
+}
+
+unsafe extern "C" fn connect_disconnect_callback(
+    context: *mut c_void,
+    client_index: i32,
+    connected: i32,
+) {
+    let client_index = client_index as usize;
+    let event = if connected == 1 {
+        TransportEvent::ClientConnected(client_index)
+    } else {
+        TransportEvent::ClientDisconnected(client_index)
+    };
+    let callbacks: *mut ServerCallbacks = context.cast();
+    callbacks.as_mut().unwrap().events.push_back(event);
+}
+
+unsafe extern "C" fn server_send_loopback_packet_callback(
+    context: *mut c_void,
+    client_index: i32,
+    packet_data: *const u8,
+    packet_bytes: i32,
+    packet_sequence: u64,
+) {
+    let callbacks: *mut ServerCallbacks = context.cast();
+    let packet_data = slice::from_raw_parts(packet_data, packet_bytes as usize).to_vec();
+    callbacks.as_mut().unwrap().loopback_packets.push_back((
+        client_index as usize,
+        packet_data,
+        packet_sequence,
+    ));
+}
+
+unsafe extern "C" fn client_send_loopback_packet_callback(
+    context: *mut c_void,
+    _client_index: i32,
+    packet_data: *const u8,
+    packet_bytes: i32,
+    packet_sequence: u64,
+) {
+    let loopback_packets: *mut VecDeque<(Vec<u8>, u64)> = context.cast();
+    let packet_data = slice::from_raw_parts(packet_data, packet_bytes as usize).to_vec();
+    loopback_packets
+        .as_mut()
+        .unwrap()
+        .push_back((packet_data, packet_sequence));
+}
+
+extern "C" fn state_change_callback(_context: *mut c_void, previous: i32, current: i32) {
+    log::debug!(
+        "client state changed from: {:?} to {:?}",
+        client_state_from_netcode_state(previous),
+        client_state_from_netcode_state(current)
+    );
+}
@@ -2,8 +2,14 @@ use std::{thread, time::Duration};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use yojimbo::{
-    client::Client, config::ClientServerConfig, message::NetworkMessage, server::Server,
-    PRIVATE_KEY_BYTES,
+    channel::SendError,
+    client::{Client, ClientError, ClientEvent, ClientState},
+    config::ClientServerConfig,
+    connect_token::ConnectTokenBuilder,
+    message::NetworkMessage,
+    server::{DisconnectReason, Server, ServerEvent},
+    transport::{ClientTransport, MemoryNetwork, Transport},
+    PRIVATE_KEY_BYTES, USER_DATA_BYTES,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -142,7 +148,452 @@ fn client_server_messages_multiple_channels() {
     server.stop();
 }
 
-fn send_messages_from_client(client: &mut Client<TestMessage>, channel: usize, count: usize) {
+#[test]
+fn client_server_connect_token() {
+    let mut time = 100.0;
+    let delta_time = 1.0 / 30.0;
+    let max_iter = 1000;
+
+    yojimbo::initialize().unwrap();
+
+    let config = ClientServerConfig::new(1);
+    let private_key = [0u8; PRIVATE_KEY_BYTES];
+    let client_id = 1234;
+
+    let mut client = Client::new("0.0.0.0".to_string(), config.clone(), time);
+    let mut server: Server<TestMessage, _> = Server::new(
+        &private_key,
+        "127.0.0.1:40003".to_string(),
+        config.clone(),
+        time,
+    );
+    server.start(1);
+
+    for test_repeat in 0..2 {
+        let mut user_data = [0u8; USER_DATA_BYTES];
+        user_data[..8].copy_from_slice(&(test_repeat as u64 + 1).to_le_bytes());
+
+        if test_repeat == 0 {
+            client.insecure_connect_with_user_data(
+                &private_key,
+                client_id,
+                &["127.0.0.1:40003"],
+                &user_data,
+            );
+        } else {
+            let connect_token =
+                ConnectTokenBuilder::new(&private_key, config.protocol_id, client_id)
+                    .server_address("127.0.0.1:40003")
+                    .user_data(&user_data)
+                    .build()
+                    .unwrap();
+            client.connect(&connect_token);
+        }
+
+        for _ in 0..max_iter {
+            pump_client_server_update(&mut time, &mut [&mut client], &mut server, delta_time);
+            if client.connection_failed()
+                || (client.is_connected() && server.connected_client_count() == 1)
+            {
+                break;
+            }
+        }
+
+        assert!(client.is_connected());
+        assert!(server.is_client_connected(0));
+        assert_eq!(server.client_user_data(0), Some(&user_data));
+
+        client.disconnect();
+
+        for _ in 0..max_iter {
+            pump_client_server_update(&mut time, &mut [&mut client], &mut server, delta_time);
+            if !server.is_client_connected(0) {
+                break;
+            }
+        }
+
+        assert_eq!(server.connected_client_count(), 0);
+        assert_eq!(server.client_user_data(0), None);
+    }
+
+    server.stop();
+}
+
+#[test]
+fn client_server_loopback() {
+    run_loopback_client(false, "127.0.0.1:40001");
+}
+
+#[test]
+fn client_server_local() {
+    run_loopback_client(true, "127.0.0.1:40002");
+}
+
+/// Exchange messages with a loopback (or local) client.
+fn run_loopback_client(local: bool, address: &str) {
+    let mut time = 100.0;
+    let delta_time = 1.0 / 30.0;
+    let max_iter = 1000;
+
+    yojimbo::initialize().unwrap();
+
+    let mut config = ClientServerConfig::new(2);
+    let send_queue_size = 64;
+    for i in 0..2 {
+        config.connection.channels[i].message_send_queue_size = send_queue_size;
+        config.connection.channels[i].max_messages_per_packet = send_queue_size;
+    }
+
+    let private_key = [0u8; PRIVATE_KEY_BYTES];
+    let max_clients = 2;
+    let client_id = 1234;
+
+    let mut server = Server::new(&private_key, address.to_string(), config.clone(), time);
+    server.start(max_clients);
+
+    let mut client = Client::new("0.0.0.0".to_string(), config.clone(), time);
+
+    let user_data = [7u8; USER_DATA_BYTES];
+
+    if local {
+        server.connect_local_client(0, client_id, &user_data);
+        client.connect_local(0, client_id, max_clients);
+    } else {
+        server.connect_loopback_client(0, client_id, &user_data);
+        client.connect_loopback(0, client_id, max_clients);
+    }
+    assert_eq!(server.client_user_data(0), Some(&user_data));
+
+    assert!(client.is_connected());
+    assert!(client.is_loopback());
+    assert_eq!(client.is_local(), local);
+    assert_eq!(server.is_local_client(0), local);
+    assert!(server.is_client_connected(0));
+    assert!(server.is_loopback_client(0));
+    assert_eq!(server.client_id(0), Some(client_id));
+    assert_eq!(server.connected_client_count(), 1);
+
+    for channel in 0..2 {
+        send_messages_from_client(&mut client, channel, send_queue_size);
+        send_messages_from_server(&mut server, 0, channel, send_queue_size);
+    }
+
+    let mut client_received = [0, 0];
+    let mut server_received = [0, 0];
+
+    for _ in 0..max_iter {
+        client.send_packets();
+        server.send_packets();
+
+        server.pump_loopback_client(&mut client);
+
+        client.receive_packets();
+        server.receive_packets();
+
+        time += delta_time;
+        client.advance_time(time);
+        server.advance_time(time);
+
+        for channel in 0..2 {
+            receive_messages_from_server(&mut client, channel, &mut client_received[channel]);
+            receive_messages_from_client(&mut server, 0, channel, &mut server_received[channel]);
+        }
+
+        if client_received == [send_queue_size as u64; 2]
+            && server_received == [send_queue_size as u64; 2]
+        {
+            break;
+        }
+    }
+
+    assert!(client.is_connected());
+    assert_eq!(client_received, [send_queue_size as u64; 2]);
+    assert_eq!(server_received, [send_queue_size as u64; 2]);
+
+    server.disconnect_loopback_client(0);
+    client.disconnect();
+
+    assert!(client.is_disconnected());
+    assert!(!server.is_client_connected(0));
+    assert_eq!(server.connected_client_count(), 0);
+
+    server.stop();
+}
+
+#[test]
+fn client_server_memory_transport() {
+    let mut time = 100.0;
+    let delta_time = 1.0 / 30.0;
+    let max_iter = 1000;
+
+    let mut config = ClientServerConfig::new(2);
+    let send_queue_size = 64;
+    for i in 0..2 {
+        config.connection.channels[i].message_send_queue_size = send_queue_size;
+        config.connection.channels[i].max_messages_per_packet = send_queue_size;
+    }
+
+    let network = MemoryNetwork::new();
+    let mut server = Server::with_transport(network.server_transport(), config.clone(), time);
+    let mut clients = [
+        Client::with_transport(network.client_transport(), config.clone(), time),
+        Client::with_transport(network.client_transport(), config.clone(), time),
+    ];
+
+    server.start(2);
+
+    for (client_id, client) in clients.iter_mut().enumerate() {
+        client.connect_with(client_id as u64 + 1);
+        assert!(!client.is_disconnected());
+    }
+
+    // the server accepts the clients, then the clients see they are connected
+    for _ in 0..2 {
+        let [first, second] = &mut clients;
+        update_client_server(&mut time, &mut [first, second], &mut server, delta_time);
+    }
+
+    assert!(clients.iter().all(|client| client.is_connected()));
+    assert_eq!(server.connected_client_count(), 2);
+    assert_eq!(server.client_id(0), Some(1));
+    assert_eq!(server.client_id(1), Some(2));
+    for client_index in 0..2 {
+        assert_eq!(
+            server.poll_event(),
+            Some(ServerEvent::ClientConnected {
+                client_index,
+                client_id: client_index as u64 + 1,
+                address: None,
+            })
+        );
+    }
+    assert_eq!(server.poll_event(), None);
+
+    for channel in 0..2 {
+        for (client_index, client) in clients.iter_mut().enumerate() {
+            send_messages_from_client(client, channel, send_queue_size);
+            send_messages_from_server(&mut server, client_index, channel, send_queue_size);
+        }
+    }
+
+    let mut client_received = [[0, 0], [0, 0]];
+    let mut server_received = [[0, 0], [0, 0]];
+
+    for _ in 0..max_iter {
+        let [first, second] = &mut clients;
+        update_client_server(&mut time, &mut [first, second], &mut server, delta_time);
+
+        for channel in 0..2 {
+            for (client_index, client) in clients.iter_mut().enumerate() {
+                receive_messages_from_server(
+                    client,
+                    channel,
+                    &mut client_received[client_index][channel],
+                );
+                receive_messages_from_client(
+                    &mut server,
+                    client_index,
+                    channel,
+                    &mut server_received[client_index][channel],
+                );
+            }
+        }
+
+        let expected = [[send_queue_size as u64; 2]; 2];
+        if client_received == expected && server_received == expected {
+            break;
+        }
+    }
+
+    assert_eq!(client_received, [[send_queue_size as u64; 2]; 2]);
+    assert_eq!(server_received, [[send_queue_size as u64; 2]; 2]);
+
+    clients[0].disconnect();
+    let [first, second] = &mut clients;
+    update_client_server(&mut time, &mut [first, second], &mut server, delta_time);
+    assert!(!server.is_client_connected(0));
+    assert!(server.is_client_connected(1));
+    assert_eq!(
+        server.poll_event(),
+        Some(ServerEvent::ClientDisconnected {
+            client_index: 0,
+            reason: DisconnectReason::Disconnected,
+        })
+    );
+
+    server.stop();
+    assert_eq!(
+        server.poll_event(),
+        Some(ServerEvent::ClientDisconnected {
+            client_index: 1,
+            reason: DisconnectReason::ServerStopped,
+        })
+    );
+    assert_eq!(server.poll_event(), None);
+    let [first, second] = &mut clients;
+    update_client_server(&mut time, &mut [first, second], &mut server, delta_time);
+    assert!(clients[1].is_disconnected());
+}
+
+#[test]
+fn client_events_memory_transport() {
+    let mut time = 100.0;
+    let delta_time = 1.0 / 30.0;
+    let config = ClientServerConfig::new(1);
+
+    let network = MemoryNetwork::new();
+    let mut server: Server<TestMessage, _> =
+        Server::with_transport(network.server_transport(), config.clone(), time);
+    let mut first = Client::with_transport(network.client_transport(), config.clone(), time);
+    let mut second = Client::with_transport(network.client_transport(), config.clone(), time);
+
+    server.start(1);
+    first.connect_with(1);
+    second.connect_with(2);
+    for _ in 0..2 {
+        update_client_server(
+            &mut time,
+            &mut [&mut first, &mut second],
+            &mut server,
+            delta_time,
+        );
+    }
+
+    assert!(first.is_connected());
+    assert_eq!(
+        first.poll_event(),
+        Some(ClientEvent::StateChanged {
+            previous: ClientState::Disconnected,
+            current: ClientState::Connecting,
+        })
+    );
+    assert_eq!(
+        first.poll_event(),
+        Some(ClientEvent::StateChanged {
+            previous: ClientState::Connecting,
+            current: ClientState::Connected,
+        })
+    );
+    assert_eq!(first.poll_event(), None);
+
+    // the server is full
+    assert!(second.connection_failed());
+    assert_eq!(second.error(), Some(ClientError::ConnectionDenied));
+    assert_eq!(
+        second.poll_event(),
+        Some(ClientEvent::StateChanged {
+            previous: ClientState::Disconnected,
+            current: ClientState::Connecting,
+        })
+    );
+    assert_eq!(
+        second.poll_event(),
+        Some(ClientEvent::StateChanged {
+            previous: ClientState::Connecting,
+            current: ClientState::Error(ClientError::ConnectionDenied),
+        })
+    );
+    assert_eq!(second.poll_event(), None);
+
+    let message = TestMessage { value: 1 };
+    assert_eq!(
+        second.try_send_message(0, message),
+        Err(SendError::NotConnected(message))
+    );
+    assert_eq!(
+        server.try_send_message(1, 0, message),
+        Err(SendError::InvalidClient(message))
+    );
+    // out of range indices are ignored instead of panicking
+    assert_eq!(server.send_message(2, 0, message), None);
+    assert_eq!(first.send_message(2, message), None);
+    server.send_message_to([0], 2, message);
+    assert_eq!(first.try_send_message(0, message), Ok(0));
+    assert_eq!(server.try_send_message(0, 0, message), Ok(0));
+
+    first.disconnect();
+    assert_eq!(
+        first.poll_event(),
+        Some(ClientEvent::StateChanged {
+            previous: ClientState::Connected,
+            current: ClientState::Disconnected,
+        })
+    );
+}
+
+#[test]
+fn broadcast_memory_transport() {
+    let mut time = 100.0;
+    let delta_time = 1.0 / 30.0;
+    let config = ClientServerConfig::new(2);
+
+    let network = MemoryNetwork::new();
+    let mut server = Server::with_transport(network.server_transport(), config.clone(), time);
+    let mut clients = [
+        Client::with_transport(network.client_transport(), config.clone(), time),
+        Client::with_transport(network.client_transport(), config.clone(), time),
+        Client::with_transport(network.client_transport(), config.clone(), time),
+    ];
+
+    server.start(4);
+    for (client_id, client) in clients.iter_mut().enumerate() {
+        client.connect_with(client_id as u64 + 1);
+    }
+    for _ in 0..2 {
+        let [first, second, third] = &mut clients;
+        update_client_server(
+            &mut time,
+            &mut [first, second, third],
+            &mut server,
+            delta_time,
+        );
+    }
+    assert!(clients.iter().all(|client| client.is_connected()));
+
+    server.broadcast_message_except(&[0], 0, TestMessage { value: 1 });
+    // client 3 isn't connected, and there is no client 4
+    server.send_message_to([0, 3, 4], 1, TestMessage { value: 2 });
+    server.broadcast_message(0, TestMessage { value: 3 });
+
+    for _ in 0..2 {
+        let [first, second, third] = &mut clients;
+        update_client_server(
+            &mut time,
+            &mut [first, second, third],
+            &mut server,
+            delta_time,
+        );
+    }
+
+    let received: Vec<_> = clients
+        .iter_mut()
+        .map(|client| {
+            let mut values = |channel| {
+                std::iter::from_fn(|| client.receive_message(channel))
+                    .map(|message| message.value)
+                    .collect::<Vec<_>>()
+            };
+            (values(0), values(1))
+        })
+        .collect();
+    assert_eq!(
+        received,
+        vec![
+            (vec![3], vec![2]),
+            (vec![1, 3], vec![]),
+            (vec![1, 3], vec![]),
+        ]
+    );
+    for client_index in 0..3 {
+        assert!(server.is_client_connected(client_index));
+    }
+}
+
+fn send_messages_from_client<CT: ClientTransport>(
+    client: &mut Client<TestMessage, CT>,
+    channel: usize,
+    count: usize,
+) {
     for i in 0..count {
         if !client.can_send_message(channel) {
             break;
@@ -154,8 +605,8 @@ fn send_messages_from_client(client: &mut Client<TestMessage>, channel: usize, c
     }
 }
 
-fn send_messages_from_server(
-    server: &mut Server<TestMessage>,
+fn send_messages_from_server<T: Transport>(
+    server: &mut Server<TestMessage, T>,
     client: usize,
     channel: usize,
     count: usize,
@@ -171,8 +622,8 @@ fn send_messages_from_server(
     }
 }
 
-fn receive_messages_from_server(
-    client: &mut Client<TestMessage>,
+fn receive_messages_from_server<CT: ClientTransport>(
+    client: &mut Client<TestMessage, CT>,
     channel: usize,
     expect_value: &mut u64,
 ) {
@@ -185,8 +636,8 @@ fn receive_messages_from_server(
     }
 }
 
-fn receive_messages_from_client(
-    server: &mut Server<TestMessage>,
+fn receive_messages_from_client<T: Transport>(
+    server: &mut Server<TestMessage, T>,
     client: usize,
     channel: usize,
     expect_value: &mut u64,
@@ -205,6 +656,17 @@ fn pump_client_server_update<M: NetworkMessage>(
     clients: &mut [&mut Client<M>],
     server: &mut Server<M>,
     delta_time: f64,
+) {
+    update_client_server(time, clients, server, delta_time);
+
+    thread::sleep(Duration::from_secs_f64(delta_time));
+}
+
+fn update_client_server<M: NetworkMessage, CT: ClientTransport, T: Transport>(
+    time: &mut f64,
+    clients: &mut [&mut Client<M, CT>],
+    server: &mut Server<M, T>,
+    delta_time: f64,
 ) {
     for client in clients.iter_mut() {
         client.send_packets();
@@ -222,6 +684,4 @@ fn pump_client_server_update<M: NetworkMessage>(
         client.advance_time(*time);
     }
     server.advance_time(*time);
-
-    thread::sleep(Duration::from_secs_f64(delta_time));
 }